@@ -0,0 +1,29 @@
+// Generated by `cargo run --bin export_schema`. Do not edit by hand.
+
+export type ClientEvent = { "type": "hello", "payload": { protocol_version: number, capabilities: Array<string>, } } | { "type": "authenticate", "payload": { token: string, } } | { "type": "join_room", "payload": string } | { "type": "send_message", "payload": string } | { "type": "leave_room", "payload": string } | { "type": "close_room", "payload": string } | { "type": "create_room", "payload": { name: string, description: string | null, is_private: boolean, } } | { "type": "update_room", "payload": { name: string, description: string | null, is_private: boolean | null, } } | { "type": "delete_room", "payload": { name: string, } } | { "type": "change_displayname", "payload": { displayName: string, } } | { "type": "private_message", "payload": { payload: string, target_username: string, } } | { "type": "server_broadcast", "payload": { payload: string, } } | { "type": "room_broadcast", "payload": { payload: string, room_name: string, } } | { "type": "get_username_from_displayname", "payload": string } | { "type": "pong" } | { "type": "get_room_list" } | { "type": "get_room_users", "payload": string } | { "type": "update_status", "payload": string } | { "type": "subscribe_to_profile", "payload": { user_id: number, } } | { "type": "unsubscribe_from_profile", "payload": { user_id: number, } } | { "type": "load_history", "payload": { room_name: string, before_id: number | null, limit: number | null, } } | { "type": "pin_message", "payload": { room_name: string, message_id: number, } } | { "type": "unpin_message", "payload": { room_name: string, message_id: number, } } | { "type": "search_messages", "payload": { query: string, room_name: string | null, from_user: string | null, before: string | null, after: string | null, limit: number | null, } } | { "type": "begin_upload", "payload": { upload_id: string | null, file_name: string, size: number, mime_type: string, room_name: string | null, target_username: string | null, } } | { "type": "cancel_upload", "payload": { upload_id: string, } } | { "type": "upload_chunk", "payload": { upload_id: string, offset: number, checksum: number, data: number[] | Uint8Array, } } | { "type": "refresh_token", "payload": { token: string, } };
+
+export type ServerEvent = { "type": "identity_announced", payload: string, } | { "type": "welcome", session_id: string, protocol_version: number, supported_versions: Array<number>, features: Array<string>, } | { "type": "send_message", payload: string, from_id: string, from_username: string, from_display_name: string, created_at: string, edited_at: string | null, mentions: Array<string>, message_type: string, attachment: FileAttachment | null, } | { "type": "room_update", room_name: string, users: Array<RoomUser>, } | { "type": "private_message", from_id: string, from_username: string, from_display_name: string, payload: string, created_at: string, edited_at: string | null, attachment: FileAttachment | null, } | { "type": "error", code: ErrorCode, message: string, details?: unknown, } | { "type": "displayname_changed", old: string, new: string, } | { "type": "user_joined", room_name: string, username: string, } | { "type": "user_left", room_name: string, username: string, } | { "type": "room_list", rooms: Array<RoomListEntry>, } | { "type": "user_status_changed", username: string, status: string, } | { "type": "load_room_messages", room_name: string, messages: Array<RoomMessage>, has_more: boolean, next_cursor: number | null, } | { "type": "user_status_update", status: string, } | { "type": "recieve_username", username: string, } | { "type": "mentioned", room_name: string, message_id: number | null, from: string, } | { "type": "pins_updated", room_name: string, pins: Array<RoomMessage>, } | { "type": "search_results", query: string, hits: Array<SearchHit>, } | { "type": "upload_ready", upload_id: string, offset: number, chunk_size: number, } | { "type": "upload_progress", upload_id: string, received: number, } | { "type": "upload_complete", upload_id: string, attachment: FileAttachment, } | { "type": "session_expired", reason: string, } | { "type": "token_refreshed", expires_at: number | null, } | { "type": "memberships", rooms: Array<string>, } | { "type": "room_created", room: RoomDetails, } | { "type": "room_updated", room: RoomDetails, } | { "type": "room_deleted", room_name: string, } | { "type": "ping" };
+
+export type ErrorCode = "invalid_payload" | "unauthorized" | "not_in_room" | "forbidden" | "not_found" | "rate_limited" | "user_offline" | "payload_too_large" | "unsupported_media_type" | "unsupported_version" | "backend_unavailable" | "internal" | "already_exists";
+
+export type RoomListEntry = { name: string, count: number, description: string | null, is_private: boolean, };
+
+export type RoomUser = { username: string, display_name: string, avatar_url: string | null, status: string, };
+
+export type RoomMessage = { id: number, content: string, created_at: string, edited_at: string | null, message_type: string, user: MessageAuthor, };
+
+export type MessageAuthor = { id: number, username: string, display_name: string, avatar_url: string | null, };
+
+export type SearchHit = { room_name: string, score: number, 
+/**
+ * HTML: escaped message text with matches wrapped in `<mark>`
+ */
+snippet: string, message: RoomMessage, };
+
+export type FileAttachment = { id: string, file_name: string, mime_type: string, size: number, url: string, };
+
+export type RoomDetails = { name: string, description: string | null, is_private: boolean, 
+/**
+ * Database id of the user who created the room
+ */
+owner_id: number | null, };
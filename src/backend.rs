@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+/// Pause before the first retry of an idempotent call; doubles after each attempt
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// The kinds of calls made to the Node API. Each has its own timeout, and
+/// only the ones that are safe to repeat are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// `/api/auth/verify-session`
+    VerifySession,
+    /// `/internal/rooms/:room/messages`
+    History,
+    /// `/internal/rooms/:room/members`
+    Members,
+    /// Reading `/internal/rooms/:room/pins`
+    Pins,
+    /// `/internal/users/resolve`
+    ResolveUsers,
+    /// `/internal/messages/search`
+    Search,
+    /// `/internal/memberships`, the whole `room_members` table
+    Memberships,
+    /// `/internal/rooms`, every room
+    Rooms,
+    /// Anything that changes data. The outbox retries these itself, with an idempotency key.
+    Write,
+}
+
+impl Endpoint {
+    const ALL: [Endpoint; 9] = [
+        Endpoint::VerifySession,
+        Endpoint::History,
+        Endpoint::Members,
+        Endpoint::Pins,
+        Endpoint::ResolveUsers,
+        Endpoint::Search,
+        Endpoint::Memberships,
+        Endpoint::Rooms,
+        Endpoint::Write,
+    ];
+
+    /// Name used in `BACKEND_TIMEOUTS`
+    pub fn name(self) -> &'static str {
+        match self {
+            Endpoint::VerifySession => "verify_session",
+            Endpoint::History => "history",
+            Endpoint::Members => "members",
+            Endpoint::Pins => "pins",
+            Endpoint::ResolveUsers => "resolve_users",
+            Endpoint::Search => "search",
+            Endpoint::Memberships => "memberships",
+            Endpoint::Rooms => "rooms",
+            Endpoint::Write => "write",
+        }
+    }
+
+    fn default_timeout(self) -> Duration {
+        match self {
+            Endpoint::VerifySession | Endpoint::Members | Endpoint::Pins | Endpoint::ResolveUsers => Duration::from_secs(3),
+            Endpoint::History => Duration::from_secs(5),
+            Endpoint::Search | Endpoint::Memberships | Endpoint::Rooms | Endpoint::Write => Duration::from_secs(10),
+        }
+    }
+
+    fn idempotent(self) -> bool {
+        !matches!(self, Endpoint::Write)
+    }
+}
+
+pub struct BackendConfig {
+    pub timeouts: HashMap<Endpoint, Duration>,
+    /// Extra attempts for idempotent calls after a timeout, network error or 5xx
+    pub retries: u32,
+    /// Consecutive failures that open the breaker
+    pub failure_threshold: u32,
+    /// How long an open breaker fails calls before letting a probe through
+    pub cooldown: Duration,
+}
+
+impl BackendConfig {
+    /// Reads `BACKEND_TIMEOUTS` (`name=seconds`, comma separated), `BACKEND_RETRIES`,
+    /// `BACKEND_FAILURE_THRESHOLD` and `BACKEND_COOLDOWN` (seconds)
+    pub fn from_env() -> Self {
+        let number = |key: &str| std::env::var(key).ok().and_then(|v| v.parse::<u64>().ok());
+
+        let mut timeouts: HashMap<Endpoint, Duration> = Endpoint::ALL.iter()
+            .map(|e| (*e, e.default_timeout()))
+            .collect();
+        if let Ok(overrides) = std::env::var("BACKEND_TIMEOUTS") {
+            for pair in overrides.split(',').filter(|p| !p.trim().is_empty()) {
+                match parse_timeout(pair) {
+                    Some((endpoint, timeout)) => {
+                        timeouts.insert(endpoint, timeout);
+                    }
+                    None => println!("Ignoring invalid BACKEND_TIMEOUTS entry {:?}", pair),
+                }
+            }
+        }
+
+        BackendConfig {
+            timeouts,
+            retries: number("BACKEND_RETRIES").unwrap_or(2) as u32,
+            failure_threshold: number("BACKEND_FAILURE_THRESHOLD").unwrap_or(5).max(1) as u32,
+            cooldown: Duration::from_secs(number("BACKEND_COOLDOWN").unwrap_or(10)),
+        }
+    }
+}
+
+/// One `name=seconds` entry of `BACKEND_TIMEOUTS`
+fn parse_timeout(pair: &str) -> Option<(Endpoint, Duration)> {
+    let (name, secs) = pair.split_once('=')?;
+    let endpoint = Endpoint::ALL.into_iter().find(|e| e.name() == name.trim())?;
+    let secs: f64 = secs.trim().parse().ok()?;
+    Duration::try_from_secs_f64(secs).ok().map(|timeout| (endpoint, timeout))
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// The breaker is open; the call was never made
+    Unavailable,
+    Timeout,
+    Network(String),
+    /// The API answered with a 5xx
+    Status(reqwest::StatusCode),
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Unavailable => write!(f, "backend unavailable (circuit open)"),
+            BackendError::Timeout => write!(f, "backend timed out"),
+            BackendError::Network(e) => write!(f, "{}", e),
+            BackendError::Status(status) => write!(f, "HTTP {}", status),
+        }
+    }
+}
+
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// One probe call is in flight; everything else still fails fast
+    HalfOpen { since: Instant },
+}
+
+#[derive(Default)]
+struct BackendStats {
+    calls: AtomicU64,
+    failures: AtomicU64,
+    timeouts: AtomicU64,
+    retries: AtomicU64,
+    rejected: AtomicU64,
+    opened: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct BackendSnapshot {
+    /// `closed`, `open` or `half_open`
+    pub breaker: &'static str,
+    pub calls: u64,
+    pub failures: u64,
+    pub timeouts: u64,
+    pub retries: u64,
+    /// Calls failed fast while the breaker was open
+    pub rejected: u64,
+    /// Times the breaker has opened
+    pub opened: u64,
+}
+
+/// Every call to the Node API goes through here. Calls time out per endpoint,
+/// idempotent ones are retried, and after `failure_threshold` failures in a row
+/// the breaker opens so callers get `BackendError::Unavailable` at once instead
+/// of piling up requests against a dead API. After `cooldown` a single probe is
+/// let through: if it succeeds the breaker closes, otherwise it opens again.
+pub struct Backend {
+    client: reqwest::Client,
+    config: BackendConfig,
+    breaker: Mutex<BreakerState>,
+    stats: BackendStats,
+}
+
+impl Backend {
+    pub fn new(config: BackendConfig, client: reqwest::Client) -> Self {
+        Backend {
+            client,
+            config,
+            breaker: Mutex::new(BreakerState::Closed { failures: 0 }),
+            stats: BackendStats::default(),
+        }
+    }
+
+    /// Sends the request built by `build`, rebuilding it for each retry.
+    /// Any response below 500 is returned as is; callers decide what a 4xx means.
+    pub async fn send<F>(&self, endpoint: Endpoint, build: F) -> Result<reqwest::Response, BackendError>
+    where
+        F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    {
+        let timeout = self.config.timeouts.get(&endpoint).copied().unwrap_or(endpoint.default_timeout());
+        let attempts = if endpoint.idempotent() { self.config.retries + 1 } else { 1 };
+        let mut delay = RETRY_DELAY;
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.admit()?;
+            self.stats.calls.fetch_add(1, Ordering::Relaxed);
+
+            let error = match build(&self.client).timeout(timeout).send().await {
+                Ok(response) if response.status().is_server_error() => BackendError::Status(response.status()),
+                Ok(response) => {
+                    self.record_success();
+                    return Ok(response);
+                }
+                Err(e) if e.is_timeout() => {
+                    self.stats.timeouts.fetch_add(1, Ordering::Relaxed);
+                    BackendError::Timeout
+                }
+                Err(e) => BackendError::Network(e.to_string()),
+            };
+            self.record_failure(endpoint, &error);
+
+            if attempt >= attempts {
+                return Err(error);
+            }
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
+    /// Whether calls are currently failing fast
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.breaker.lock().unwrap(), BreakerState::Closed { .. })
+    }
+
+    pub fn snapshot(&self) -> BackendSnapshot {
+        let breaker = match *self.breaker.lock().unwrap() {
+            BreakerState::Closed { .. } => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half_open",
+        };
+        BackendSnapshot {
+            breaker,
+            calls: self.stats.calls.load(Ordering::Relaxed),
+            failures: self.stats.failures.load(Ordering::Relaxed),
+            timeouts: self.stats.timeouts.load(Ordering::Relaxed),
+            retries: self.stats.retries.load(Ordering::Relaxed),
+            rejected: self.stats.rejected.load(Ordering::Relaxed),
+            opened: self.stats.opened.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Lets a call through unless the breaker is open. Once the cooldown is
+    /// over the first caller becomes the probe.
+    fn admit(&self) -> Result<(), BackendError> {
+        let mut breaker = self.breaker.lock().unwrap();
+        let probe = match *breaker {
+            BreakerState::Closed { .. } => return Ok(()),
+            BreakerState::Open { until } => Instant::now() >= until,
+            // A probe whose caller went away never reports back; don't wait on it forever
+            BreakerState::HalfOpen { since } => since.elapsed() >= self.config.cooldown,
+        };
+        if probe {
+            *breaker = BreakerState::HalfOpen { since: Instant::now() };
+            return Ok(());
+        }
+        drop(breaker);
+        self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+        Err(BackendError::Unavailable)
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if !matches!(*breaker, BreakerState::Closed { .. }) {
+            println!("Backend recovered, closing the circuit");
+        }
+        *breaker = BreakerState::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self, endpoint: Endpoint, error: &BackendError) {
+        self.stats.failures.fetch_add(1, Ordering::Relaxed);
+        let mut breaker = self.breaker.lock().unwrap();
+        let failures = match *breaker {
+            BreakerState::Closed { failures } => failures + 1,
+            // The probe failed, or a call admitted before the breaker opened came back late
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => self.config.failure_threshold,
+        };
+
+        if failures < self.config.failure_threshold {
+            *breaker = BreakerState::Closed { failures };
+            return;
+        }
+        if !matches!(*breaker, BreakerState::Open { .. }) {
+            self.stats.opened.fetch_add(1, Ordering::Relaxed);
+            println!(
+                "Backend failing ({} on {}), opening the circuit for {:?}",
+                error, endpoint.name(), self.config.cooldown,
+            );
+        }
+        *breaker = BreakerState::Open { until: Instant::now() + self.config.cooldown };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU32;
+    use axum::http::StatusCode;
+    use axum::routing::any;
+    use axum::Router;
+
+    fn backend(failure_threshold: u32, cooldown: Duration) -> Backend {
+        let config = BackendConfig { timeouts: HashMap::new(), retries: 2, failure_threshold, cooldown };
+        Backend::new(config, reqwest::Client::new())
+    }
+
+    fn state(backend: &Backend) -> &'static str {
+        backend.snapshot().breaker
+    }
+
+    #[test]
+    fn breaker_opens_after_consecutive_failures() {
+        let backend = backend(3, Duration::from_secs(60));
+        for _ in 0..2 {
+            backend.admit().unwrap();
+            backend.record_failure(Endpoint::History, &BackendError::Timeout);
+        }
+        // A success in between starts the count over
+        backend.record_success();
+        for _ in 0..2 {
+            backend.record_failure(Endpoint::History, &BackendError::Timeout);
+        }
+        assert_eq!(state(&backend), "closed");
+
+        backend.record_failure(Endpoint::History, &BackendError::Timeout);
+        assert_eq!(state(&backend), "open");
+        assert!(matches!(backend.admit(), Err(BackendError::Unavailable)));
+        assert_eq!(backend.snapshot().opened, 1);
+        assert_eq!(backend.snapshot().rejected, 1);
+    }
+
+    #[test]
+    fn one_probe_after_the_cooldown_closes_the_breaker() {
+        let backend = backend(1, Duration::ZERO);
+        backend.record_failure(Endpoint::History, &BackendError::Timeout);
+        assert_eq!(state(&backend), "open");
+
+        backend.admit().unwrap();
+        assert_eq!(state(&backend), "half_open");
+        backend.record_success();
+        assert_eq!(state(&backend), "closed");
+    }
+
+    #[test]
+    fn a_failed_probe_opens_the_breaker_again() {
+        let backend = backend(1, Duration::from_millis(50));
+        backend.record_failure(Endpoint::History, &BackendError::Timeout);
+        std::thread::sleep(Duration::from_millis(60));
+
+        backend.admit().unwrap();
+        // Only the probe goes through while it is out
+        assert!(matches!(backend.admit(), Err(BackendError::Unavailable)));
+        backend.record_failure(Endpoint::History, &BackendError::Timeout);
+        assert_eq!(state(&backend), "open");
+        assert!(matches!(backend.admit(), Err(BackendError::Unavailable)));
+        assert_eq!(backend.snapshot().opened, 2);
+    }
+
+    /// A backend that always answers 503, and how many requests it got
+    async fn failing_backend() -> (String, Arc<AtomicU32>) {
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = hits.clone();
+        let app = Router::new().route("/", any(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async { StatusCode::SERVICE_UNAVAILABLE }
+        }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}/", addr), hits)
+    }
+
+    #[tokio::test]
+    async fn only_idempotent_calls_are_retried() {
+        let (url, hits) = failing_backend().await;
+        let backend = backend(100, Duration::from_secs(60));
+
+        let result = backend.send(Endpoint::History, |c| c.get(&url)).await;
+        assert!(matches!(result, Err(BackendError::Status(StatusCode::SERVICE_UNAVAILABLE))));
+        assert_eq!(hits.swap(0, Ordering::Relaxed), 3);
+
+        let result = backend.send(Endpoint::Write, |c| c.post(&url)).await;
+        assert!(matches!(result, Err(BackendError::Status(_))));
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn bad_timeout_entries_are_skipped() {
+        assert_eq!(parse_timeout("history=2.5"), Some((Endpoint::History, Duration::from_millis(2500))));
+        assert_eq!(parse_timeout(" write = 1 "), Some((Endpoint::Write, Duration::from_secs(1))));
+        assert_eq!(parse_timeout("history"), None);
+        assert_eq!(parse_timeout("nope=1"), None);
+        assert_eq!(parse_timeout("history=soon"), None);
+        assert_eq!(parse_timeout("history=-1"), None);
+    }
+}
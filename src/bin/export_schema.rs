@@ -0,0 +1,15 @@
+use my_websocket::schema::{json_schema, typescript, JSON_SCHEMA_PATH, TYPESCRIPT_PATH};
+use std::fs;
+use std::path::Path;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Regenerate both files whenever src/events.rs or the types it uses change
+    if let Some(dir) = Path::new(JSON_SCHEMA_PATH).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(JSON_SCHEMA_PATH, json_schema())?;
+    fs::write(TYPESCRIPT_PATH, typescript())?;
+
+    println!("Wrote {} and {}", JSON_SCHEMA_PATH, TYPESCRIPT_PATH);
+    Ok(())
+}
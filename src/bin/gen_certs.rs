@@ -1,22 +1,247 @@
-use rcgen::generate_simple_self_signed;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_ED25519,
+};
+use rsa::pkcs8::EncodePrivateKey;
 use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use time::{Duration, OffsetDateTime};
+
+const USAGE: &str = "\
+Usage: gen_certs [options]
+
+Creates a local root CA (reused if it already exists) and a server certificate signed by it.
+
+Options:
+  --out <dir>          Output directory (default: certs)
+  --san <name>         DNS name or IP for the server certificate, repeatable
+                       (default: localhost, 127.0.0.1)
+  --days <n>           Validity of issued certificates in days (default: 365)
+  --key-type <type>    ecdsa, ed25519 or rsa (default: ecdsa)
+  --client <name>      Also issue a client certificate for mTLS, repeatable
+  --new-ca             Replace the existing CA (needs --force)
+  --force              Overwrite existing keys
+  -h, --help           Show this help";
+
+/// Distinguished name of the CA. Kept fixed so an existing CA key can be reused to sign.
+const CA_NAME: &str = "Nexus Local Development CA";
+const CA_DAYS: i64 = 3650;
+
+struct Options {
+    out: PathBuf,
+    sans: Vec<String>,
+    days: i64,
+    key_type: KeyType,
+    clients: Vec<String>,
+    new_ca: bool,
+    force: bool,
+}
+
+#[derive(Clone, Copy)]
+enum KeyType {
+    Ecdsa,
+    Ed25519,
+    Rsa,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        out: PathBuf::from("certs"),
+        sans: Vec::new(),
+        days: 365,
+        key_type: KeyType::Ecdsa,
+        clients: Vec::new(),
+        new_ca: false,
+        force: false,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--out" => options.out = PathBuf::from(value()?),
+            "--san" => options.sans.push(value()?),
+            "--days" => options.days = value()?.parse().map_err(|_| "--days must be a number".to_string())?,
+            "--key-type" => {
+                options.key_type = match value()?.as_str() {
+                    "ecdsa" => KeyType::Ecdsa,
+                    "ed25519" => KeyType::Ed25519,
+                    "rsa" => KeyType::Rsa,
+                    other => return Err(format!("Unknown key type {}", other)),
+                }
+            }
+            "--client" => options.clients.push(value()?),
+            "--new-ca" => options.new_ca = true,
+            "--force" => options.force = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            other => return Err(format!("Unknown argument {}", other)),
+        }
+    }
+
+    if options.sans.is_empty() {
+        options.sans = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    }
+    if options.days <= 0 {
+        return Err("--days must be positive".to_string());
+    }
+    // Checked here too so a bad value fails before the CA is written
+    validity(&mut CertificateParams::default(), options.days)?;
+    Ok(options)
+}
+
+fn generate_key(key_type: KeyType) -> Result<KeyPair, Box<dyn std::error::Error>> {
+    let alg: &'static SignatureAlgorithm = match key_type {
+        KeyType::Ecdsa => &PKCS_ECDSA_P256_SHA256,
+        KeyType::Ed25519 => &PKCS_ED25519,
+        // ring can sign with RSA but not generate keys, so the rsa crate makes them
+        KeyType::Rsa => {
+            let key = rsa::RsaPrivateKey::new(&mut rand_core::OsRng, 2048)?;
+            let der = key.to_pkcs8_der()?;
+            return Ok(KeyPair::try_from(der.as_bytes())?);
+        }
+    };
+    Ok(KeyPair::generate_for(alg)?)
+}
+
+fn validity(params: &mut CertificateParams, days: i64) -> Result<(), String> {
+    let now = OffsetDateTime::now_utc();
+    let not_after = days.checked_mul(86_400)
+        .map(Duration::seconds)
+        .and_then(|d| now.checked_add(d))
+        .ok_or(format!("{} days is too far in the future", days))?;
+    params.not_before = now - Duration::days(1);
+    params.not_after = not_after;
+    Ok(())
+}
+
+fn ca_params() -> Result<CertificateParams, String> {
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    validity(&mut params, CA_DAYS)?;
+    Ok(params)
+}
+
+/// Loads the CA from `out`, or creates one. Reusing it keeps certificates
+/// issued earlier valid and saves importing a new root into the browser.
+fn load_or_create_ca(options: &Options) -> Result<(Certificate, KeyPair), Box<dyn std::error::Error>> {
+    let cert_path = options.out.join("ca.pem");
+    let key_path = options.out.join("ca-key.pem");
+
+    if key_path.exists() && !options.new_ca {
+        let key = KeyPair::from_pem(&fs::read_to_string(&key_path)?)?;
+        // Re-signing the same name and key gives an issuer that chains to the saved CA certificate
+        let cert = ca_params()?.self_signed(&key)?;
+        println!("Using existing CA from {}", key_path.display());
+        return Ok((cert, key));
+    }
+
+    refuse_overwrite(&key_path, options.force)?;
+    let key = generate_key(options.key_type)?;
+    let cert = ca_params()?.self_signed(&key)?;
+    write_pair(&cert_path, &cert.pem(), &key_path, &key.serialize_pem())?;
+    println!("Created CA {}", cert_path.display());
+    Ok((cert, key))
+}
+
+fn issue(
+    mut params: CertificateParams,
+    usage: ExtendedKeyUsagePurpose,
+    options: &Options,
+    ca: &(Certificate, KeyPair),
+) -> Result<(Certificate, KeyPair), Box<dyn std::error::Error>> {
+    params.is_ca = IsCa::ExplicitNoCa;
+    params.key_usages = match options.key_type {
+        // RSA key exchange encrypts the premaster secret with the certificate key
+        KeyType::Rsa => vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment],
+        // EC and Ed25519 keys only ever sign
+        KeyType::Ecdsa | KeyType::Ed25519 => vec![KeyUsagePurpose::DigitalSignature],
+    };
+    params.extended_key_usages = vec![usage];
+    params.use_authority_key_identifier_extension = true;
+    validity(&mut params, options.days)?;
+
+    let key = generate_key(options.key_type)?;
+    let cert = params.signed_by(&key, &ca.0, &ca.1)?;
+    Ok((cert, key))
+}
+
+fn refuse_overwrite(key_path: &Path, force: bool) -> Result<(), String> {
+    if key_path.exists() && !force {
+        return Err(format!("{} already exists, pass --force to overwrite it", key_path.display()));
+    }
+    Ok(())
+}
+
+fn write_pair(cert_path: &Path, cert_pem: &str, key_path: &Path, key_pem: &str) -> std::io::Result<()> {
+    fs::write(cert_path, cert_pem)?;
+
+    // The key is created private rather than tightened after writing, so it is never readable by others.
+    // An existing file keeps its old mode when opened, so it is removed first (overwriting needs --force).
+    if key_path.exists() {
+        fs::remove_file(key_path)?;
+    }
+    let mut open = fs::OpenOptions::new();
+    open.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        open.mode(0o600);
+    }
+    open.open(key_path)?.write_all(key_pem.as_bytes())
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Define the hostnames/IPs this certificate will be valid for
-    let subject_alt_names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // 1. Check nothing would be overwritten before writing anything
+    if options.new_ca && options.out.join("ca-key.pem").exists() && !options.force {
+        return Err("--new-ca replaces the existing CA, pass --force as well".into());
+    }
+    refuse_overwrite(&options.out.join("key.pem"), options.force)?;
+    for name in &options.clients {
+        refuse_overwrite(&options.out.join(format!("client-{}-key.pem", name)), options.force)?;
+    }
+    fs::create_dir_all(&options.out)?;
 
-    // Generate a self-signed certificate
-    let cert = generate_simple_self_signed(subject_alt_names)?;
+    // 2. Root CA
+    let ca = load_or_create_ca(&options)?;
 
-    // In rcgen 0.13, you access the certificate and key serialization differently
-    let cert_pem = cert.cert.pem();
-    let key_pem = cert.key_pair.serialize_pem();
+    // 3. Server certificate for the given names
+    let mut params = CertificateParams::new(options.sans.clone())?;
+    params.distinguished_name.push(DnType::CommonName, options.sans[0].as_str());
+    let (cert, key) = issue(params, ExtendedKeyUsagePurpose::ServerAuth, &options, &ca)?;
+    write_pair(&options.out.join("cert.pem"), &cert.pem(), &options.out.join("key.pem"), &key.serialize_pem())?;
+    println!("Issued server certificate for {}", options.sans.join(", "));
 
-    // Save to files in the project root
-    fs::create_dir_all("certs")?;
-    fs::write("certs/cert.pem", cert_pem)?;
-    fs::write("certs/key.pem", key_pem)?;
+    // 4. Client certificates for mTLS between the servers
+    for name in &options.clients {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, name.as_str());
+        let (cert, key) = issue(params, ExtendedKeyUsagePurpose::ClientAuth, &options, &ca)?;
+        write_pair(
+            &options.out.join(format!("client-{}.pem", name)),
+            &cert.pem(),
+            &options.out.join(format!("client-{}-key.pem", name)),
+            &key.serialize_pem(),
+        )?;
+        println!("Issued client certificate for {}", name);
+    }
 
-    println!("Successfully generated cert.pem and key.pem in the certs/ directory.");
+    println!(
+        "Done. Import {} into your browser or OS trust store to trust the server certificate.",
+        options.out.join("ca.pem").display()
+    );
     Ok(())
 }
@@ -0,0 +1,166 @@
+use std::time::Duration;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::catalog::RoomDetails;
+use crate::directory::RemoteSession;
+use crate::state::RoomUser;
+
+/// Redis channel every node publishes to when `BUS_CHANNEL` is unset
+const DEFAULT_CHANNEL: &str = "nexus:bus";
+/// Envelopes held for the dispatcher; a dispatcher further behind than this skips ahead
+const BUFFER: usize = 1024;
+/// Wait between attempts to re-subscribe after the Redis connection drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Something one node tells the others. Payloads are serialized `ServerEvent`s,
+/// ready to queue on local sessions as they are.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BusEvent {
+    /// For every session in the room, except `except`
+    Room { room: String, payload: String, except: Option<Uuid> },
+    /// For every session of a user (DMs and mentions)
+    User { username: String, payload: String },
+    /// For every session of these users, by database id
+    Members { db_user_ids: Vec<i32>, payload: String },
+    /// For every session watching a profile
+    Profile { db_user_id: i32, payload: String },
+    /// For every session, except `except`
+    Everyone { payload: String, except: Option<Uuid> },
+    /// A session connected
+    SessionOpened { session: RemoteSession },
+    /// A session joined a room
+    Joined { room: String, session_id: Uuid },
+    /// A session left a room
+    Left { room: String, session_id: Uuid },
+    /// A user became or stopped being a member of a room
+    Membership { room: String, db_user_id: i32, member: bool },
+    /// A room was created; its owner is its first member
+    RoomCreated { room: RoomDetails },
+    RoomUpdated { room: RoomDetails },
+    /// A room was deleted along with its memberships
+    RoomDeleted { room_name: String },
+    /// A session's status or display name changed
+    Presence { session_id: Uuid, user: RoomUser },
+    /// A session disconnected and left all its rooms
+    SessionClosed { session_id: Uuid },
+    /// Heartbeat with every session the node serves
+    NodeAlive { sessions: Vec<RemoteSession> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    /// Node that published the event; nodes ignore their own events
+    pub origin: Uuid,
+    pub event: BusEvent,
+}
+
+/// How nodes share room traffic, DMs, presence and membership.
+/// `Local` is for a single node, `Redis` fans out through Redis pub/sub.
+pub enum Bus {
+    Local {
+        node_id: Uuid,
+        tx: broadcast::Sender<Envelope>,
+    },
+    Redis {
+        node_id: Uuid,
+        channel: String,
+        conn: Box<redis::aio::ConnectionManager>,
+        tx: broadcast::Sender<Envelope>,
+    },
+}
+
+impl Bus {
+    pub fn local() -> Self {
+        let (tx, _) = broadcast::channel(BUFFER);
+        Bus::Local { node_id: Uuid::new_v4(), tx }
+    }
+
+    /// Picks the implementation from `BUS_URL` (a `redis://` URL, in-process when unset) and `BUS_CHANNEL`
+    pub async fn from_env() -> Result<Self, String> {
+        let Some(url) = std::env::var("BUS_URL").ok().filter(|v| !v.is_empty()) else {
+            return Ok(Bus::local());
+        };
+        let channel = std::env::var("BUS_CHANNEL").unwrap_or_else(|_| DEFAULT_CHANNEL.to_string());
+        Bus::redis(&url, channel).await
+    }
+
+    /// Connects to Redis at `url` and starts following `channel`
+    pub async fn redis(url: &str, channel: String) -> Result<Self, String> {
+        let client = redis::Client::open(url).map_err(|e| format!("BUS_URL: {}", e))?;
+        let conn = redis::aio::ConnectionManager::new(client.clone())
+            .await
+            .map_err(|e| format!("Redis bus: {}", e))?;
+        let (tx, _) = broadcast::channel(BUFFER);
+        let node_id = Uuid::new_v4();
+
+        tokio::spawn(subscribe_redis(client, channel.clone(), tx.clone()));
+        println!("Cluster bus on Redis channel {} (node {})", channel, node_id);
+        Ok(Bus::Redis { node_id, channel, conn: Box::new(conn), tx })
+    }
+
+    pub fn node_id(&self) -> Uuid {
+        match self {
+            Bus::Local { node_id, .. } | Bus::Redis { node_id, .. } => *node_id,
+        }
+    }
+
+    /// Whether other nodes may be serving sessions this node cannot see
+    pub fn is_clustered(&self) -> bool {
+        matches!(self, Bus::Redis { .. })
+    }
+
+    /// Sends an event to every other node. Delivery is best effort: a failed
+    /// publish is logged and the event is only seen locally.
+    pub async fn publish(&self, event: BusEvent) {
+        let envelope = Envelope { origin: self.node_id(), event };
+        match self {
+            Bus::Local { tx, .. } => {
+                let _ = tx.send(envelope);
+            }
+            Bus::Redis { channel, conn, .. } => {
+                let payload = serde_json::to_string(&envelope).unwrap();
+                let mut conn = (**conn).clone();
+                if let Err(e) = redis::cmd("PUBLISH").arg(channel).arg(payload).query_async::<()>(&mut conn).await {
+                    println!("Failed to publish to the bus: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Events published by any node, this one included
+    pub fn subscribe(&self) -> broadcast::Receiver<Envelope> {
+        match self {
+            Bus::Local { tx, .. } | Bus::Redis { tx, .. } => tx.subscribe(),
+        }
+    }
+}
+
+/// Forwards the Redis channel into `tx`, re-subscribing whenever the connection drops
+async fn subscribe_redis(client: redis::Client, channel: String, tx: broadcast::Sender<Envelope>) {
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => match pubsub.subscribe(&channel).await {
+                Ok(()) => {
+                    let mut messages = pubsub.on_message();
+                    while let Some(msg) = messages.next().await {
+                        let Ok(payload) = msg.get_payload::<String>() else { continue };
+                        match serde_json::from_str::<Envelope>(&payload) {
+                            Ok(envelope) => {
+                                let _ = tx.send(envelope);
+                            }
+                            Err(e) => println!("Ignoring malformed bus message: {}", e),
+                        }
+                    }
+                    println!("Lost the Redis bus subscription, reconnecting");
+                }
+                Err(e) => println!("Failed to subscribe to {}: {}", channel, e),
+            },
+            Err(e) => println!("Failed to connect to the Redis bus: {}", e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::backend::{Backend, Endpoint};
+
+pub const MAX_NAME_LEN: usize = 50;
+pub const MAX_DESCRIPTION_LEN: usize = 500;
+
+/// A room as stored in the `rooms` table
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
+pub struct RoomDetails {
+    pub name: String,
+    pub description: Option<String>,
+    pub is_private: bool,
+    /// Database id of the user who created the room
+    pub owner_id: Option<i32>,
+}
+
+impl RoomDetails {
+    /// Parses a room as the backend returns it
+    pub fn from_api(r: &serde_json::Value) -> Option<Self> {
+        Some(RoomDetails {
+            name: r["name"].as_str()?.to_string(),
+            description: r["description"].as_str().map(String::from),
+            is_private: r["isPrivate"].as_bool().unwrap_or(false),
+            owner_id: r["createdById"].as_i64().map(|id| id as i32),
+        })
+    }
+}
+
+/// Every room that exists, whether or not anyone is in it.
+/// Loaded at startup and kept current by room lifecycle events.
+#[derive(Default)]
+pub struct RoomCatalog {
+    rooms: HashMap<String, RoomDetails>,
+    /// False when the startup load failed. The load is retried on the next
+    /// room access, which fails with `BackendUnavailable` until it succeeds.
+    pub loaded: bool,
+}
+
+impl RoomCatalog {
+    pub async fn load(backend: &Backend) -> Result<Self, String> {
+        let response = backend
+            .send(Endpoint::Rooms, |c| c.get("https://localhost:443/internal/rooms"))
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        let data = response.json::<serde_json::Value>().await.map_err(|e| e.to_string())?;
+
+        let rooms = data["rooms"].as_array().into_iter().flatten()
+            .filter_map(RoomDetails::from_api)
+            .map(|r| (r.name.clone(), r))
+            .collect();
+        Ok(RoomCatalog { rooms, loaded: true })
+    }
+
+    /// Retries the load if it failed so far, returning whether the catalog is usable
+    pub async fn ensure_loaded(catalog: &Mutex<RoomCatalog>, backend: &Backend) -> bool {
+        if catalog.lock().unwrap().loaded {
+            return true;
+        }
+        match RoomCatalog::load(backend).await {
+            Ok(loaded) => {
+                println!("Loaded {} rooms", loaded.len());
+                *catalog.lock().unwrap() = loaded;
+                true
+            }
+            Err(e) => {
+                println!("Failed to load rooms: {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RoomDetails> {
+        self.rooms.get(name)
+    }
+
+    pub fn insert(&mut self, room: RoomDetails) {
+        self.rooms.insert(room.name.clone(), room);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<RoomDetails> {
+        self.rooms.remove(name)
+    }
+
+    pub fn rooms(&self) -> impl Iterator<Item = &RoomDetails> {
+        self.rooms.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+}
+
+/// Room names end up in URLs and the database's 100 character column, so
+/// they are kept to letters, digits, `-` and `_`, starting with a letter or digit
+pub fn validate_room_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!("Room names must be 1 to {} characters long", MAX_NAME_LEN));
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("Room names must start with a letter or digit".to_string());
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Room names may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,228 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use axum::extract::ws::Message;
+use flate2::{Compress, Compression, FlushCompress};
+use serde::Serialize;
+
+/// First byte of every server frame on a deflate session
+pub const FRAME_RAW: u8 = 0;
+pub const FRAME_DEFLATE: u8 = 1;
+
+/// Trailer a sync flush leaves behind. Like RFC 7692 it is stripped from each
+/// message, and clients append it back before inflating.
+const SYNC_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Compression for the `nexus.*.deflate.v1` subprotocols. This is this server's
+/// own framing, not RFC 7692 `permessage-deflate`: it is negotiated as a
+/// subprotocol rather than an extension and every server frame carries a one
+/// byte header. Only clients written for it can use it and proxies see opaque
+/// binary frames, so it is off unless `WS_COMPRESSION` is set.
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Frames smaller than this many bytes are sent raw
+    pub threshold: usize,
+    pub level: u32,
+    /// Keep the deflate window between messages instead of resetting it for each one
+    pub context_takeover: bool,
+}
+
+impl CompressionConfig {
+    /// Reads `WS_COMPRESSION` (off by default), `WS_COMPRESSION_THRESHOLD`, `WS_COMPRESSION_LEVEL`
+    /// and `WS_COMPRESSION_CONTEXT_TAKEOVER`
+    pub fn from_env() -> Self {
+        let flag = |key: &str, default: bool| {
+            std::env::var(key).map(|v| matches!(v.as_str(), "1" | "true" | "on")).unwrap_or(default)
+        };
+
+        CompressionConfig {
+            enabled: flag("WS_COMPRESSION", false),
+            threshold: std::env::var("WS_COMPRESSION_THRESHOLD").ok().and_then(|v| v.parse().ok()).unwrap_or(1024),
+            level: std::env::var("WS_COMPRESSION_LEVEL").ok().and_then(|v| v.parse().ok()).unwrap_or(6).min(9),
+            context_takeover: flag("WS_COMPRESSION_CONTEXT_TAKEOVER", true),
+        }
+    }
+}
+
+/// Process-wide counters, reported by `GET /metrics`
+#[derive(Default)]
+pub struct CompressionStats {
+    frames_compressed: AtomicU64,
+    frames_skipped: AtomicU64,
+    bytes_before: AtomicU64,
+    bytes_after: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct CompressionSnapshot {
+    pub frames_compressed: u64,
+    pub frames_skipped: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Compressed size over original size, for the frames that were compressed
+    pub ratio: f64,
+}
+
+impl CompressionStats {
+    pub fn snapshot(&self) -> CompressionSnapshot {
+        let before = self.bytes_before.load(Ordering::Relaxed);
+        let after = self.bytes_after.load(Ordering::Relaxed);
+        CompressionSnapshot {
+            frames_compressed: self.frames_compressed.load(Ordering::Relaxed),
+            frames_skipped: self.frames_skipped.load(Ordering::Relaxed),
+            bytes_before: before,
+            bytes_after: after,
+            ratio: if before == 0 { 1.0 } else { after as f64 / before as f64 },
+        }
+    }
+}
+
+/// Per-session compressor for the outgoing direction. Every frame becomes
+/// binary with a one byte header saying whether the rest is deflated. Clients
+/// inflate with a raw deflate stream after appending `00 00 ff ff`, keeping
+/// one stream for the whole session when context takeover is on.
+pub struct Deflater {
+    compress: Compress,
+    threshold: usize,
+    context_takeover: bool,
+}
+
+impl Deflater {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Deflater {
+            compress: Compress::new(Compression::new(config.level), false),
+            threshold: config.threshold,
+            context_takeover: config.context_takeover,
+        }
+    }
+
+    pub fn wrap(&mut self, msg: Message, stats: &CompressionStats) -> Message {
+        let payload = match msg {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(bytes) => bytes,
+            other => return other,
+        };
+
+        // Once deflated the frame has to be sent compressed, even if it grew,
+        // or the client's window would fall out of sync with ours
+        if payload.len() >= self.threshold
+            && let Some(compressed) = self.deflate(&payload)
+        {
+            stats.frames_compressed.fetch_add(1, Ordering::Relaxed);
+            stats.bytes_before.fetch_add(payload.len() as u64, Ordering::Relaxed);
+            stats.bytes_after.fetch_add(compressed.len() as u64 + 1, Ordering::Relaxed);
+            return Message::Binary(with_header(FRAME_DEFLATE, &compressed));
+        }
+
+        stats.frames_skipped.fetch_add(1, Ordering::Relaxed);
+        Message::Binary(with_header(FRAME_RAW, &payload))
+    }
+
+    fn deflate(&mut self, input: &[u8]) -> Option<Vec<u8>> {
+        if !self.context_takeover {
+            self.compress.reset();
+        }
+
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+        let start_in = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start_in) as usize;
+            if out.capacity() - out.len() < 64 {
+                out.reserve(input.len() / 2 + 64);
+            }
+            self.compress.compress_vec(&input[consumed..], &mut out, FlushCompress::Sync).ok()?;
+            let done = (self.compress.total_in() - start_in) as usize == input.len();
+            if done && out.ends_with(&SYNC_TRAILER) {
+                break;
+            }
+        }
+
+        out.truncate(out.len() - SYNC_TRAILER.len());
+        Some(out)
+    }
+}
+
+fn with_header(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 1);
+    frame.push(flag);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Decompress, FlushDecompress};
+
+    fn config(threshold: usize, context_takeover: bool) -> CompressionConfig {
+        CompressionConfig { enabled: true, threshold, level: 6, context_takeover }
+    }
+
+    /// What a client does with a frame: strip the header and inflate if needed
+    fn unwrap(frame: Message, inflater: &mut Decompress) -> Vec<u8> {
+        let Message::Binary(frame) = frame else { panic!("expected a binary frame") };
+        match frame[0] {
+            FRAME_RAW => frame[1..].to_vec(),
+            FRAME_DEFLATE => {
+                let mut input = frame[1..].to_vec();
+                input.extend_from_slice(&SYNC_TRAILER);
+                let mut out = Vec::with_capacity(64 * 1024);
+                inflater.decompress_vec(&input, &mut out, FlushDecompress::Sync).unwrap();
+                out
+            }
+            other => panic!("unknown frame header {}", other),
+        }
+    }
+
+    fn payload(n: usize) -> String {
+        (0..n).map(|i| format!(r#"{{"type":"send_message","payload":"message {}"}}"#, i)).collect()
+    }
+
+    #[test]
+    fn frames_round_trip_with_context_takeover() {
+        let mut deflater = Deflater::new(&config(16, true));
+        let mut inflater = Decompress::new(false);
+        let stats = CompressionStats::default();
+
+        let text = payload(50);
+        let first = deflater.wrap(Message::Text(text.clone()), &stats);
+        let second = deflater.wrap(Message::Text(text.clone()), &stats);
+        let second_len = match &second { Message::Binary(b) => b.len(), _ => unreachable!() };
+
+        assert_eq!(unwrap(first, &mut inflater), text.as_bytes());
+        assert_eq!(unwrap(second, &mut inflater), text.as_bytes());
+        // The repeat refers back to the first message
+        assert!(second_len < 64);
+        assert_eq!(stats.snapshot().frames_compressed, 2);
+    }
+
+    #[test]
+    fn frames_stand_alone_without_context_takeover() {
+        let mut deflater = Deflater::new(&config(16, false));
+        let stats = CompressionStats::default();
+
+        let text = payload(50);
+        for _ in 0..3 {
+            let frame = deflater.wrap(Message::Text(text.clone()), &stats);
+            // A fresh stream for every frame is enough
+            assert_eq!(unwrap(frame, &mut Decompress::new(false)), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn small_frames_are_sent_raw() {
+        let mut deflater = Deflater::new(&config(1024, true));
+        let mut inflater = Decompress::new(false);
+        let stats = CompressionStats::default();
+
+        let frame = deflater.wrap(Message::Text("{\"type\":\"ping\"}".to_string()), &stats);
+        let Message::Binary(bytes) = &frame else { panic!("expected a binary frame") };
+        assert_eq!(bytes[0], FRAME_RAW);
+        assert_eq!(unwrap(frame, &mut inflater), b"{\"type\":\"ping\"}");
+
+        // Raw frames don't disturb the stream for the compressed ones after them
+        let text = payload(50);
+        assert_eq!(unwrap(deflater.wrap(Message::Text(text.clone()), &stats), &mut inflater), text.as_bytes());
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.frames_skipped, snapshot.frames_compressed), (1, 1));
+        assert!(snapshot.ratio < 0.5);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::state::RoomUser;
+
+/// How often each node announces its sessions
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// A node that hasn't announced itself for this long is considered gone, with all its sessions
+pub const NODE_TTL: Duration = Duration::from_secs(30);
+
+/// What other nodes need to know about a session
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RemoteSession {
+    pub session_id: Uuid,
+    pub db_user_id: i32,
+    pub user: RoomUser,
+    pub rooms: HashSet<String>,
+}
+
+struct RemoteNode {
+    last_seen: Instant,
+    sessions: HashMap<Uuid, RemoteSession>,
+}
+
+/// Sessions served by other nodes, grouped by node. Kept current from bus
+/// deltas and replaced wholesale by each node's heartbeat, so a missed delta
+/// heals within one `HEARTBEAT_INTERVAL`.
+#[derive(Default)]
+pub struct Directory {
+    nodes: HashMap<Uuid, RemoteNode>,
+}
+
+impl Directory {
+    fn node(&mut self, node_id: Uuid) -> &mut RemoteNode {
+        let node = self.nodes.entry(node_id).or_insert_with(|| RemoteNode {
+            last_seen: Instant::now(),
+            sessions: HashMap::new(),
+        });
+        node.last_seen = Instant::now();
+        node
+    }
+
+    fn session(&mut self, node_id: Uuid, session_id: Uuid) -> Option<&mut RemoteSession> {
+        self.node(node_id).sessions.get_mut(&session_id)
+    }
+
+    /// A heartbeat: `sessions` is everything the node currently serves
+    pub fn node_alive(&mut self, node_id: Uuid, sessions: Vec<RemoteSession>) {
+        self.node(node_id).sessions = sessions.into_iter().map(|s| (s.session_id, s)).collect();
+    }
+
+    pub fn session_opened(&mut self, node_id: Uuid, session: RemoteSession) {
+        self.node(node_id).sessions.insert(session.session_id, session);
+    }
+
+    pub fn session_closed(&mut self, node_id: Uuid, session_id: Uuid) {
+        self.node(node_id).sessions.remove(&session_id);
+    }
+
+    pub fn joined(&mut self, node_id: Uuid, session_id: Uuid, room: String) {
+        if let Some(session) = self.session(node_id, session_id) {
+            session.rooms.insert(room);
+        }
+    }
+
+    pub fn left(&mut self, node_id: Uuid, session_id: Uuid, room: &str) {
+        if let Some(session) = self.session(node_id, session_id) {
+            session.rooms.remove(room);
+        }
+    }
+
+    pub fn update_user(&mut self, node_id: Uuid, session_id: Uuid, user: RoomUser) {
+        if let Some(session) = self.session(node_id, session_id) {
+            session.user = user;
+        }
+    }
+
+    /// Takes a deleted room out of every remote session
+    pub fn forget_room(&mut self, room: &str) {
+        for node in self.nodes.values_mut() {
+            for session in node.sessions.values_mut() {
+                session.rooms.remove(room);
+            }
+        }
+    }
+
+    /// Forgets nodes not heard from within `NODE_TTL`, returning the rooms their sessions were in
+    pub fn expire(&mut self) -> HashSet<String> {
+        let mut rooms = HashSet::new();
+        self.nodes.retain(|node_id, node| {
+            let alive = node.last_seen.elapsed() < NODE_TTL;
+            if !alive {
+                println!("Node {} stopped sending heartbeats, dropping its {} sessions", node_id, node.sessions.len());
+                rooms.extend(node.sessions.values().flat_map(|s| s.rooms.iter().cloned()));
+            }
+            alive
+        });
+        rooms
+    }
+
+    fn sessions(&self) -> impl Iterator<Item = &RemoteSession> {
+        self.nodes.values().flat_map(|n| n.sessions.values())
+    }
+
+    /// Whether `username` has a session on another node
+    pub fn is_online(&self, username: &str) -> bool {
+        self.sessions().any(|s| s.user.username == username)
+    }
+
+    /// Status of a user on another node, `None` if they have no session there
+    pub fn status_of(&self, db_user_id: i32) -> Option<String> {
+        self.sessions().find(|s| s.db_user_id == db_user_id).map(|s| s.user.status.clone())
+    }
+
+    /// Username of a user on another node, looked up by display name
+    pub fn username_for(&self, display_name: &str) -> Option<String> {
+        self.sessions().find(|s| s.user.display_name == display_name).map(|s| s.user.username.clone())
+    }
+
+    /// Members of `room` connected to other nodes
+    pub fn room_members(&self, room: &str) -> Vec<RoomUser> {
+        self.sessions().filter(|s| s.rooms.contains(room)).map(|s| s.user.clone()).collect()
+    }
+
+    /// Number of sessions on other nodes in each room
+    pub fn room_counts(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for room in self.sessions().flat_map(|s| s.rooms.iter()) {
+            *counts.entry(room.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(username: &str, rooms: &[&str]) -> RemoteSession {
+        RemoteSession {
+            session_id: Uuid::new_v4(),
+            db_user_id: 7,
+            user: RoomUser {
+                username: username.to_string(),
+                display_name: format!("{} display", username),
+                avatar_url: None,
+                status: "away".to_string(),
+            },
+            rooms: rooms.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn deltas_update_sessions() {
+        let mut directory = Directory::default();
+        let node = Uuid::new_v4();
+        let alice = session("alice", &["lobby"]);
+        let alice_id = alice.session_id;
+
+        directory.session_opened(node, alice);
+        assert!(directory.is_online("alice"));
+        assert_eq!(directory.status_of(7).as_deref(), Some("away"));
+        assert_eq!(directory.username_for("alice display").as_deref(), Some("alice"));
+
+        directory.joined(node, alice_id, "dev".to_string());
+        directory.left(node, alice_id, "lobby");
+        assert_eq!(directory.room_counts(), HashMap::from([("dev".to_string(), 1)]));
+        assert_eq!(directory.room_members("dev")[0].username, "alice");
+
+        directory.forget_room("dev");
+        assert!(directory.room_counts().is_empty());
+
+        directory.session_closed(node, alice_id);
+        assert!(!directory.is_online("alice"));
+    }
+
+    #[test]
+    fn heartbeat_replaces_a_nodes_sessions() {
+        let mut directory = Directory::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        directory.session_opened(first, session("alice", &["lobby"]));
+        directory.session_opened(second, session("bob", &["lobby"]));
+
+        directory.node_alive(first, vec![session("carol", &[])]);
+        assert!(!directory.is_online("alice"));
+        assert!(directory.is_online("carol"));
+        assert!(directory.is_online("bob"), "other nodes are untouched");
+        assert_eq!(directory.room_counts().get("lobby"), Some(&1));
+    }
+
+    #[test]
+    fn live_nodes_are_not_expired() {
+        let mut directory = Directory::default();
+        directory.session_opened(Uuid::new_v4(), session("alice", &["lobby"]));
+        assert!(directory.expire().is_empty());
+        assert!(directory.is_online("alice"));
+    }
+}
@@ -1,13 +1,32 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use ts_rs::TS;
 use uuid::Uuid;
 use crate::state::{ RoomUser, RoomMessage};
+use crate::search::SearchHit;
+use crate::uploads::FileAttachment;
+use crate::catalog::RoomDetails;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
 #[serde(tag = "type", content = "payload", rename_all = "snake_case")]
 pub enum ClientEvent {
+    Hello { protocol_version: u32, capabilities: Vec<String> },
+    /// First frame of a connection that didn't send its token with the upgrade
+    Authenticate { token: String },
+    /// Opens an existing room and makes the sender a member. Unknown rooms are `NotFound`.
     JoinRoom(String),
     SendMessage(String),
+    /// Gives up membership of the room and stops viewing it
     LeaveRoom(String),
+    /// Stops viewing the room but stays a member
+    CloseRoom(String),
+    /// Creates a room owned by the sender, who becomes its first member
+    CreateRoom { name: String, description: Option<String>, is_private: bool },
+    /// Changes a room's description or privacy; fields left out are kept.
+    /// An empty description clears it. Rooms cannot be renamed.
+    UpdateRoom { name: String, description: Option<String>, is_private: Option<bool> },
+    /// Deletes a room with its messages and memberships
+    DeleteRoom { name: String },
     ChangeDisplayname{ #[serde(rename = "displayName")] display_name: String },
     PrivateMessage{ payload: String, target_username: String },
     ServerBroadcast{ payload: String },
@@ -19,25 +38,106 @@ pub enum ClientEvent {
     UpdateStatus(String),
     SubscribeToProfile { user_id: i32 },
     UnsubscribeFromProfile { user_id: i32 },
+    LoadHistory { room_name: String, before_id: Option<i32>, limit: Option<u32> },
+    PinMessage { room_name: String, message_id: i32 },
+    UnpinMessage { room_name: String, message_id: i32 },
+    SearchMessages {
+        query: String,
+        room_name: Option<String>,
+        from_user: Option<String>,
+        before: Option<String>,
+        after: Option<String>,
+        limit: Option<u32>
+    },
+    BeginUpload {
+        upload_id: Option<Uuid>,
+        file_name: String,
+        #[ts(type = "number")]
+        size: u64,
+        mime_type: String,
+        room_name: Option<String>,
+        target_username: Option<String>
+    },
+    CancelUpload { upload_id: Uuid },
+    /// Upload chunk for msgpack sessions, where binary frames carry events
+    UploadChunk {
+        upload_id: Uuid,
+        #[ts(type = "number")]
+        offset: u64,
+        checksum: u32,
+        #[schemars(with = "Vec<u8>")]
+        #[ts(type = "number[] | Uint8Array")]
+        data: serde_bytes::ByteBuf
+    },
+    /// Swaps in a fresh token before the current one expires
+    RefreshToken { token: String },
+}
+
+/// Stable wire names for `ServerEvent::Error`. Never rename a variant, add a new one instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema, TS)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidPayload,
+    Unauthorized,
+    NotInRoom,
+    Forbidden,
+    NotFound,
+    RateLimited,
+    UserOffline,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    UnsupportedVersion,
+    BackendUnavailable,
+    Internal,
+    AlreadyExists,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl ErrorCode {
+    /// The HTTP-style code protocol version 1 clients expect
+    pub fn legacy_code(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidPayload | ErrorCode::UserOffline | ErrorCode::UnsupportedVersion => "400",
+            ErrorCode::Unauthorized => "401",
+            ErrorCode::NotInRoom | ErrorCode::Forbidden => "403",
+            ErrorCode::NotFound => "404",
+            ErrorCode::AlreadyExists => "409",
+            ErrorCode::PayloadTooLarge => "413",
+            ErrorCode::UnsupportedMediaType => "415",
+            ErrorCode::RateLimited => "429",
+            ErrorCode::BackendUnavailable | ErrorCode::Internal => "500",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
 pub struct RoomListEntry {
     pub name: String,
     pub count: usize,
+    pub description: Option<String>,
+    pub is_private: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerEvent{
+    /// Sent to version 1 clients instead of `Welcome`
     IdentityAnnounced{ payload: String },
+    Welcome{
+        session_id: Uuid,
+        protocol_version: u32,
+        supported_versions: Vec<u32>,
+        features: Vec<String>
+    },
     SendMessage { 
         payload: String, 
         from_id: Uuid, 
         from_username: String, 
         from_display_name: String,
         created_at: String,
-        edited_at: Option<String>
+        edited_at: Option<String>,
+        mentions: Vec<String>,
+        message_type: String,
+        attachment: Option<FileAttachment>
     }, 
     RoomUpdate{ room_name: String, users: Vec<RoomUser> },
     PrivateMessage { 
@@ -46,16 +146,44 @@ pub enum ServerEvent{
         from_display_name: String,
         payload: String,
         created_at: String,
-        edited_at: Option<String>
+        edited_at: Option<String>,
+        attachment: Option<FileAttachment>
+    },
+    Error{
+        code: ErrorCode,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[ts(optional, type = "unknown")]
+        details: Option<serde_json::Value>
     },
-    Error{ code: String, message: String },
     DisplaynameChanged{ old: String, new: String },
     UserJoined{ room_name: String, username: String },
     UserLeft{ room_name: String, username: String },
     RoomList{rooms: Vec<RoomListEntry>},
     UserStatusChanged{ username: String, status: String },
-    LoadRoomMessages{ room_name: String, messages: Vec<RoomMessage> },
+    LoadRoomMessages{ 
+        room_name: String, 
+        messages: Vec<RoomMessage>,
+        has_more: bool,
+        next_cursor: Option<i32>
+    },
     UserStatusUpdate { status: String },
     RecieveUsername{username: String},
+    Mentioned{ room_name: String, message_id: Option<i32>, from: String },
+    PinsUpdated{ room_name: String, pins: Vec<RoomMessage> },
+    SearchResults{ query: String, hits: Vec<SearchHit> },
+    UploadReady{ upload_id: Uuid, #[ts(type = "number")] offset: u64, chunk_size: u32 },
+    UploadProgress{ upload_id: Uuid, #[ts(type = "number")] received: u64 },
+    UploadComplete{ upload_id: Uuid, attachment: FileAttachment },
+    /// Sent right before the server closes a session whose token ran out
+    SessionExpired{ reason: String },
+    /// `expires_at` is in seconds since the Unix epoch, `None` if the token never expires
+    TokenRefreshed{ #[ts(type = "number | null")] expires_at: Option<u64> },
+    /// Rooms the user is a member of, sent after `Welcome` and whenever that changes
+    Memberships{ rooms: Vec<String> },
+    RoomCreated{ room: RoomDetails },
+    RoomUpdated{ room: RoomDetails },
+    /// The room is gone; sessions viewing it have been taken out of it
+    RoomDeleted{ room_name: String },
     Ping,
 }
\ No newline at end of file
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+use axum::{
+    extract::{ws::Message, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Json, Response},
+};
+use futures_util::stream::{self, Stream, StreamExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+use my_websocket::events::{ClientEvent, ErrorCode, ServerEvent};
+use my_websocket::protocol::{Codec, Session, SUPPORTED_VERSIONS};
+use my_websocket::proxy::ClientIp;
+use my_websocket::state::{AppState, FallbackSession};
+use crate::ws::{authenticate, handle_client_event, open_session, run_heartbeat};
+
+/// How long `GET /poll/:session_id` waits for an event before returning an empty list
+const POLL_WAIT: Duration = Duration::from_secs(25);
+/// Most events returned by a single poll
+const MAX_POLL_BATCH: usize = 100;
+
+/// `GET /sse?protocol_version=&capabilities=` - server events as an event stream.
+/// The first event is `session` with the id and key to post with.
+pub async fn sse(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let (session_id, key, session, rx) = match start(&state, client_ip, &headers, &params, "sse", false).await {
+        Ok((session_id, key, session, Some(rx))) => (session_id, key, session, rx),
+        Ok(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        Err(response) => return response,
+    };
+
+    let hello = Event::default()
+        .event("session")
+        .json_data(serde_json::json!({ "session_id": session_id, "key": key }))
+        .unwrap();
+
+    // The guard rides along with the stream, so the session ends when the client goes away
+    let guard = SessionGuard { session_id, state };
+    let events = stream::unfold((rx, session, guard), |(mut rx, session, guard)| async move {
+        loop {
+            let msg = rx.recv().await?;
+            if let Some(Message::Text(text)) = session.outgoing(msg) {
+                return Some((Ok(Event::default().data(text)), (rx, session, guard)));
+            }
+        }
+    });
+
+    let stream: std::pin::Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>> =
+        Box::pin(stream::once(async { Ok(hello) }).chain(events));
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// `POST /poll?protocol_version=&capabilities=` - opens a long-polling session
+pub async fn open_poll(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    match start(&state, client_ip, &headers, &params, "long-poll", true).await {
+        Ok((session_id, key, _, _)) => {
+            Json(serde_json::json!({ "session_id": session_id, "key": key })).into_response()
+        }
+        Err(response) => response,
+    }
+}
+
+/// `GET /poll/:session_id?key=` - waits for events and returns everything queued
+pub async fn poll(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let (session, queue) = match lookup(&state, session_id, &params) {
+        Ok(found) => found,
+        Err(status) => return status.into_response(),
+    };
+    let Some(queue) = queue else {
+        return error_response(StatusCode::CONFLICT, ErrorCode::InvalidPayload, "Session is served over SSE", &session);
+    };
+
+    // Only one poll per session at a time; a second one waits its turn
+    let mut rx = queue.lock().await;
+    let mut events = Vec::new();
+    match time::timeout(POLL_WAIT, rx.recv()).await {
+        Ok(Some(msg)) => events.push(msg),
+        Ok(None) => return StatusCode::GONE.into_response(),
+        Err(_) => {}
+    }
+    while events.len() < MAX_POLL_BATCH {
+        match rx.try_recv() {
+            Ok(msg) => events.push(msg),
+            Err(_) => break,
+        }
+    }
+
+    let events: Vec<serde_json::Value> = events.into_iter()
+        .filter_map(|msg| session.outgoing(msg))
+        .filter_map(|msg| match msg {
+            Message::Text(text) => serde_json::from_str(&text).ok(),
+            _ => None,
+        })
+        .collect();
+    Json(events).into_response()
+}
+
+/// `POST /fallback/:session_id?key=` - a `ClientEvent` from an SSE or long-polling client
+pub async fn post_event(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+    Query(params): Query<HashMap<String, String>>,
+    body: String,
+) -> Response {
+    let session = match lookup(&state, session_id, &params) {
+        Ok((session, _)) => session,
+        Err(status) => return status.into_response(),
+    };
+
+    let event = match serde_json::from_str::<ClientEvent>(&body) {
+        Ok(ClientEvent::Hello { .. }) => {
+            return error_response(StatusCode::BAD_REQUEST, ErrorCode::InvalidPayload, "Hello is negotiated when the session is opened", &session);
+        }
+        Ok(event) => event,
+        Err(e) => {
+            println!("Failed to parse fallback event from {}: {}", session_id, e);
+            return error_response(StatusCode::BAD_REQUEST, ErrorCode::InvalidPayload, "Body could not be parsed as a client event", &session);
+        }
+    };
+
+    let tx = state.users.lock().unwrap().get(&session_id).map(|u| u.tx.clone());
+    let Some(tx) = tx else {
+        return StatusCode::GONE.into_response();
+    };
+
+    handle_client_event(event, state, session_id, tx).await;
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// `DELETE /fallback/:session_id?key=` - ends a session without waiting for the heartbeat to time out
+pub async fn close(
+    State(state): State<AppState>,
+    Path(session_id): Path<Uuid>,
+    Query(params): Query<HashMap<String, String>>,
+) -> StatusCode {
+    if let Err(status) = lookup(&state, session_id, &params) {
+        return status;
+    }
+    state.fallback_sessions.lock().unwrap().remove(&session_id);
+    StatusCode::NO_CONTENT
+}
+
+/// Authenticates, negotiates and registers a fallback session, then starts its heartbeat.
+/// The receiver is returned for SSE and kept in the registry for long-polling.
+/// There is no first frame to authenticate with, so the request has to carry the token.
+async fn start(
+    state: &AppState,
+    client_ip: IpAddr,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+    transport: &str,
+    queued: bool,
+) -> Result<(Uuid, Uuid, Session, Option<mpsc::Receiver<Message>>), Response> {
+    if !state.origin_policy.check(headers, transport, client_ip) {
+        return Err((StatusCode::FORBIDDEN, "Origin not allowed").into_response());
+    }
+
+    let token = state.token_sources.from_request(headers, params).unwrap_or_default();
+    let Some(auth) = authenticate(&token, state).await else {
+        return Err(StatusCode::UNAUTHORIZED.into_response());
+    };
+
+    // The query string stands in for `Hello`; without a version the client gets version 1
+    let session = match params.get("protocol_version").map(|v| v.parse::<u32>()) {
+        None => Session::legacy(Codec::Json),
+        Some(Ok(version)) => {
+            let capabilities: Vec<String> = params.get("capabilities")
+                .map(|c| c.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect())
+                .unwrap_or_default();
+            match Session::negotiate(Codec::Json, version, &capabilities) {
+                Ok(session) => session,
+                Err(reason) => {
+                    let error = ServerEvent::Error {
+                        code: ErrorCode::UnsupportedVersion,
+                        message: reason,
+                        details: Some(serde_json::json!({ "supported_versions": SUPPORTED_VERSIONS })),
+                    };
+                    return Err((StatusCode::BAD_REQUEST, Json(error)).into_response());
+                }
+            }
+        }
+        Some(Err(_)) => {
+            let session = Session::legacy(Codec::Json);
+            return Err(error_response(StatusCode::BAD_REQUEST, ErrorCode::InvalidPayload, "protocol_version must be a number", &session));
+        }
+    };
+
+    let (tx, rx) = mpsc::channel(100);
+    let session_id = open_session(auth, &session, transport, client_ip, tx.clone(), state).await;
+
+    let key = Uuid::new_v4();
+    let (close_tx, close_rx) = oneshot::channel();
+    let (queue, rx) = if queued {
+        (Some(Arc::new(tokio::sync::Mutex::new(rx))), None)
+    } else {
+        (None, Some(rx))
+    };
+    state.fallback_sessions.lock().unwrap().insert(session_id, FallbackSession {
+        key,
+        session: session.clone(),
+        queue,
+        _close: close_tx,
+    });
+
+    let heartbeat_state = state.clone();
+    tokio::spawn(async move {
+        let closed = async {
+            let _ = close_rx.await;
+        };
+        run_heartbeat(session_id, tx, heartbeat_state.clone(), closed).await;
+        heartbeat_state.fallback_sessions.lock().unwrap().remove(&session_id);
+    });
+
+    Ok((session_id, key, session, rx))
+}
+
+/// Finds a session and checks the caller holds its key
+#[allow(clippy::type_complexity)]
+fn lookup(
+    state: &AppState,
+    session_id: Uuid,
+    params: &HashMap<String, String>,
+) -> Result<(Session, Option<Arc<tokio::sync::Mutex<mpsc::Receiver<Message>>>>), StatusCode> {
+    let sessions = state.fallback_sessions.lock().unwrap();
+    let Some(fallback) = sessions.get(&session_id) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let key = params.get("key").and_then(|k| Uuid::parse_str(k).ok());
+    if key != Some(fallback.key) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok((fallback.session.clone(), fallback.queue.clone()))
+}
+
+/// An `error` event as the response body, shaped for the session's protocol version
+fn error_response(status: StatusCode, code: ErrorCode, message: &str, session: &Session) -> Response {
+    let error = ServerEvent::Error { code, message: message.to_string(), details: None };
+    let body = match session.outgoing(Message::Text(serde_json::to_string(&error).unwrap())) {
+        Some(Message::Text(text)) => text,
+        _ => serde_json::to_string(&error).unwrap(),
+    };
+    (status, [(axum::http::header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+/// Ends an SSE session when its stream is dropped
+struct SessionGuard {
+    session_id: Uuid,
+    state: AppState,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.state.fallback_sessions.lock().unwrap().remove(&self.session_id);
+    }
+}
@@ -0,0 +1,369 @@
+use axum::extract::ws::Message;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use my_websocket::bus::{BusEvent, Envelope};
+use my_websocket::directory::{RemoteSession, HEARTBEAT_INTERVAL};
+use my_websocket::events::ServerEvent;
+use my_websocket::state::{AppState, RoomUser, UserInfo};
+use crate::ws::room_update_payload;
+
+// Every helper here queues on this node's sessions first, then publishes the
+// same event so the other nodes can do the same for theirs.
+
+/// Sends to everyone in the room, except `except`
+pub async fn to_room(room: &str, payload: String, except: Option<Uuid>, state: &AppState) {
+    deliver(room_transmitters(room, except, state), &payload);
+    state.bus.publish(BusEvent::Room { room: room.to_string(), payload, except }).await;
+}
+
+/// Sends to every session of `username`. Returns whether one of them is on this node.
+pub async fn to_user(username: &str, payload: String, state: &AppState) -> bool {
+    let transmitters = user_transmitters(username, state);
+    let delivered = !transmitters.is_empty();
+    deliver(transmitters, &payload);
+    state.bus.publish(BusEvent::User { username: username.to_string(), payload }).await;
+    delivered
+}
+
+/// Sends to every session of the given users
+pub async fn to_members(db_user_ids: Vec<i32>, payload: String, state: &AppState) {
+    deliver(member_transmitters(&db_user_ids, state), &payload);
+    state.bus.publish(BusEvent::Members { db_user_ids, payload }).await;
+}
+
+/// Sends to every session subscribed to the profile of `db_user_id`
+pub async fn to_profile(db_user_id: i32, payload: String, state: &AppState) {
+    deliver(profile_transmitters(db_user_id, state), &payload);
+    state.bus.publish(BusEvent::Profile { db_user_id, payload }).await;
+}
+
+/// Sends to every session, except `except`
+pub async fn to_everyone(payload: String, except: Option<Uuid>, state: &AppState) {
+    deliver(all_transmitters(except, state), &payload);
+    state.bus.publish(BusEvent::Everyone { payload, except }).await;
+}
+
+/// Applies events from other nodes until the bus closes. In a cluster it also
+/// sends this node's heartbeat and drops nodes whose heartbeats stopped.
+pub async fn run(state: AppState) {
+    let mut events = state.bus.subscribe();
+    let node_id = state.bus.node_id();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+    loop {
+        tokio::select! {
+            received = events.recv() => match received {
+                Ok(envelope) if envelope.origin != node_id => apply(envelope, &state).await,
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    println!("Bus dispatcher fell behind, {} events skipped", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+
+            _ = heartbeat.tick(), if state.bus.is_clustered() => {
+                state.bus.publish(BusEvent::NodeAlive { sessions: local_sessions(&state) }).await;
+
+                // Members of a dead node vanish from the lists shown here; every node
+                // does this for itself, so the update isn't published
+                let rooms = state.directory.lock().unwrap().expire();
+                for room in rooms {
+                    let payload = room_update_payload(&room, &state);
+                    deliver(room_transmitters(&room, None, &state), &payload);
+                }
+            }
+        }
+    }
+}
+
+async fn apply(envelope: Envelope, state: &AppState) {
+    let node_id = envelope.origin;
+    match envelope.event {
+        BusEvent::Room { room, payload, except } => {
+            deliver(room_transmitters(&room, except, state), &payload);
+        }
+        BusEvent::User { username, payload } => {
+            deliver(user_transmitters(&username, state), &payload);
+        }
+        BusEvent::Members { db_user_ids, payload } => {
+            deliver(member_transmitters(&db_user_ids, state), &payload);
+        }
+        BusEvent::Profile { db_user_id, payload } => {
+            deliver(profile_transmitters(db_user_id, state), &payload);
+        }
+        BusEvent::Everyone { payload, except } => {
+            deliver(all_transmitters(except, state), &payload);
+        }
+        BusEvent::SessionOpened { session } => {
+            let username = session.user.username.clone();
+            state.directory.lock().unwrap().session_opened(node_id, session);
+            forward_pending_mentions(&username, state).await;
+        }
+        BusEvent::Joined { room, session_id } => {
+            state.directory.lock().unwrap().joined(node_id, session_id, room);
+        }
+        BusEvent::Left { room, session_id } => {
+            state.directory.lock().unwrap().left(node_id, session_id, &room);
+        }
+        BusEvent::Membership { room, db_user_id, member } => {
+            let mut memberships = state.memberships.lock().unwrap();
+            if member {
+                memberships.add(&room, db_user_id);
+            } else {
+                memberships.remove(&room, db_user_id);
+            }
+        }
+        BusEvent::RoomCreated { room } => {
+            if let Some(owner_id) = room.owner_id {
+                state.memberships.lock().unwrap().grant(&room.name, owner_id, "owner");
+            }
+            state.catalog.lock().unwrap().insert(room);
+        }
+        BusEvent::RoomUpdated { room } => {
+            state.catalog.lock().unwrap().insert(room);
+        }
+        BusEvent::RoomDeleted { room_name } => {
+            forget_room(&room_name, state);
+        }
+        BusEvent::Presence { session_id, user } => {
+            state.directory.lock().unwrap().update_user(node_id, session_id, user);
+        }
+        BusEvent::SessionClosed { session_id } => {
+            state.directory.lock().unwrap().session_closed(node_id, session_id);
+        }
+        BusEvent::NodeAlive { sessions } => {
+            state.directory.lock().unwrap().node_alive(node_id, sessions);
+        }
+    }
+}
+
+/// Mentions queued here while a user was offline, sent on to the node they just connected to
+async fn forward_pending_mentions(username: &str, state: &AppState) {
+    let pending = state.mention_inbox.lock().unwrap().remove(username).unwrap_or_default();
+    for mention in pending {
+        let out_event = ServerEvent::Mentioned {
+            room_name: mention.room_name,
+            message_id: mention.message_id,
+            from: mention.from,
+        };
+        state.bus.publish(BusEvent::User {
+            username: username.to_string(),
+            payload: serde_json::to_string(&out_event).unwrap(),
+        }).await;
+    }
+}
+
+/// Removes a deleted room from the catalog, the memberships and every session
+/// viewing it. Returns who its members were.
+pub fn forget_room(room_name: &str, state: &AppState) -> Vec<i32> {
+    state.catalog.lock().unwrap().remove(room_name);
+    state.directory.lock().unwrap().forget_room(room_name);
+    let members = state.memberships.lock().unwrap().remove_room(room_name);
+
+    let viewers = state.rooms.lock().unwrap().remove(room_name).unwrap_or_default();
+    let mut users = state.users.lock().unwrap();
+    for session_id in viewers {
+        if let Some(user) = users.get_mut(&session_id) {
+            user.rooms.remove(room_name);
+        }
+    }
+    members
+}
+
+/// What other nodes should know about a local session
+pub fn remote_session(session_id: Uuid, state: &AppState) -> Option<RemoteSession> {
+    let users = state.users.lock().unwrap();
+    users.get(&session_id).map(|info| RemoteSession {
+        session_id,
+        db_user_id: info.db_user_id,
+        user: to_room_user(info),
+        rooms: info.rooms.clone(),
+    })
+}
+
+fn local_sessions(state: &AppState) -> Vec<RemoteSession> {
+    let users = state.users.lock().unwrap();
+    users.values()
+        .map(|info| RemoteSession {
+            session_id: info.session_id,
+            db_user_id: info.db_user_id,
+            user: to_room_user(info),
+            rooms: info.rooms.clone(),
+        })
+        .collect()
+}
+
+/// The `RoomUser` other nodes should show for a local session
+pub fn room_user(session_id: Uuid, state: &AppState) -> Option<RoomUser> {
+    let users = state.users.lock().unwrap();
+    users.get(&session_id).map(to_room_user)
+}
+
+fn to_room_user(info: &UserInfo) -> RoomUser {
+    RoomUser {
+        username: info.username.clone(),
+        display_name: info.display_name.clone(),
+        avatar_url: info.avatar_url.clone(),
+        status: info.status.clone(),
+    }
+}
+
+/// Queues without waiting, so a session that stopped reading its queue can't
+/// hold up the sender or the bus dispatcher. Such a session misses the event;
+/// it stops answering pings too and its heartbeat tears it down.
+fn deliver(transmitters: Vec<mpsc::Sender<Message>>, payload: &str) {
+    for tx in transmitters {
+        if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(Message::Text(payload.to_string())) {
+            println!("Dropped an event for a session whose queue is full");
+        }
+    }
+}
+
+fn room_transmitters(room: &str, except: Option<Uuid>, state: &AppState) -> Vec<mpsc::Sender<Message>> {
+    let rooms = state.rooms.lock().unwrap();
+    let users = state.users.lock().unwrap();
+    rooms.get(room)
+        .map(|members| members.iter()
+            .filter(|id| Some(**id) != except)
+            .filter_map(|id| users.get(id).map(|info| info.tx.clone()))
+            .collect())
+        .unwrap_or_default()
+}
+
+fn user_transmitters(username: &str, state: &AppState) -> Vec<mpsc::Sender<Message>> {
+    let users = state.users.lock().unwrap();
+    users.values()
+        .filter(|u| u.username == username)
+        .map(|u| u.tx.clone())
+        .collect()
+}
+
+fn member_transmitters(db_user_ids: &[i32], state: &AppState) -> Vec<mpsc::Sender<Message>> {
+    let users = state.users.lock().unwrap();
+    users.values()
+        .filter(|u| db_user_ids.contains(&u.db_user_id))
+        .map(|u| u.tx.clone())
+        .collect()
+}
+
+fn profile_transmitters(db_user_id: i32, state: &AppState) -> Vec<mpsc::Sender<Message>> {
+    let sessions = {
+        let subs = state.profile_subscribers.lock().unwrap();
+        subs.get(&db_user_id).cloned().unwrap_or_default()
+    };
+    let users = state.users.lock().unwrap();
+    sessions.iter()
+        .filter_map(|id| users.get(id).map(|u| u.tx.clone()))
+        .collect()
+}
+
+fn all_transmitters(except: Option<Uuid>, state: &AppState) -> Vec<mpsc::Sender<Message>> {
+    let users = state.users.lock().unwrap();
+    users.iter()
+        .filter(|(id, _)| Some(**id) != except)
+        .map(|(_, info)| info.tx.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use my_websocket::bus::Bus;
+    use crate::ws::tests::{add_session, test_state, test_state_on};
+
+    #[tokio::test]
+    async fn stuck_session_does_not_block_delivery() {
+        let state = test_state().await;
+        let (stuck, _stuck_rx) = add_session(&state, "stuck", 1);
+        let (reader, mut reader_rx) = add_session(&state, "reader", 2);
+        state.rooms.lock().unwrap().insert("lobby".to_string(), [stuck, reader].into());
+
+        // Well past the stuck session's queue, which nobody drains
+        let sent = async {
+            for i in 0..250 {
+                to_room("lobby", format!("\"{}\"", i), None, &state).await;
+                while reader_rx.try_recv().is_ok() {}
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), sent).await
+            .expect("delivery waited on a full queue");
+    }
+
+    /// Waits for the next frame queued on a session
+    async fn next_frame(rx: &mut mpsc::Receiver<Message>) -> String {
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await {
+            Ok(Some(Message::Text(text))) => text,
+            other => panic!("expected a frame, got {:?}", other),
+        }
+    }
+
+    /// Polls until `check` holds, for events that only change the directory
+    async fn eventually(check: impl Fn() -> bool) {
+        for _ in 0..50 {
+            if check() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("condition never held");
+    }
+
+    fn remote(session_id: Uuid, username: &str, room: &str) -> RemoteSession {
+        RemoteSession {
+            session_id,
+            db_user_id: 42,
+            user: RoomUser {
+                username: username.to_string(),
+                display_name: username.to_string(),
+                avatar_url: None,
+                status: "online".to_string(),
+            },
+            rooms: [room.to_string()].into(),
+        }
+    }
+
+    /// Two nodes on one Redis channel, against the server in `REDIS_URL`.
+    /// Run with `REDIS_URL=redis://... cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs a Redis server in REDIS_URL"]
+    async fn nodes_share_traffic_and_presence_over_redis() {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL must point at a Redis server");
+        let channel = format!("nexus:test:{}", Uuid::new_v4());
+        let a = test_state_on(Bus::redis(&url, channel.clone()).await.unwrap()).await;
+        let b = test_state_on(Bus::redis(&url, channel).await.unwrap()).await;
+        tokio::spawn(run(a.clone()));
+        tokio::spawn(run(b.clone()));
+        // Let both subscriptions settle before publishing
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let (alice, mut alice_rx) = add_session(&a, "alice", 1);
+        a.rooms.lock().unwrap().insert("lobby".to_string(), [alice].into());
+
+        // Room traffic reaches the other node's sessions, but not past `except`
+        to_room("lobby", "\"hello\"".to_string(), None, &b).await;
+        assert_eq!(next_frame(&mut alice_rx).await, "\"hello\"");
+        to_room("lobby", "\"skipped\"".to_string(), Some(alice), &b).await;
+        to_user("alice", "\"direct\"".to_string(), &b).await;
+        assert_eq!(next_frame(&mut alice_rx).await, "\"direct\"");
+
+        // Sessions announced by a node show up elsewhere until they close
+        let bob = Uuid::new_v4();
+        b.bus.publish(BusEvent::SessionOpened { session: remote(bob, "bob", "lobby") }).await;
+        eventually(|| a.directory.lock().unwrap().is_online("bob")).await;
+        assert_eq!(a.directory.lock().unwrap().room_counts().get("lobby"), Some(&1));
+        b.bus.publish(BusEvent::SessionClosed { session_id: bob }).await;
+        eventually(|| !a.directory.lock().unwrap().is_online("bob")).await;
+
+        // A heartbeat replaces everything known about the node
+        let carol = remote(Uuid::new_v4(), "carol", "lobby");
+        b.bus.publish(BusEvent::NodeAlive { sessions: vec![carol] }).await;
+        eventually(|| a.directory.lock().unwrap().is_online("carol")).await;
+        b.bus.publish(BusEvent::NodeAlive { sessions: Vec::new() }).await;
+        eventually(|| !a.directory.lock().unwrap().is_online("carol")).await;
+
+        // A node ignores its own events
+        assert!(!b.directory.lock().unwrap().is_online("carol"));
+    }
+}
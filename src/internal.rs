@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+
+/// How this server authenticates itself to the Node backend, and how it
+/// checks the backend's certificate in return.
+pub struct InternalConfig {
+    /// Allows `tls_insecure` and falls back to it when no CA is pinned
+    pub dev_mode: bool,
+    /// Skip certificate checks on backend calls. Refused outside dev mode.
+    pub tls_insecure: bool,
+    /// Only trust backend certificates issued by this CA
+    pub ca_cert: Option<PathBuf>,
+    /// Client certificate and PKCS#8 key presented for mutual TLS
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+    /// Sent as `Authorization: Bearer` on every backend request
+    pub api_secret: Option<String>,
+}
+
+impl InternalConfig {
+    /// Reads `DEV_MODE`, `INTERNAL_TLS_INSECURE`, `INTERNAL_CA_CERT`,
+    /// `INTERNAL_CLIENT_CERT`/`INTERNAL_CLIENT_KEY` and `INTERNAL_API_SECRET`
+    pub fn from_env() -> Self {
+        let flag = |key: &str| std::env::var(key).is_ok_and(|v| matches!(v.as_str(), "1" | "true" | "on"));
+        let path = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty()).map(PathBuf::from);
+
+        InternalConfig {
+            dev_mode: flag("DEV_MODE"),
+            tls_insecure: flag("INTERNAL_TLS_INSECURE"),
+            ca_cert: path("INTERNAL_CA_CERT"),
+            client_cert: path("INTERNAL_CLIENT_CERT").zip(path("INTERNAL_CLIENT_KEY")),
+            api_secret: std::env::var("INTERNAL_API_SECRET").ok().filter(|v| !v.is_empty()),
+        }
+    }
+
+    /// Builds the client used for every backend call. Fails rather than
+    /// silently skipping certificate checks in production.
+    pub fn http_client(&self) -> Result<reqwest::Client, String> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(ca_path) = &self.ca_cert {
+            let pem = std::fs::read(ca_path).map_err(|e| format!("{}: {}", ca_path.display(), e))?;
+            let ca = reqwest::Certificate::from_pem(&pem).map_err(|e| format!("{}: {}", ca_path.display(), e))?;
+            builder = builder.tls_built_in_root_certs(false).add_root_certificate(ca);
+        }
+
+        if let Some((cert_path, key_path)) = &self.client_cert {
+            let cert = std::fs::read(cert_path).map_err(|e| format!("{}: {}", cert_path.display(), e))?;
+            let key = std::fs::read(key_path).map_err(|e| format!("{}: {}", key_path.display(), e))?;
+            let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key).map_err(|e| format!("client certificate: {}", e))?;
+            builder = builder.identity(identity);
+        }
+
+        let insecure = self.tls_insecure || (self.dev_mode && self.ca_cert.is_none());
+        if insecure {
+            if !self.dev_mode {
+                return Err("INTERNAL_TLS_INSECURE is only allowed with DEV_MODE=1".to_string());
+            }
+            println!("WARNING: backend certificates are not verified (dev mode)");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        match &self.api_secret {
+            Some(secret) => {
+                let mut value = HeaderValue::from_str(&format!("Bearer {}", secret))
+                    .map_err(|_| "INTERNAL_API_SECRET contains invalid characters".to_string())?;
+                value.set_sensitive(true);
+                let mut headers = HeaderMap::new();
+                headers.insert(AUTHORIZATION, value);
+                builder = builder.default_headers(headers);
+            }
+            None if !self.dev_mode => return Err("INTERNAL_API_SECRET is required outside dev mode".to_string()),
+            None => println!("WARNING: INTERNAL_API_SECRET is not set, backend calls are unauthenticated"),
+        }
+
+        builder.build().map_err(|e| e.to_string())
+    }
+}
@@ -1,2 +1,20 @@
 pub mod events;
 pub mod state;
+pub mod mentions;
+pub mod search;
+pub mod uploads;
+pub mod protocol;
+pub mod schema;
+pub mod compression;
+pub mod auth;
+pub mod origin;
+pub mod internal;
+pub mod tls;
+pub mod proxy;
+pub mod listen;
+pub mod bus;
+pub mod directory;
+pub mod outbox;
+pub mod backend;
+pub mod membership;
+pub mod catalog;
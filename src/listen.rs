@@ -0,0 +1,205 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Where and how the server accepts connections
+pub struct ListenConfig {
+    /// `None` when only the Unix socket is served
+    pub addr: Option<SocketAddr>,
+    /// Serve plain `ws://` and `http://`, for when TLS terminates at a load balancer
+    pub plain: bool,
+    /// Unix domain socket for an ingress sidecar, always plain HTTP
+    pub unix: Option<UnixSocketConfig>,
+}
+
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    /// Permission bits of the socket file
+    pub mode: u32,
+}
+
+impl ListenConfig {
+    /// Reads `BIND_ADDR` (default `127.0.0.1:3000`, `none` to disable TCP), `LISTEN_MODE`
+    /// (`tls` or `plain`), `UNIX_SOCKET_PATH` and `UNIX_SOCKET_MODE` (octal, default `660`)
+    pub fn from_env() -> Self {
+        let addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "127.0.0.1:3000".to_string());
+        let addr = match addr.as_str() {
+            "none" | "" => None,
+            _ => Some(addr.parse().unwrap_or_else(|_| panic!("Invalid BIND_ADDR {}", addr))),
+        };
+
+        let unix = std::env::var("UNIX_SOCKET_PATH").ok().filter(|p| !p.is_empty()).map(|path| {
+            let mode = std::env::var("UNIX_SOCKET_MODE").unwrap_or_else(|_| "660".to_string());
+            UnixSocketConfig {
+                path: PathBuf::from(path),
+                mode: u32::from_str_radix(&mode, 8).unwrap_or_else(|_| panic!("Invalid UNIX_SOCKET_MODE {}", mode)),
+            }
+        });
+
+        if addr.is_none() && unix.is_none() {
+            panic!("BIND_ADDR=none needs UNIX_SOCKET_PATH, otherwise there is nothing to listen on");
+        }
+
+        ListenConfig {
+            addr,
+            plain: std::env::var("LISTEN_MODE").is_ok_and(|m| m == "plain"),
+            unix,
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{bind_unix, serve_unix};
+
+#[cfg(unix)]
+mod unix {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use axum::{Extension, Router};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto::Builder;
+    use hyper_util::service::TowerToHyperService;
+    use tokio::net::UnixListener;
+    use tokio::sync::watch;
+    use tower_layer::Layer;
+
+    use super::UnixSocketConfig;
+    use crate::proxy::{read_proxy_header, ProxiedAddr, HEADER_TIMEOUT};
+
+    /// Pause after a failed accept, so running out of file descriptors doesn't spin the loop
+    const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+    /// Binds the socket and restricts who may connect. A socket left behind
+    /// by a previous run is replaced, but never some other file.
+    pub fn bind_unix(config: &UnixSocketConfig) -> std::io::Result<UnixListener> {
+        if let Ok(meta) = std::fs::symlink_metadata(&config.path) {
+            if !meta.file_type().is_socket() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", config.path.display()),
+                ));
+            }
+            std::fs::remove_file(&config.path)?;
+        }
+
+        // Bind inside a directory only we can enter, so the socket is
+        // unreachable until its mode is set, whatever the umask is
+        let parent = match config.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let staging = parent.join(format!(".socket-{}", std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+        let result = bind_staged(&staging, config);
+        let _ = std::fs::remove_dir(&staging);
+        result
+    }
+
+    /// Binds in `staging`, sets the mode and moves the socket into place
+    fn bind_staged(staging: &Path, config: &UnixSocketConfig) -> std::io::Result<UnixListener> {
+        let staged = staging.join("socket");
+        let listener = UnixListener::bind(&staged)?;
+        let placed = std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(config.mode))
+            .and_then(|()| std::fs::rename(&staged, &config.path));
+        if let Err(e) = placed {
+            let _ = std::fs::remove_file(&staged);
+            return Err(e);
+        }
+        Ok(listener)
+    }
+
+    /// Serves `app` on `listener`, bound at `path`, until `shutdown` flips to true.
+    /// Requests carry no `ConnectInfo`; `ClientIp` falls back to the forwarded headers.
+    pub async fn serve_unix(
+        listener: UnixListener,
+        path: PathBuf,
+        app: Router,
+        proxy_protocol: bool,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        // 1. Accept until shutdown
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        println!("Unix socket accept failed: {}", e);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                },
+                _ = shutdown.changed() => break,
+            };
+
+            let app = app.clone();
+            let mut shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let mut stream = stream;
+                let source = if proxy_protocol {
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_proxy_header(&mut stream)).await {
+                        Ok(Ok(source)) => source,
+                        Ok(Err(e)) => {
+                            println!("Dropping unix connection: {}", e);
+                            return;
+                        }
+                        Err(_) => {
+                            println!("Dropping unix connection: no PROXY header");
+                            return;
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let service = TowerToHyperService::new(Extension(ProxiedAddr(source)).layer(app));
+                let builder = Builder::new(TokioExecutor::new());
+                let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                tokio::pin!(conn);
+                let result = tokio::select! {
+                    result = conn.as_mut() => result,
+                    _ = shutdown.changed() => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(e) = result {
+                    println!("Unix connection error: {}", e);
+                }
+            });
+        }
+
+        // 2. Remove the socket so the ingress stops sending to us
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use super::{bind_unix, UnixSocketConfig};
+
+    #[tokio::test]
+    async fn socket_is_placed_with_its_mode() {
+        let dir = std::env::temp_dir().join(format!("listen-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let config = UnixSocketConfig { path: dir.join("app.sock"), mode: 0o600 };
+
+        let listener = bind_unix(&config).unwrap();
+        let mode = std::fs::metadata(&config.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only the socket is left behind, not the staging directory
+        let entries: Vec<PathBuf> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(entries, vec![config.path.clone()]);
+
+        // A socket from an earlier run is replaced, any other file is not
+        drop(listener);
+        bind_unix(&config).unwrap();
+        std::fs::remove_file(&config.path).unwrap();
+        std::fs::write(&config.path, "data").unwrap();
+        assert!(bind_unix(&config).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,28 +1,74 @@
 use axum::{
     extract::{ws::WebSocketUpgrade, State},
-    response::Response,
-    routing::any,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::{any, get, post},
     Router,
 };
 use std::path::PathBuf;
 use axum_server::Handle;
 use std::time::Duration;
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use std::net::SocketAddr;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use tokio::sync::broadcast;
+use tokio::sync::watch;
 
 use my_websocket::state::AppState;
+use my_websocket::bus::Bus;
+use my_websocket::directory::Directory;
+use my_websocket::outbox::{Outbox, OutboxConfig};
+use my_websocket::backend::{Backend, BackendConfig};
+use my_websocket::membership::{self, Memberships};
+use my_websocket::catalog::RoomCatalog;
+use my_websocket::search::SearchBackend;
+use my_websocket::auth::{Authenticator, TokenSources};
+use my_websocket::origin::OriginPolicy;
+use my_websocket::internal::InternalConfig;
+use my_websocket::tls;
+use my_websocket::listen::ListenConfig;
+#[cfg(unix)]
+use my_websocket::listen::{bind_unix, serve_unix};
+use my_websocket::proxy::{ClientIp, ProxyConfig, ProxyProtocolAcceptor};
+use my_websocket::uploads::{self, download_file, UploadConfig};
+use my_websocket::protocol::{offered_protocols, wants_deflate, Codec};
+use my_websocket::compression::{CompressionConfig, CompressionStats};
 mod ws;
+mod fallback;
+mod fanout;
 use crate::ws::handle_socket;
 
 async fn handler(
     ws: WebSocketUpgrade, 
     State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
     axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
 ) -> Response {
-    let token = params.get("token").cloned().unwrap_or_default();
-    ws.on_upgrade(move |socket| handle_socket(socket, state, token))
+    if !state.origin_policy.check(&headers, "/ws", client_ip) {
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+
+    let token = state.token_sources.from_request(&headers, &params);
+    if token.is_none() && !state.token_sources.message {
+        println!("Connection from {} rejected: No token provided", client_ip);
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let protocols = offered_protocols(state.compression_config.enabled);
+    ws.protocols(protocols).on_upgrade(move |socket| {
+        let protocol = socket.protocol().and_then(|p| p.to_str().ok());
+        let codec = Codec::from_protocol(protocol);
+        let deflate = wants_deflate(protocol);
+        handle_socket(socket, state, token, client_ip, codec, deflate)
+    })
+}
+
+async fn metrics(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "compression": state.compression_stats.snapshot(),
+        "outbox": state.outbox.snapshot(),
+        "backend": state.backend.snapshot(),
+    }))
 }
 
 #[tokio::main]
@@ -31,44 +77,137 @@ async fn main() {
 
     let users = Arc::new(Mutex::new(HashMap::new()));
     let rooms = Arc::new(Mutex::new(HashMap::new()));
-    let (tx, _rx) = broadcast::channel(100);
+    let bus = Bus::from_env().await.unwrap_or_else(|e| panic!("Refusing to start: {}", e));
+    let http_client = InternalConfig::from_env()
+        .http_client()
+        .unwrap_or_else(|e| panic!("Refusing to start: {}", e));
+    let backend = Arc::new(Backend::new(BackendConfig::from_env(), http_client));
+    let outbox = Outbox::open(OutboxConfig::from_env(), backend.clone())
+        .await
+        .unwrap_or_else(|e| panic!("Refusing to start: outbox: {}", e));
+    let memberships = match Memberships::load(&backend).await {
+        Ok(memberships) => {
+            println!("Loaded {} room memberships", memberships.len());
+            memberships
+        }
+        // The periodic resync fills it in once the API is reachable
+        Err(e) => {
+            println!("Failed to load room memberships, starting empty: {}", e);
+            Memberships::default()
+        }
+    };
+    let catalog = match RoomCatalog::load(&backend).await {
+        Ok(catalog) => {
+            println!("Loaded {} rooms", catalog.len());
+            catalog
+        }
+        // Retried on the first join
+        Err(e) => {
+            println!("Failed to load rooms: {}", e);
+            RoomCatalog::default()
+        }
+    };
     let state = AppState {
         users,
         rooms,
+        memberships: Arc::new(Mutex::new(memberships)),
+        catalog: Arc::new(Mutex::new(catalog)),
         profile_subscribers: Arc::new(Mutex::new(HashMap::new())),
-        _tx: tx,
-        http_client: reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
-            .build()
-            .unwrap(),
+        mention_inbox: Arc::new(Mutex::new(HashMap::new())),
+        directory: Arc::new(Mutex::new(Directory::default())),
+        bus: Arc::new(bus),
+        outbox,
+        search: Arc::new(SearchBackend::from_env(backend.clone())),
+        auth: Arc::new(Authenticator::from_env(backend.clone())),
+        token_sources: Arc::new(TokenSources::from_env()),
+        origin_policy: Arc::new(OriginPolicy::from_env()),
+        proxy_config: Arc::new(ProxyConfig::from_env()),
+        backend,
+        uploads: Arc::new(Mutex::new(HashMap::new())),
+        upload_config: Arc::new(UploadConfig::from_env()),
+        compression_config: Arc::new(CompressionConfig::from_env()),
+        compression_stats: Arc::new(CompressionStats::default()),
+        fallback_sessions: Arc::new(Mutex::new(HashMap::new())),
     };
 
+    tokio::spawn(fanout::run(state.clone()));
+    tokio::spawn(uploads::sweep(state.clone()));
+    tokio::spawn(membership::sync(state.memberships.clone(), state.backend.clone(), state.outbox.clone()));
+
+    let acceptor = ProxyProtocolAcceptor { enabled: state.proxy_config.proxy_protocol };
     let app = Router::new()
         .route("/ws", any(handler)) 
+        .route("/files/:id", get(download_file))
+        .route("/metrics", get(metrics))
+        .route("/sse", get(fallback::sse))
+        .route("/poll", post(fallback::open_poll))
+        .route("/poll/:session_id", get(fallback::poll))
+        .route("/fallback/:session_id", post(fallback::post_event).delete(fallback::close))
+        .layer(state.origin_policy.cors_layer())
         .with_state(state);   
 
-    let config = RustlsConfig::from_pem_file(
-        PathBuf::from("certs/cert.pem"),
-        PathBuf::from("certs/key.pem"),
-    )
-    .await
-    .unwrap();
+    let listen = ListenConfig::from_env();
+
+    let cert_path = PathBuf::from("certs/cert.pem");
+    let key_path = PathBuf::from("certs/key.pem");
+    let config = if listen.plain || listen.addr.is_none() {
+        None
+    } else {
+        let config = RustlsConfig::from_pem_file(&cert_path, &key_path)
+            .await
+            .unwrap();
+        tokio::spawn(tls::watch(config.clone(), cert_path, key_path));
+        Some(config)
+    };
 
     let handle = Handle::new();
     let shutdown_handle = handle.clone();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
     tokio::spawn(async move {
         shutdown_signal().await;
         shutdown_handle.graceful_shutdown(Some(Duration::from_secs(30)));
+        let _ = shutdown_tx.send(true);
+    });
+
+    #[cfg(not(unix))]
+    if listen.unix.is_some() {
+        panic!("UNIX_SOCKET_PATH is only supported on Unix");
+    }
+    #[cfg(unix)]
+    let unix_server = listen.unix.as_ref().map(|unix| {
+        let listener = bind_unix(unix)
+            .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", unix.path.display(), e));
+        println!("Server listening on unix:{} (mode {:o})", unix.path.display(), unix.mode);
+        tokio::spawn(serve_unix(listener, unix.path.clone(), app.clone(), acceptor.enabled, shutdown_rx))
     });
 
-    let addr = "127.0.0.1:3000".parse().unwrap();
-    println!("Server running at https://{}", addr);
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match (listen.addr, config) {
+        (None, _) => {}
+        (Some(addr), None) => {
+            println!("Server running at http://{} (TLS terminated upstream)", addr);
+            axum_server::bind(addr)
+                .acceptor(acceptor)
+                .handle(handle)
+                .serve(make_service)
+                .await
+                .unwrap();
+        }
+        (Some(addr), Some(config)) => {
+            println!("Server running at https://{}", addr);
+            axum_server::bind(addr)
+                .acceptor(RustlsAcceptor::new(config).acceptor(acceptor))
+                .handle(handle)
+                .serve(make_service)
+                .await
+                .unwrap();
+        }
+    }
 
-    axum_server::bind_rustls(addr, config)
-        .handle(handle)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    #[cfg(unix)]
+    if let Some(server) = unix_server {
+        let _ = server.await;
+    }
 }
 
 async fn shutdown_signal() {
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::backend::{Backend, Endpoint};
+use crate::outbox::Outbox;
+
+/// How often the index is reloaded from `room_members`, dropping memberships
+/// removed outside this server and any write that never made it
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(300);
+/// Role given to users who join a room by themselves
+pub const DEFAULT_ROLE: &str = "member";
+
+/// Who belongs to which room, mirrored from the `room_members` table.
+/// Membership outlives sessions; which sessions are currently viewing a
+/// room is tracked separately in `AppState.rooms`.
+#[derive(Default)]
+pub struct Memberships {
+    /// Room name -> member database ids and their role
+    rooms: HashMap<String, HashMap<i32, String>>,
+    /// Changes made since a reload started, replayed onto the reloaded table
+    journal: Option<Vec<Change>>,
+}
+
+/// A local change to the index, as recorded while a reload is in flight
+#[derive(Debug, Clone)]
+enum Change {
+    Grant { room: String, db_user_id: i32, role: String },
+    Remove { room: String, db_user_id: i32 },
+    RemoveRoom { room: String },
+}
+
+impl Memberships {
+    /// Reads every membership from the backend
+    pub async fn load(backend: &Backend) -> Result<Self, String> {
+        let response = backend
+            .send(Endpoint::Memberships, |c| c.get("https://localhost:443/internal/memberships"))
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP {}", response.status()));
+        }
+        let data = response.json::<serde_json::Value>().await.map_err(|e| e.to_string())?;
+
+        let mut memberships = Memberships::default();
+        for m in data["memberships"].as_array().into_iter().flatten() {
+            let (Some(room), Some(db_user_id)) = (m["room"].as_str(), m["userId"].as_i64()) else { continue };
+            let role = m["role"].as_str().unwrap_or(DEFAULT_ROLE).to_string();
+            memberships.rooms.entry(room.to_string()).or_default().insert(db_user_id as i32, role);
+        }
+        Ok(memberships)
+    }
+
+    /// Returns false if the user already was a member
+    pub fn add(&mut self, room: &str, db_user_id: i32) -> bool {
+        if self.role(room, db_user_id).is_some() {
+            return false;
+        }
+        self.grant(room, db_user_id, DEFAULT_ROLE);
+        true
+    }
+
+    /// Makes the user a member with `role`, replacing any role they had
+    pub fn grant(&mut self, room: &str, db_user_id: i32, role: &str) {
+        self.record(Change::Grant { room: room.to_string(), db_user_id, role: role.to_string() });
+        self.rooms.entry(room.to_string()).or_default().insert(db_user_id, role.to_string());
+    }
+
+    /// Returns false if the user wasn't a member
+    pub fn remove(&mut self, room: &str, db_user_id: i32) -> bool {
+        self.record(Change::Remove { room: room.to_string(), db_user_id });
+        let Some(members) = self.rooms.get_mut(room) else { return false };
+        let removed = members.remove(&db_user_id).is_some();
+        if members.is_empty() {
+            self.rooms.remove(room);
+        }
+        removed
+    }
+
+    /// Drops every membership of a deleted room, returning who the members were
+    pub fn remove_room(&mut self, room: &str) -> Vec<i32> {
+        self.record(Change::RemoveRoom { room: room.to_string() });
+        self.rooms.remove(room).map(|m| m.into_keys().collect()).unwrap_or_default()
+    }
+
+    fn record(&mut self, change: Change) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(change);
+        }
+    }
+
+    /// Starts recording changes, to be kept when `replace` swaps in a reloaded table
+    pub fn begin_reload(&mut self) {
+        self.journal = Some(Vec::new());
+    }
+
+    /// Stops recording after a failed reload
+    pub fn abort_reload(&mut self) {
+        self.journal = None;
+    }
+
+    /// Swaps in `loaded`, with the changes made since `begin_reload` applied on top
+    pub fn replace(&mut self, mut loaded: Memberships) {
+        for change in self.journal.take().unwrap_or_default() {
+            match change {
+                Change::Grant { room, db_user_id, role } => loaded.grant(&room, db_user_id, &role),
+                Change::Remove { room, db_user_id } => {
+                    loaded.remove(&room, db_user_id);
+                }
+                Change::RemoveRoom { room } => {
+                    loaded.remove_room(&room);
+                }
+            }
+        }
+        *self = loaded;
+    }
+
+    /// The user's role in the room, `None` if they aren't a member
+    pub fn role(&self, room: &str, db_user_id: i32) -> Option<&str> {
+        self.rooms.get(room).and_then(|m| m.get(&db_user_id)).map(String::as_str)
+    }
+
+    /// Database ids of everyone in the room
+    pub fn members(&self, room: &str) -> Vec<i32> {
+        self.rooms.get(room).map(|m| m.keys().copied().collect()).unwrap_or_default()
+    }
+
+    /// Rooms the user belongs to, sorted by name
+    pub fn rooms_of(&self, db_user_id: i32) -> Vec<String> {
+        let mut rooms: Vec<String> = self.rooms.iter()
+            .filter(|(_, members)| members.contains_key(&db_user_id))
+            .map(|(room, _)| room.clone())
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    /// Every room with at least one member
+    pub fn room_names(&self) -> impl Iterator<Item = &String> {
+        self.rooms.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rooms.values().map(|m| m.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+}
+
+/// Reloads the index every `SYNC_INTERVAL`. Changes made while the load is in
+/// flight are replayed onto the result, and the reload is skipped while the
+/// outbox still holds writes, since it would undo them until they land.
+pub async fn sync(memberships: Arc<Mutex<Memberships>>, backend: Arc<Backend>, outbox: Arc<Outbox>) {
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if outbox.snapshot().depth > 0 {
+            continue;
+        }
+        memberships.lock().unwrap().begin_reload();
+        match Memberships::load(&backend).await {
+            Ok(loaded) => {
+                let mut current = memberships.lock().unwrap();
+                let before = current.len();
+                current.replace(loaded);
+                if current.len() != before {
+                    println!("Room memberships resynced: {} -> {}", before, current.len());
+                }
+            }
+            Err(e) => {
+                memberships.lock().unwrap().abort_reload();
+                println!("Failed to resync room memberships: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_during_a_reload_survive_it() {
+        let mut current = Memberships::default();
+        current.add("lobby", 1);
+        current.add("lobby", 2);
+        current.add("old", 1);
+
+        current.begin_reload();
+        // The backend's view, read before the changes below reached it
+        let mut loaded = Memberships::default();
+        loaded.add("lobby", 1);
+        loaded.add("lobby", 2);
+        loaded.add("old", 1);
+        loaded.add("elsewhere", 5);
+
+        current.add("lobby", 3);
+        current.remove("lobby", 2);
+        current.grant("lobby", 1, "owner");
+        current.remove_room("old");
+        current.replace(loaded);
+
+        assert_eq!(current.role("lobby", 1), Some("owner"));
+        assert_eq!(current.role("lobby", 2), None);
+        assert_eq!(current.role("lobby", 3), Some(DEFAULT_ROLE));
+        assert!(current.members("old").is_empty());
+        assert_eq!(current.members("elsewhere"), vec![5]);
+        assert_eq!(current.len(), 3);
+
+        // Nothing is recorded once the reload is done
+        current.add("lobby", 4);
+        assert!(current.journal.is_none());
+    }
+
+    #[test]
+    fn a_plain_replace_takes_the_loaded_table() {
+        let mut current = Memberships::default();
+        current.add("lobby", 1);
+        let mut loaded = Memberships::default();
+        loaded.add("lobby", 2);
+
+        current.replace(loaded);
+        assert_eq!(current.members("lobby"), vec![2]);
+    }
+}
@@ -0,0 +1,40 @@
+/// Longest username accepted by the `users.username` column
+const MAX_USERNAME_LEN: usize = 50;
+
+/// Extracts the distinct `@username` tokens from a message, in order of appearance.
+/// An `@` only starts a mention at the beginning of a word, so emails are skipped.
+pub fn parse_mentions(text: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = Vec::new();
+    let mut prev: Option<char> = None;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let at_word_start = prev.is_none_or(|p| !is_username_char(p));
+        prev = Some(c);
+        if c != '@' || !at_word_start {
+            continue;
+        }
+
+        let start = i + 1;
+        let mut end = start;
+        while let Some(&(j, next)) = chars.peek() {
+            if !is_username_char(next) {
+                break;
+            }
+            end = j + next.len_utf8();
+            prev = Some(next);
+            chars.next();
+        }
+
+        let name = &text[start..end];
+        if !name.is_empty() && name.len() <= MAX_USERNAME_LEN && !mentions.iter().any(|m| m == name) {
+            mentions.push(name.to_string());
+        }
+    }
+
+    mentions
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
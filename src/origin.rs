@@ -0,0 +1,66 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Origins browsers may connect from. Browsers always send `Origin` on
+/// WebSocket upgrades, so checking it stops other sites from opening a
+/// socket with the user's cookie.
+pub struct OriginPolicy {
+    allowed: Vec<String>,
+    allow_any: bool,
+    /// Non-browser clients usually send no `Origin` at all
+    allow_missing: bool,
+}
+
+impl OriginPolicy {
+    /// Reads `ALLOWED_ORIGINS` (comma separated, `*` for any) and `ALLOW_MISSING_ORIGIN`
+    pub fn from_env() -> Self {
+        let allowed: Vec<String> = std::env::var("ALLOWED_ORIGINS")
+            .map(|v| v.split(',').map(normalize).filter(|o| !o.is_empty()).collect())
+            .unwrap_or_else(|_| vec!["https://localhost".to_string(), "https://127.0.0.1".to_string()]);
+
+        OriginPolicy {
+            allow_any: allowed.iter().any(|o| o == "*"),
+            allowed,
+            allow_missing: std::env::var("ALLOW_MISSING_ORIGIN")
+                .map(|v| matches!(v.as_str(), "1" | "true" | "on"))
+                .unwrap_or(true),
+        }
+    }
+
+    pub fn is_allowed(&self, origin: Option<&str>) -> bool {
+        match origin {
+            None => self.allow_missing,
+            Some(_) if self.allow_any => true,
+            Some(origin) => self.allowed.contains(&normalize(origin)),
+        }
+    }
+
+    /// Checks the request's `Origin`, logging it when rejected
+    pub fn check(&self, headers: &HeaderMap, route: &str, client_ip: IpAddr) -> bool {
+        let origin = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok());
+        let allowed = self.is_allowed(origin);
+        if !allowed {
+            println!("Rejected {} request from {} with origin {:?}", route, client_ip, origin.unwrap_or("<none>"));
+        }
+        allowed
+    }
+
+    /// CORS for the plain HTTP routes, answering only allowed origins.
+    /// Credentials are allowed so cookie-authenticated SSE and polling work.
+    pub fn cors_layer(self: &Arc<Self>) -> CorsLayer {
+        let policy = Arc::clone(self);
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+                policy.is_allowed(origin.to_str().ok())
+            }))
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+            .allow_credentials(true)
+    }
+}
+
+fn normalize(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_lowercase()
+}
@@ -0,0 +1,536 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::backend::{Backend, Endpoint};
+
+/// First retry delay; it doubles on every failure up to `OutboxConfig::max_backoff`
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+pub struct OutboxConfig {
+    pub dir: PathBuf,
+    /// Log an alert when more operations than this are waiting
+    pub alert_depth: usize,
+    pub max_backoff: Duration,
+    /// Rewrite the log with only the live entries once it holds this many done ones
+    pub compact_after: usize,
+}
+
+impl OutboxConfig {
+    /// Reads `OUTBOX_DIR`, `OUTBOX_ALERT_DEPTH`, `OUTBOX_MAX_BACKOFF` (seconds) and `OUTBOX_COMPACT_AFTER`
+    pub fn from_env() -> Self {
+        let number = |key: &str| std::env::var(key).ok().and_then(|v| v.parse::<u64>().ok());
+        OutboxConfig {
+            dir: std::env::var("OUTBOX_DIR").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("outbox")),
+            alert_depth: number("OUTBOX_ALERT_DEPTH").unwrap_or(1000) as usize,
+            max_backoff: Duration::from_secs(number("OUTBOX_MAX_BACKOFF").unwrap_or(60)),
+            compact_after: number("OUTBOX_COMPACT_AFTER").unwrap_or(1000).max(1) as usize,
+        }
+    }
+
+    fn wal_path(&self) -> PathBuf {
+        self.dir.join("outbox.wal")
+    }
+}
+
+/// A backend write that must eventually happen. Entries with the same `key`
+/// are sent in the order they were queued.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxEntry {
+    /// Sent as `Idempotency-Key`, so a retry after a lost response isn't stored twice
+    pub id: Uuid,
+    pub key: String,
+    pub url: String,
+    pub body: serde_json::Value,
+    /// Unix milliseconds
+    pub queued_at: u64,
+}
+
+/// One line of the write-ahead log
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WalRecord {
+    Append(OutboxEntry),
+    Done { id: Uuid },
+}
+
+struct Wal {
+    file: tokio::fs::File,
+    /// Appended entries not yet marked done, in log order
+    live: BTreeMap<u64, OutboxEntry>,
+    /// Position of each live entry in `live`
+    positions: HashMap<Uuid, u64>,
+    next_position: u64,
+    /// `Done` records in the log since it was last rewritten
+    done: usize,
+}
+
+impl Wal {
+    fn insert(&mut self, entry: OutboxEntry) {
+        self.positions.insert(entry.id, self.next_position);
+        self.live.insert(self.next_position, entry);
+        self.next_position += 1;
+    }
+
+    fn remove(&mut self, id: Uuid) {
+        if let Some(position) = self.positions.remove(&id) {
+            self.live.remove(&position);
+        }
+    }
+}
+
+struct Queued {
+    entry: OutboxEntry,
+    /// Receives the response body once the backend accepts the entry
+    waiter: Option<oneshot::Sender<serde_json::Value>>,
+}
+
+#[derive(Default)]
+struct OutboxStats {
+    depth: AtomicUsize,
+    delivered: AtomicU64,
+    retries: AtomicU64,
+    dropped: AtomicU64,
+    alerting: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct OutboxSnapshot {
+    pub depth: usize,
+    pub delivered: u64,
+    pub retries: u64,
+    /// Entries the backend rejected outright, which no retry would fix
+    pub dropped: u64,
+    /// Age of the longest waiting entry
+    pub oldest_secs: u64,
+    pub alert: bool,
+}
+
+/// Entries appended and never marked done. Unreadable lines are skipped:
+/// a torn write from a crash can only be the last one.
+fn replay(log: &str) -> Vec<OutboxEntry> {
+    let mut live: Vec<OutboxEntry> = Vec::new();
+    for line in log.lines().filter(|l| !l.trim().is_empty()) {
+        match serde_json::from_str::<WalRecord>(line) {
+            Ok(WalRecord::Append(entry)) => live.push(entry),
+            Ok(WalRecord::Done { id }) => live.retain(|e| e.id != id),
+            Err(e) => println!("Skipping unreadable outbox record: {}", e),
+        }
+    }
+    live
+}
+
+/// Replaces the log with `entries` through a temporary file, so a crash
+/// leaves either the old log or the new one, and opens it for appending
+async fn rewrite(path: &Path, entries: &[OutboxEntry]) -> Result<tokio::fs::File, String> {
+    let mut contents = String::new();
+    for entry in entries {
+        contents.push_str(&serde_json::to_string(&WalRecord::Append(entry.clone())).unwrap());
+        contents.push('\n');
+    }
+
+    let tmp = path.with_extension("wal.tmp");
+    let err = |e: std::io::Error| format!("{}: {}", path.display(), e);
+    let mut file = tokio::fs::File::create(&tmp).await.map_err(err)?;
+    file.write_all(contents.as_bytes()).await.map_err(err)?;
+    file.sync_data().await.map_err(err)?;
+    tokio::fs::rename(&tmp, path).await.map_err(err)?;
+
+    tokio::fs::OpenOptions::new().append(true).open(path).await.map_err(err)
+}
+
+/// Durable queue for writes to the backend. Every entry is appended to a
+/// log on disk before it is attempted, so a crash or a backend outage delays
+/// it instead of losing it.
+pub struct Outbox {
+    config: OutboxConfig,
+    backend: Arc<Backend>,
+    wal: tokio::sync::Mutex<Wal>,
+    /// Pending entries by key. A key is present exactly while its worker runs.
+    queues: Mutex<HashMap<String, VecDeque<Queued>>>,
+    stats: OutboxStats,
+}
+
+impl Outbox {
+    /// Replays the log left by the previous run and starts retrying what it still holds
+    pub async fn open(config: OutboxConfig, backend: Arc<Backend>) -> Result<Arc<Self>, String> {
+        let path = config.wal_path();
+        tokio::fs::create_dir_all(&config.dir).await.map_err(|e| format!("{}: {}", config.dir.display(), e))?;
+
+        // 1. Replay: everything appended and never marked done is still pending
+        let live = match tokio::fs::read_to_string(&path).await {
+            Ok(log) => replay(&log),
+            Err(_) => Vec::new(),
+        };
+
+        // 2. Compact the log down to the pending entries
+        let file = rewrite(&path, &live).await?;
+        let pending = live.clone();
+        let mut wal = Wal { file, live: BTreeMap::new(), positions: HashMap::new(), next_position: 0, done: 0 };
+        for entry in live {
+            wal.insert(entry);
+        }
+
+        let outbox = Arc::new(Outbox {
+            config,
+            backend,
+            wal: tokio::sync::Mutex::new(wal),
+            queues: Mutex::new(HashMap::new()),
+            stats: OutboxStats::default(),
+        });
+
+        // 3. Resume delivery
+        if !pending.is_empty() {
+            println!("Outbox: resuming {} pending operations", pending.len());
+        }
+        for entry in pending {
+            outbox.enqueue(Queued { entry, waiter: None });
+        }
+        Ok(outbox)
+    }
+
+    /// Logs a POST of `body` to `url` and queues it behind earlier entries with the same key.
+    /// The receiver gets the response body once the backend accepts it.
+    pub async fn submit(
+        self: &Arc<Self>,
+        key: String,
+        url: String,
+        body: serde_json::Value,
+    ) -> Result<oneshot::Receiver<serde_json::Value>, String> {
+        let entry = OutboxEntry {
+            id: Uuid::new_v4(),
+            key,
+            url,
+            body,
+            queued_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        };
+        self.append(&WalRecord::Append(entry.clone())).await?;
+
+        let (waiter, delivered) = oneshot::channel();
+        self.enqueue(Queued { entry, waiter: Some(waiter) });
+        Ok(delivered)
+    }
+
+    /// Whether entries for `key` are still waiting to be delivered
+    pub fn has_pending(&self, key: &str) -> bool {
+        self.queues.lock().unwrap().contains_key(key)
+    }
+
+    pub fn snapshot(&self) -> OutboxSnapshot {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let oldest = {
+            let queues = self.queues.lock().unwrap();
+            queues.values().filter_map(|q| q.front()).map(|q| q.entry.queued_at).min()
+        };
+        OutboxSnapshot {
+            depth: self.stats.depth.load(Ordering::Relaxed),
+            delivered: self.stats.delivered.load(Ordering::Relaxed),
+            retries: self.stats.retries.load(Ordering::Relaxed),
+            dropped: self.stats.dropped.load(Ordering::Relaxed),
+            oldest_secs: oldest.map(|t| now.saturating_sub(t) / 1000).unwrap_or(0),
+            alert: self.stats.alerting.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn append(&self, record: &WalRecord) -> Result<(), String> {
+        let mut line = serde_json::to_string(record).unwrap();
+        line.push('\n');
+        let mut wal = self.wal.lock().await;
+        wal.file.write_all(line.as_bytes()).await.map_err(|e| e.to_string())?;
+        wal.file.sync_data().await.map_err(|e| e.to_string())?;
+
+        match record {
+            WalRecord::Append(entry) => wal.insert(entry.clone()),
+            WalRecord::Done { id } => {
+                wal.remove(*id);
+                wal.done += 1;
+                // Compacting keeps the log bounded even when some entry is always in flight
+                if wal.live.is_empty() {
+                    wal.file.set_len(0).await.map_err(|e| e.to_string())?;
+                    wal.done = 0;
+                } else if wal.done >= self.config.compact_after {
+                    let live: Vec<OutboxEntry> = wal.live.values().cloned().collect();
+                    wal.file = rewrite(&self.config.wal_path(), &live).await?;
+                    wal.done = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn enqueue(self: &Arc<Self>, queued: Queued) {
+        let key = queued.entry.key.clone();
+        let start_worker = {
+            let mut queues = self.queues.lock().unwrap();
+            let start = !queues.contains_key(&key);
+            queues.entry(key.clone()).or_default().push_back(queued);
+            start
+        };
+
+        let depth = self.stats.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        if depth > self.config.alert_depth && !self.stats.alerting.swap(true, Ordering::Relaxed) {
+            println!("ALERT: outbox depth {} exceeds {}, is the backend down?", depth, self.config.alert_depth);
+        }
+
+        if start_worker {
+            tokio::spawn(Arc::clone(self).work(key));
+        }
+    }
+
+    /// Sends the entries of one key in order until none are left
+    async fn work(self: Arc<Self>, key: String) {
+        loop {
+            let entry = {
+                let queues = self.queues.lock().unwrap();
+                match queues.get(&key).and_then(|q| q.front()) {
+                    Some(queued) => queued.entry.clone(),
+                    None => return,
+                }
+            };
+
+            let response = self.deliver(&entry).await;
+            if let Err(e) = self.append(&WalRecord::Done { id: entry.id }).await {
+                // Not fatal: the entry is sent again after a restart and deduplicated by its key
+                println!("Failed to record outbox entry {} as done: {}", entry.id, e);
+            }
+
+            let (waiter, remaining) = {
+                let mut queues = self.queues.lock().unwrap();
+                let queue = queues.get_mut(&key).unwrap();
+                let waiter = queue.pop_front().and_then(|q| q.waiter);
+                let remaining = queue.len();
+                if remaining == 0 {
+                    queues.remove(&key);
+                }
+                (waiter, remaining)
+            };
+            if let (Some(waiter), Some(response)) = (waiter, response) {
+                let _ = waiter.send(response);
+            }
+
+            let depth = self.stats.depth.fetch_sub(1, Ordering::Relaxed) - 1;
+            if depth <= self.config.alert_depth / 2 && self.stats.alerting.swap(false, Ordering::Relaxed) {
+                println!("Outbox depth back to {}", depth);
+            }
+            if remaining == 0 {
+                return;
+            }
+        }
+    }
+
+    /// Retries until the backend accepts or rejects the entry. `None` if it was rejected.
+    async fn deliver(&self, entry: &OutboxEntry) -> Option<serde_json::Value> {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let result = self.backend.send(Endpoint::Write, |c| {
+                c.post(&entry.url)
+                    .header("Idempotency-Key", entry.id.to_string())
+                    .json(&entry.body)
+            }).await;
+
+            match result {
+                Ok(response) if response.status().is_success() => {
+                    self.stats.delivered.fetch_add(1, Ordering::Relaxed);
+                    return Some(response.json().await.unwrap_or(serde_json::Value::Null));
+                }
+                // Retrying a request the backend refuses won't change its mind
+                Ok(response) if response.status().is_client_error()
+                    && !matches!(response.status().as_u16(), 408 | 429) =>
+                {
+                    println!("Outbox: dropping {} for {}: HTTP {}", entry.id, entry.key, response.status());
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                Ok(response) => println!("Outbox: {} failed with HTTP {}, retrying in {:?}", entry.key, response.status(), backoff),
+                Err(e) => println!("Outbox: {} failed ({}), retrying in {:?}", entry.key, e, backoff),
+            }
+
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path as UrlPath, State};
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+
+    use crate::backend::BackendConfig;
+
+    /// Paths the stub backend was sent, in arrival order, and how often each was hit
+    #[derive(Clone, Default)]
+    struct Received {
+        paths: Arc<Mutex<Vec<String>>>,
+        hits: Arc<Mutex<HashMap<String, u32>>>,
+    }
+
+    /// `/ok/*` answers 200, `/slow/*` 200 after a pause, `/reject/*` 400,
+    /// `/flaky/*` 503 the first time and 200 after that, `/down/*` always 503
+    async fn respond(
+        State(received): State<Received>,
+        UrlPath((kind, name)): UrlPath<(String, String)>,
+        Json(_): Json<serde_json::Value>,
+    ) -> StatusCode {
+        let path = format!("/{}/{}", kind, name);
+        received.paths.lock().unwrap().push(path.clone());
+        let hits = {
+            let mut hits = received.hits.lock().unwrap();
+            let count = hits.entry(path).or_default();
+            *count += 1;
+            *count
+        };
+        match kind.as_str() {
+            "slow" => {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                StatusCode::OK
+            }
+            "reject" => StatusCode::BAD_REQUEST,
+            "flaky" if hits == 1 => StatusCode::SERVICE_UNAVAILABLE,
+            "down" => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::OK,
+        }
+    }
+
+    async fn stub_backend() -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new().route("/:kind/:name", post(respond)).with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", addr), received)
+    }
+
+    fn backend() -> Arc<Backend> {
+        let config = BackendConfig {
+            timeouts: HashMap::new(),
+            retries: 0,
+            failure_threshold: 100,
+            cooldown: Duration::from_secs(1),
+        };
+        Arc::new(Backend::new(config, reqwest::Client::new()))
+    }
+
+    fn config(compact_after: usize) -> OutboxConfig {
+        OutboxConfig {
+            dir: std::env::temp_dir().join(format!("outbox-test-{}", Uuid::new_v4())),
+            alert_depth: 1000,
+            max_backoff: Duration::from_secs(1),
+            compact_after,
+        }
+    }
+
+    fn entry(base: &str, key: &str, path: &str) -> OutboxEntry {
+        OutboxEntry {
+            id: Uuid::new_v4(),
+            key: key.to_string(),
+            url: format!("{}{}", base, path),
+            body: serde_json::json!({}),
+            queued_at: 0,
+        }
+    }
+
+    fn record(record: &WalRecord) -> String {
+        serde_json::to_string(record).unwrap() + "\n"
+    }
+
+    async fn eventually(what: &str, check: impl Fn() -> bool) {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while !check() {
+            assert!(tokio::time::Instant::now() < deadline, "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_resends_unfinished_entries_and_skips_a_torn_line() {
+        let (base, received) = stub_backend().await;
+        let config = config(1000);
+        let path = config.wal_path();
+        let (done, pending) = (entry(&base, "a", "/ok/done"), entry(&base, "b", "/ok/pending"));
+
+        std::fs::create_dir_all(&config.dir).unwrap();
+        let log = record(&WalRecord::Append(done.clone()))
+            + &record(&WalRecord::Append(pending.clone()))
+            + &record(&WalRecord::Done { id: done.id })
+            + r#"{"op":"append","id":"#;
+        std::fs::write(&path, log).unwrap();
+
+        let outbox = Outbox::open(config, backend()).await.unwrap();
+        eventually("the pending entry", || outbox.snapshot().delivered == 1).await;
+        assert_eq!(*received.paths.lock().unwrap(), vec!["/ok/pending".to_string()]);
+
+        // Nothing is left, so the log starts over
+        eventually("the log to empty", || std::fs::metadata(&path).unwrap().len() == 0).await;
+    }
+
+    #[tokio::test]
+    async fn entries_with_the_same_key_are_sent_in_order() {
+        let (base, received) = stub_backend().await;
+        let outbox = Outbox::open(config(1000), backend()).await.unwrap();
+
+        let mut waiting = Vec::new();
+        for (key, path) in [("k", "/slow/first"), ("k", "/ok/second"), ("other", "/ok/unrelated")] {
+            waiting.push(outbox.submit(key.to_string(), format!("{}{}", base, path), serde_json::json!({})).await.unwrap());
+        }
+        for delivered in waiting {
+            delivered.await.unwrap();
+        }
+
+        let paths = received.paths.lock().unwrap();
+        let position = |p: &str| paths.iter().position(|x| x == p).unwrap();
+        assert!(position("/slow/first") < position("/ok/second"));
+        // Other keys don't wait behind a slow one
+        assert!(position("/ok/unrelated") < position("/ok/second"));
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_dropped_and_server_errors_retried() {
+        let (base, received) = stub_backend().await;
+        let outbox = Outbox::open(config(1000), backend()).await.unwrap();
+
+        let rejected = outbox.submit("a".to_string(), format!("{}/reject/x", base), serde_json::json!({})).await.unwrap();
+        assert!(rejected.await.is_err(), "a rejected entry never gets a response");
+
+        let flaky = outbox.submit("b".to_string(), format!("{}/flaky/y", base), serde_json::json!({})).await.unwrap();
+        flaky.await.unwrap();
+
+        let hits = received.hits.lock().unwrap();
+        assert_eq!(hits["/reject/x"], 1);
+        assert_eq!(hits["/flaky/y"], 2);
+        let snapshot = outbox.snapshot();
+        assert_eq!((snapshot.dropped, snapshot.delivered, snapshot.depth), (1, 1, 0));
+        assert!(snapshot.retries >= 1);
+    }
+
+    #[tokio::test]
+    async fn log_is_compacted_while_an_entry_stays_in_flight() {
+        let (base, _) = stub_backend().await;
+        let config = config(3);
+        let path = config.wal_path();
+        let outbox = Outbox::open(config, backend()).await.unwrap();
+
+        outbox.submit("stuck".to_string(), format!("{}/down/z", base), serde_json::json!({})).await.unwrap();
+        for i in 0..5 {
+            let delivered = outbox.submit(format!("k{}", i), format!("{}/ok/{}", base, i), serde_json::json!({})).await.unwrap();
+            delivered.await.unwrap();
+        }
+
+        // Rewritten after the third done to just the stuck entry, then two more appends and dones
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 5);
+        let live = replay(&log);
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].key, "stuck");
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use axum::extract::ws::Message;
+use serde::Deserialize;
+
+use crate::events::{ClientEvent, ErrorCode};
+
+/// `Sec-WebSocket-Protocol` names. The `deflate` variants add compression of
+/// large outgoing frames in this server's own framing, not RFC 7692 (see `compression`).
+pub const MSGPACK_DEFLATE_PROTOCOL: &str = "nexus.msgpack.deflate.v1";
+pub const MSGPACK_PROTOCOL: &str = "nexus.msgpack.v1";
+pub const JSON_DEFLATE_PROTOCOL: &str = "nexus.json.deflate.v1";
+pub const JSON_PROTOCOL: &str = "nexus.json.v1";
+
+/// Subprotocols to offer during the upgrade, in the order the server prefers them
+pub fn offered_protocols(compression: bool) -> Vec<&'static str> {
+    if compression {
+        vec![MSGPACK_DEFLATE_PROTOCOL, MSGPACK_PROTOCOL, JSON_DEFLATE_PROTOCOL, JSON_PROTOCOL]
+    } else {
+        vec![MSGPACK_PROTOCOL, JSON_PROTOCOL]
+    }
+}
+
+pub fn wants_deflate(protocol: Option<&str>) -> bool {
+    matches!(protocol, Some(MSGPACK_DEFLATE_PROTOCOL | JSON_DEFLATE_PROTOCOL))
+}
+
+/// Protocol version spoken by this server. Version 1 is the original
+/// protocol without a handshake and is still served to clients that never send `Hello`.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const SUPPORTED_VERSIONS: [u32; 2] = [1, PROTOCOL_VERSION];
+/// Optional features a client can opt into with `Hello { capabilities }`
+pub const SERVER_FEATURES: [&str; 6] = ["history_pagination", "memberships", "mentions", "pins", "search", "uploads"];
+
+/// Wire encoding of a session, chosen during the upgrade.
+/// Clients that don't ask for a subprotocol get JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    MsgPack,
+}
+
+impl Codec {
+    pub fn from_protocol(protocol: Option<&str>) -> Self {
+        match protocol {
+            Some(MSGPACK_PROTOCOL | MSGPACK_DEFLATE_PROTOCOL) => Codec::MsgPack,
+            _ => Codec::Json,
+        }
+    }
+
+    /// Events are queued as JSON text so one serialization can be fanned out to
+    /// every session; MessagePack sessions get it re-encoded as a binary frame here.
+    pub fn encode(self, msg: Message) -> Message {
+        match (self, msg) {
+            (Codec::MsgPack, Message::Text(text)) => {
+                match serde_json::from_str::<serde_json::Value>(&text)
+                    .ok()
+                    .and_then(|value| rmp_serde::to_vec_named(&value).ok())
+                {
+                    Some(bytes) => Message::Binary(bytes),
+                    None => Message::Text(text),
+                }
+            }
+            (_, msg) => msg,
+        }
+    }
+
+    /// Decodes a binary frame as a `ClientEvent`. JSON sessions use binary
+    /// frames for raw upload chunks, so they never reach this.
+    pub fn decode_binary(self, frame: &[u8]) -> Result<ClientEvent, String> {
+        match self {
+            Codec::MsgPack => {
+                // Human readable so ids arrive as strings, matching what we send
+                let mut de = rmp_serde::Deserializer::from_read_ref(frame).with_human_readable();
+                ClientEvent::deserialize(&mut de).map_err(|e| e.to_string())
+            }
+            Codec::Json => Err("binary events require the msgpack protocol".to_string()),
+        }
+    }
+}
+
+/// What a connection agreed on during the handshake
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub codec: Codec,
+    pub version: u32,
+    pub features: HashSet<String>,
+}
+
+impl Session {
+    /// A client that skipped the handshake gets version 1 and every event, as before
+    pub fn legacy(codec: Codec) -> Self {
+        Session {
+            codec,
+            version: 1,
+            features: SERVER_FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    /// Checks the requested version and keeps the capabilities this server also supports
+    pub fn negotiate(codec: Codec, version: u32, capabilities: &[String]) -> Result<Self, String> {
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(format!("Unsupported protocol version {}, supported: {:?}", version, SUPPORTED_VERSIONS));
+        }
+        if version == 1 {
+            return Ok(Session::legacy(codec));
+        }
+
+        Ok(Session {
+            codec,
+            version,
+            features: capabilities.iter()
+                .filter(|c| SERVER_FEATURES.contains(&c.as_str()))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    /// Sorted so the `Welcome` payload is stable
+    pub fn feature_list(&self) -> Vec<String> {
+        let mut features: Vec<String> = self.features.iter().cloned().collect();
+        features.sort();
+        features
+    }
+
+    /// Applies the negotiated features and codec to a queued event.
+    /// Events for features the client didn't ask for are dropped.
+    pub fn outgoing(&self, msg: Message) -> Option<Message> {
+        if let Message::Text(text) = &msg {
+            let kind = serde_json::from_str::<EventType>(text).ok().map(|e| e.kind);
+            if kind.and_then(event_feature).is_some_and(|f| !self.features.contains(f)) {
+                return None;
+            }
+            if self.is_legacy() && kind == Some("error") {
+                return Some(self.codec.encode(Message::Text(legacy_error(text))));
+            }
+        }
+        Some(self.codec.encode(msg))
+    }
+}
+
+#[derive(Deserialize)]
+struct EventType<'a> {
+    #[serde(rename = "type", borrow)]
+    kind: &'a str,
+}
+
+/// Version 1 errors carry HTTP-style codes and no details
+fn legacy_error(text: &str) -> String {
+    let Ok(mut value) = serde_json::from_str::<serde_json::Value>(text) else {
+        return text.to_string();
+    };
+    if let Ok(code) = serde_json::from_value::<ErrorCode>(value["code"].clone()) {
+        value["code"] = code.legacy_code().into();
+    }
+    if let Some(fields) = value.as_object_mut() {
+        fields.remove("details");
+    }
+    value.to_string()
+}
+
+/// The feature a `ServerEvent` belongs to, by its wire `type`
+fn event_feature(kind: &str) -> Option<&'static str> {
+    match kind {
+        "memberships" => Some("memberships"),
+        "mentioned" => Some("mentions"),
+        "pins_updated" => Some("pins"),
+        "search_results" => Some("search"),
+        "upload_ready" | "upload_progress" | "upload_complete" => Some("uploads"),
+        _ => None,
+    }
+}
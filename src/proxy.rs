@@ -0,0 +1,489 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts, HeaderMap},
+    Extension,
+};
+use axum_server::accept::Accept;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+use tower_layer::Layer;
+
+use crate::state::AppState;
+
+/// PROXY protocol v2 signature
+const V2_SIGNATURE: [u8; 12] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a];
+/// Longest possible v1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+/// Largest v2 address block accepted, TLVs included. The format allows 64 KiB,
+/// far more than any proxy sends.
+const V2_MAX_LEN: usize = 4096;
+/// How long a new connection has to send its PROXY header
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which peers may tell us the real client address
+pub struct ProxyConfig {
+    trusted: Vec<(IpAddr, u8)>,
+    /// Every connection starts with a PROXY protocol v1 or v2 header
+    pub proxy_protocol: bool,
+}
+
+impl ProxyConfig {
+    /// Reads `TRUSTED_PROXIES` (comma separated addresses or CIDR ranges) and `PROXY_PROTOCOL`
+    pub fn from_env() -> Self {
+        let trusted = std::env::var("TRUSTED_PROXIES")
+            .map(|v| v.split(',').filter(|s| !s.trim().is_empty()).map(|s| {
+                parse_cidr(s.trim()).unwrap_or_else(|| panic!("Invalid TRUSTED_PROXIES entry {}", s))
+            }).collect())
+            .unwrap_or_default();
+
+        ProxyConfig {
+            trusted,
+            proxy_protocol: std::env::var("PROXY_PROTOCOL").is_ok_and(|v| matches!(v.as_str(), "1" | "true" | "on")),
+        }
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|(net, prefix)| in_cidr(ip, *net, *prefix))
+    }
+
+    /// Headers from untrusted peers are ignored
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+        self.forwarded_ip(headers).unwrap_or(peer)
+    }
+
+    /// Walks `Forwarded` (or `X-Forwarded-For`) from the nearest hop back, skipping trusted proxies
+    pub fn forwarded_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        let forwarded: Vec<IpAddr> = if headers.contains_key(header::FORWARDED) {
+            header_values(headers, header::FORWARDED.as_str())
+                .flat_map(|v| v.split(','))
+                .filter_map(|hop| {
+                    hop.split(';')
+                        .find_map(|pair| {
+                            let (key, value) = pair.trim().split_once('=')?;
+                            key.eq_ignore_ascii_case("for").then(|| parse_forwarded_node(value))
+                        })
+                        .flatten()
+                })
+                .collect()
+        } else {
+            header_values(headers, "x-forwarded-for")
+                .flat_map(|v| v.split(','))
+                .filter_map(|ip| ip.trim().parse().ok())
+                .collect()
+        };
+
+        forwarded.iter().rev()
+            .find(|ip| !self.is_trusted(**ip))
+            .or(forwarded.first())
+            .copied()
+    }
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers.get_all(name).into_iter().filter_map(|v| v.to_str().ok())
+}
+
+/// `for=` values look like `192.0.2.1`, `"192.0.2.1:4711"` or `"[2001:db8::1]:4711"`
+fn parse_forwarded_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    value.parse().ok().or_else(|| value.rsplit_once(':')?.0.parse().ok())
+}
+
+fn parse_cidr(value: &str) -> Option<(IpAddr, u8)> {
+    match value.split_once('/') {
+        Some((ip, prefix)) => {
+            let ip: IpAddr = ip.parse().ok()?;
+            let prefix: u8 = prefix.parse().ok()?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            (prefix <= max).then_some((ip, prefix))
+        }
+        None => {
+            let ip: IpAddr = value.parse().ok()?;
+            Some((ip, if ip.is_ipv4() { 32 } else { 128 }))
+        }
+    }
+}
+
+fn in_cidr(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    };
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Source address from a PROXY protocol header, attached to every request on the connection
+#[derive(Debug, Clone, Copy)]
+pub struct ProxiedAddr(pub Option<SocketAddr>);
+
+/// Reads the PROXY protocol header off new connections before TLS or HTTP see them
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyProtocolAcceptor {
+    pub enabled: bool,
+}
+
+impl<S> Accept<TcpStream, S> for ProxyProtocolAcceptor
+where
+    S: Send + 'static,
+{
+    type Stream = TcpStream;
+    type Service = <Extension<ProxiedAddr> as Layer<S>>::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        let enabled = self.enabled;
+        Box::pin(async move {
+            let source = if enabled {
+                tokio::time::timeout(HEADER_TIMEOUT, read_proxy_header(&mut stream))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY protocol: no header"))??
+            } else {
+                None
+            };
+            Ok((stream, Extension(ProxiedAddr(source)).layer(service)))
+        })
+    }
+}
+
+/// Consumes exactly the PROXY header, leaving the stream at the first byte of the real protocol.
+/// `None` for `UNKNOWN`/`LOCAL` headers, which carry no address.
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("PROXY protocol: {}", msg));
+
+    let mut start = [0u8; 5];
+    stream.read_exact(&mut start).await?;
+
+    if &start == b"PROXY" {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid("v1 header is not text"))?;
+        let fields: Vec<&str> = line.trim_end().split(' ').collect();
+        return match fields.as_slice() {
+            ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, sport, _dport] => {
+                let ip: IpAddr = src.parse().map_err(|_| invalid("bad source address"))?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(invalid("source address doesn't match the family"));
+                }
+                let port: u16 = sport.parse().map_err(|_| invalid("bad source port"))?;
+                Ok(Some(SocketAddr::new(ip, port)))
+            }
+            ["PROXY", "UNKNOWN", ..] => Ok(None),
+            _ => Err(invalid("malformed v1 header")),
+        };
+    }
+
+    let mut header = [0u8; 16];
+    header[..5].copy_from_slice(&start);
+    stream.read_exact(&mut header[5..]).await?;
+    if header[..12] != V2_SIGNATURE || header[12] >> 4 != 2 {
+        return Err(invalid("missing header"));
+    }
+
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    if len > V2_MAX_LEN {
+        return Err(invalid("v2 header too long"));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    match header[12] & 0x0f {
+        // LOCAL connections are health checks from the proxy itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown v2 command")),
+    }
+    match header[13] >> 4 {
+        1 if len >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))))
+        }
+        2 if len >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([body[32], body[33]]))))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The address of the client behind any proxies, for logging and per-client limits
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let proxied = parts.extensions.get::<ProxiedAddr>().and_then(|p| p.0);
+        let connected = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|c| c.0);
+        match proxied.or(connected) {
+            Some(peer) => Ok(ClientIp(state.proxy_config.client_ip(peer.ip(), &parts.headers))),
+            // Unix socket: only processes the socket's permissions let in can connect, so trust their headers
+            None => Ok(ClientIp(state.proxy_config.forwarded_ip(&parts.headers).unwrap_or(Ipv4Addr::LOCALHOST.into()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn config(trusted: &[&str]) -> ProxyConfig {
+        ProxyConfig {
+            trusted: trusted.iter().map(|t| parse_cidr(t).unwrap()).collect(),
+            proxy_protocol: true,
+        }
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    /// Parses a header off `bytes`, returning the address and what is left to read
+    async fn read(bytes: &[u8]) -> io::Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = bytes;
+        let addr = read_proxy_header(&mut stream).await?;
+        Ok((addr, stream.to_vec()))
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 1);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[test]
+    fn cidr_parsing() {
+        assert_eq!(parse_cidr("10.0.0.1"), Some((ip("10.0.0.1"), 32)));
+        assert_eq!(parse_cidr("::1"), Some((ip("::1"), 128)));
+        assert_eq!(parse_cidr("10.0.0.0/0"), Some((ip("10.0.0.0"), 0)));
+        assert_eq!(parse_cidr("2001:db8::/128"), Some((ip("2001:db8::"), 128)));
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("2001:db8::/129"), None);
+        assert_eq!(parse_cidr("10.0.0.0/"), None);
+        assert_eq!(parse_cidr("10.0.0.0/-1"), None);
+        assert_eq!(parse_cidr("example.com"), None);
+        assert_eq!(parse_cidr(""), None);
+    }
+
+    #[test]
+    fn cidr_matching() {
+        // /0 matches everything of the same family
+        assert!(in_cidr(ip("203.0.113.9"), ip("0.0.0.0"), 0));
+        assert!(in_cidr(ip("2001:db8::1"), ip("::"), 0));
+        assert!(!in_cidr(ip("2001:db8::1"), ip("0.0.0.0"), 0));
+
+        // Full-length prefixes match one address
+        assert!(in_cidr(ip("10.0.0.1"), ip("10.0.0.1"), 32));
+        assert!(!in_cidr(ip("10.0.0.2"), ip("10.0.0.1"), 32));
+        assert!(in_cidr(ip("2001:db8::1"), ip("2001:db8::1"), 128));
+        assert!(!in_cidr(ip("2001:db8::2"), ip("2001:db8::1"), 128));
+
+        assert!(in_cidr(ip("10.1.255.255"), ip("10.1.0.0"), 16));
+        assert!(!in_cidr(ip("10.2.0.0"), ip("10.1.0.0"), 16));
+        assert!(in_cidr(ip("2001:db8:ffff::1"), ip("2001:db8::"), 32));
+
+        // IPv4-mapped IPv6 peers match IPv4 ranges
+        assert!(in_cidr(ip("::ffff:10.0.0.1"), ip("10.0.0.0"), 8));
+    }
+
+    #[test]
+    fn headers_from_untrusted_peers_are_ignored() {
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4"), ("forwarded", "for=5.6.7.8")]);
+        assert_eq!(config(&["10.0.0.0/8"]).client_ip(ip("203.0.113.9"), &spoofed), ip("203.0.113.9"));
+
+        // With no trusted proxies configured nobody is believed
+        assert_eq!(config(&[]).client_ip(ip("10.0.0.1"), &spoofed), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_chain_is_walked_from_the_nearest_hop() {
+        let config = config(&["10.0.0.0/8", "2001:db8::/32"]);
+
+        // The client can prepend anything; only the hop our proxy appended counts
+        let xff = headers(&[("x-forwarded-for", "6.6.6.6, 198.51.100.7, 10.0.0.2")]);
+        assert_eq!(config.client_ip(ip("10.0.0.1"), &xff), ip("198.51.100.7"));
+
+        let split = headers(&[("x-forwarded-for", "6.6.6.6"), ("x-forwarded-for", "198.51.100.7")]);
+        assert_eq!(config.client_ip(ip("10.0.0.1"), &split), ip("198.51.100.7"));
+
+        // Only proxies in the chain: the furthest one is as good as it gets
+        let all_trusted = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(config.client_ip(ip("10.0.0.1"), &all_trusted), ip("10.0.0.3"));
+
+        // Garbage entries are skipped, and with nothing usable the peer is used
+        let garbage = headers(&[("x-forwarded-for", "not-an-ip, 198.51.100.7:80")]);
+        assert_eq!(config.client_ip(ip("10.0.0.1"), &garbage), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_header_takes_precedence() {
+        let config = config(&["10.0.0.0/8"]);
+        let both = headers(&[
+            ("forwarded", "for=198.51.100.7;proto=https, for=10.0.0.2"),
+            ("x-forwarded-for", "6.6.6.6"),
+        ]);
+        assert_eq!(config.client_ip(ip("10.0.0.1"), &both), ip("198.51.100.7"));
+
+        let ports_and_v6 = headers(&[("forwarded", "for=\"[2001:db8:cafe::17]:4711\", For=\"198.51.100.7:80\"")]);
+        assert_eq!(config.forwarded_ip(&ports_and_v6), Some(ip("198.51.100.7")));
+
+        let obfuscated = headers(&[("forwarded", "for=unknown, for=_hidden;by=10.0.0.2")]);
+        assert_eq!(config.forwarded_ip(&obfuscated), None);
+    }
+
+    #[test]
+    fn forwarded_nodes() {
+        assert_eq!(parse_forwarded_node("192.0.2.1"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_forwarded_node("\"192.0.2.1:4711\""), Some(ip("192.0.2.1")));
+        assert_eq!(parse_forwarded_node("\"[2001:db8::1]:4711\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_forwarded_node("\"[2001:db8::1]\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_forwarded_node("[garbage]"), None);
+        assert_eq!(parse_forwarded_node("unknown"), None);
+        assert_eq!(parse_forwarded_node(""), None);
+    }
+
+    #[tokio::test]
+    async fn v1_headers() {
+        let (addr, rest) = read(b"PROXY TCP4 198.51.100.7 10.0.0.1 4711 443\r\nGET /").await.unwrap();
+        assert_eq!(addr, Some("198.51.100.7:4711".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n").await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4711".parse().unwrap()));
+
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\nrest").await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn malformed_v1_headers_are_rejected() {
+        let malformed: [&[u8]; 9] = [
+            b"PROXY TCP4 198.51.100.7 10.0.0.1 4711\r\n",
+            b"PROXY TCP4 198.51.100.7 10.0.0.1 4711 443 extra\r\n",
+            b"PROXY UDP4 198.51.100.7 10.0.0.1 4711 443\r\n",
+            b"PROXY TCP4 not-an-ip 10.0.0.1 4711 443\r\n",
+            b"PROXY TCP4 198.51.100.7 10.0.0.1 70000 443\r\n",
+            b"PROXY TCP4 2001:db8::1 10.0.0.1 4711 443\r\n",
+            b"PROXY TCP6 198.51.100.7 10.0.0.1 4711 443\r\n",
+            b"PROXY  TCP4 198.51.100.7 10.0.0.1 4711 443\r\n",
+            b"PROXY TCP4 \xff\xfe 10.0.0.1 4711 443\r\n",
+        ];
+        for header in malformed {
+            let error = read(header).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(header));
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_and_oversized_v1_headers_are_rejected() {
+        assert_eq!(read(b"PROX").await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read(b"PROXY TCP4 198.51.100.7").await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.extend(std::iter::repeat_n(b'a', 200));
+        long.extend_from_slice(b"\r\n");
+        assert_eq!(read(&long).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Exactly the longest legal header still fits
+        let mut longest = b"PROXY UNKNOWN ".to_vec();
+        longest.extend(std::iter::repeat_n(b'a', V1_MAX_LEN - longest.len() - 2));
+        longest.extend_from_slice(b"\r\n");
+        assert_eq!(longest.len(), V1_MAX_LEN);
+        assert_eq!(read(&longest).await.unwrap().0, None);
+    }
+
+    #[tokio::test]
+    async fn v2_headers() {
+        let mut ipv4 = vec![198, 51, 100, 7, 10, 0, 0, 1];
+        ipv4.extend_from_slice(&4711u16.to_be_bytes());
+        ipv4.extend_from_slice(&443u16.to_be_bytes());
+        let mut with_tlv = ipv4.clone();
+        with_tlv.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let mut stream = v2(1, 1, &with_tlv);
+        stream.extend_from_slice(b"GET /");
+        let (addr, rest) = read(&stream).await.unwrap();
+        assert_eq!(addr, Some("198.51.100.7:4711".parse().unwrap()));
+        assert_eq!(rest, b"GET /", "TLVs are consumed with the header");
+
+        let mut ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend_from_slice(&[0; 16]);
+        ipv6.extend_from_slice(&4711u16.to_be_bytes());
+        ipv6.extend_from_slice(&443u16.to_be_bytes());
+        let (addr, _) = read(&v2(1, 2, &ipv6)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4711".parse().unwrap()));
+
+        // LOCAL, unspecified and unix families carry no usable address
+        assert_eq!(read(&v2(0, 1, &ipv4)).await.unwrap().0, None);
+        assert_eq!(read(&v2(1, 0, &[])).await.unwrap().0, None);
+        assert_eq!(read(&v2(1, 3, &[0; 216])).await.unwrap().0, None);
+    }
+
+    #[tokio::test]
+    async fn bad_v2_headers_are_rejected() {
+        let invalid = |result: io::Result<(Option<SocketAddr>, Vec<u8>)>| result.unwrap_err().kind();
+
+        // Not a PROXY header at all
+        assert_eq!(invalid(read(b"GET / HTTP/1.1\r\nHost: x\r\n").await), io::ErrorKind::InvalidData);
+
+        // Wrong version, unknown command
+        let mut wrong_version = v2(1, 1, &[0; 12]);
+        wrong_version[12] = 0x11;
+        assert_eq!(invalid(read(&wrong_version).await), io::ErrorKind::InvalidData);
+        assert_eq!(invalid(read(&v2(2, 1, &[0; 12])).await), io::ErrorKind::InvalidData);
+
+        // Too short for its family: no address rather than reading past the block
+        assert_eq!(read(&v2(1, 1, &[0; 11])).await.unwrap().0, None);
+        assert_eq!(read(&v2(1, 2, &[0; 35])).await.unwrap().0, None);
+
+        // Truncated signature, header or body
+        assert_eq!(invalid(read(&V2_SIGNATURE[..10]).await), io::ErrorKind::UnexpectedEof);
+        let full = v2(1, 1, &[0; 12]);
+        assert_eq!(invalid(read(&full[..20]).await), io::ErrorKind::UnexpectedEof);
+
+        // A length past the cap is refused before anything is allocated for it
+        let mut oversized = v2(1, 1, &[]);
+        oversized[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(invalid(read(&oversized).await), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,62 @@
+use schemars::generate::SchemaSettings;
+use ts_rs::TS;
+
+use crate::events::{ClientEvent, ErrorCode, RoomListEntry, ServerEvent};
+use crate::search::SearchHit;
+use crate::state::{MessageAuthor, RoomMessage, RoomUser};
+use crate::uploads::FileAttachment;
+use crate::catalog::RoomDetails;
+
+/// Where `export_schema` writes its output and where the staleness test reads it from
+pub const JSON_SCHEMA_PATH: &str = "schema/protocol.schema.json";
+pub const TYPESCRIPT_PATH: &str = "schema/protocol.d.ts";
+
+const GENERATED_HEADER: &str = "// Generated by `cargo run --bin export_schema`. Do not edit by hand.";
+
+/// JSON Schema document with every wire type under `$defs`
+pub fn json_schema() -> String {
+    let mut generator = SchemaSettings::draft2020_12().into_generator();
+    generator.subschema_for::<ClientEvent>();
+    generator.subschema_for::<ServerEvent>();
+    generator.subschema_for::<ErrorCode>();
+    generator.subschema_for::<RoomListEntry>();
+    generator.subschema_for::<RoomUser>();
+    generator.subschema_for::<RoomMessage>();
+    generator.subschema_for::<MessageAuthor>();
+    generator.subschema_for::<SearchHit>();
+    generator.subschema_for::<FileAttachment>();
+    generator.subschema_for::<RoomDetails>();
+
+    let defs: serde_json::Map<String, serde_json::Value> = generator.take_definitions(true)
+        .into_iter()
+        .collect();
+
+    let document = serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Nexus WebSocket protocol",
+        "$defs": defs,
+    });
+    format!("{}\n", serde_json::to_string_pretty(&document).unwrap())
+}
+
+/// TypeScript declarations for the same types, for the frontend to import
+pub fn typescript() -> String {
+    let decls = [
+        ClientEvent::decl(),
+        ServerEvent::decl(),
+        ErrorCode::decl(),
+        RoomListEntry::decl(),
+        RoomUser::decl(),
+        RoomMessage::decl(),
+        MessageAuthor::decl(),
+        SearchHit::decl(),
+        FileAttachment::decl(),
+        RoomDetails::decl(),
+    ];
+
+    let mut out = format!("{}\n", GENERATED_HEADER);
+    for decl in decls {
+        out.push_str(&format!("\nexport {}\n", decl));
+    }
+    out
+}
@@ -0,0 +1,444 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, FixedOffset};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::state::{MessageAuthor, RoomMessage};
+use crate::backend::{Backend, Endpoint};
+
+/// Markers wrapped around matched terms in snippets (same as the API's `ts_headline` options).
+/// Snippets are HTML: the message text in them is escaped, so only these tags are markup.
+pub const HIGHLIGHT_START: &str = "<mark>";
+pub const HIGHLIGHT_END: &str = "</mark>";
+/// Characters of context kept on each side of the first match in a snippet
+const SNIPPET_CONTEXT: usize = 40;
+/// Messages the in-memory index holds when `SEARCH_MEMORY_LIMIT` is unset
+const DEFAULT_MEMORY_LIMIT: usize = 100_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchQuery {
+    pub query: String,
+    pub room_name: Option<String>,
+    pub from_user: Option<String>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub limit: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
+pub struct SearchHit {
+    pub room_name: String,
+    pub score: f32,
+    /// HTML: escaped message text with matches wrapped in `<mark>`
+    pub snippet: String,
+    pub message: RoomMessage,
+}
+
+/// Where `SearchMessages` is served from. `Api` lets PostgreSQL do the work
+/// through the Node server, `Memory` keeps an index of messages this process has seen.
+pub enum SearchBackend {
+    Api { backend: Arc<Backend>, url: String },
+    Memory(MemoryIndex),
+}
+
+impl SearchBackend {
+    /// Picks the backend from `SEARCH_BACKEND` (`api` by default, or `memory`).
+    /// `SEARCH_MEMORY_LIMIT` caps how many messages the memory index keeps.
+    pub fn from_env(backend: Arc<Backend>) -> Self {
+        match std::env::var("SEARCH_BACKEND").as_deref() {
+            Ok("memory") => {
+                let limit = std::env::var("SEARCH_MEMORY_LIMIT").ok()
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .unwrap_or(DEFAULT_MEMORY_LIMIT);
+                SearchBackend::Memory(MemoryIndex::new(limit))
+            }
+            _ => SearchBackend::Api {
+                backend,
+                url: "https://localhost:443/internal/messages/search".to_string(),
+            },
+        }
+    }
+
+    /// Runs a query restricted to `readable_rooms`, best hits first
+    pub async fn search(&self, query: &SearchQuery, readable_rooms: &[String]) -> Result<Vec<SearchHit>, String> {
+        match self {
+            SearchBackend::Api { backend, url } => {
+                let body = serde_json::json!({
+                    "query": query.query,
+                    "rooms": readable_rooms,
+                    "roomName": query.room_name,
+                    "fromUser": query.from_user,
+                    "before": query.before,
+                    "after": query.after,
+                    "limit": query.limit,
+                });
+                let response = backend.send(Endpoint::Search, |c| c.post(url).json(&body))
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                if !response.status().is_success() {
+                    return Err(format!("HTTP {}", response.status()));
+                }
+
+                let data = response.json::<serde_json::Value>().await.map_err(|e| e.to_string())?;
+                Ok(data["hits"].as_array()
+                    .map(|arr| arr.iter().filter_map(parse_api_hit).collect())
+                    .unwrap_or_default())
+            }
+            SearchBackend::Memory(index) => Ok(index.search(query, readable_rooms)),
+        }
+    }
+
+    /// Adds a message to the local index. The API backend reads the database directly, so this is a no-op there.
+    pub fn index(&self, room_name: &str, message: &RoomMessage) {
+        if let SearchBackend::Memory(index) = self {
+            index.insert(room_name, message);
+        }
+    }
+}
+
+fn parse_api_hit(h: &serde_json::Value) -> Option<SearchHit> {
+    Some(SearchHit {
+        room_name: h["roomName"].as_str()?.to_string(),
+        score: h["rank"].as_f64().unwrap_or(0.0) as f32,
+        snippet: h["snippet"].as_str().unwrap_or_default().to_string(),
+        message: RoomMessage {
+            id: h["id"].as_i64()? as i32,
+            content: h["content"].as_str()?.to_string(),
+            created_at: h["createdAt"].as_str()?.to_string(),
+            edited_at: h["editedAt"].as_str().map(String::from),
+            message_type: h["messageType"].as_str().unwrap_or("text").to_string(),
+            user: MessageAuthor {
+                id: h["user"]["id"].as_i64()? as i32,
+                username: h["user"]["username"].as_str()?.to_string(),
+                display_name: h["user"]["displayName"].as_str()
+                    .or(h["user"]["username"].as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                avatar_url: h["user"]["avatarUrl"].as_str().map(String::from),
+            },
+        },
+    })
+}
+
+struct IndexedMessage {
+    room_name: String,
+    message: RoomMessage,
+    terms: HashMap<String, u32>,
+}
+
+/// Inverted index over the most recent messages the server has broadcast or
+/// loaded. Past `limit` messages the oldest indexed ones are dropped.
+pub struct MemoryIndex {
+    limit: usize,
+    inner: Mutex<MemoryIndexInner>,
+}
+
+#[derive(Default)]
+struct MemoryIndexInner {
+    messages: HashMap<i32, IndexedMessage>,
+    postings: HashMap<String, HashSet<i32>>,
+    /// Message ids in the order they were indexed, oldest first
+    order: VecDeque<i32>,
+}
+
+impl MemoryIndexInner {
+    fn remove(&mut self, id: i32) {
+        let Some(old) = self.messages.remove(&id) else { return };
+        for term in old.terms.keys() {
+            if let Some(ids) = self.postings.get_mut(term) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(term);
+                }
+            }
+        }
+    }
+}
+
+impl MemoryIndex {
+    pub fn new(limit: usize) -> Self {
+        MemoryIndex { limit: limit.max(1), inner: Mutex::new(MemoryIndexInner::default()) }
+    }
+
+    /// Indexes a message, replacing it if it was indexed before
+    pub fn insert(&self, room_name: &str, message: &RoomMessage) {
+        let mut terms: HashMap<String, u32> = HashMap::new();
+        for term in tokenize(&message.content) {
+            *terms.entry(term).or_default() += 1;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.messages.contains_key(&message.id) {
+            inner.remove(message.id);
+            inner.order.retain(|id| *id != message.id);
+        }
+        while inner.messages.len() >= self.limit {
+            let Some(oldest) = inner.order.pop_front() else { break };
+            inner.remove(oldest);
+        }
+
+        for term in terms.keys() {
+            inner.postings.entry(term.clone()).or_default().insert(message.id);
+        }
+        inner.order.push_back(message.id);
+        inner.messages.insert(message.id, IndexedMessage {
+            room_name: room_name.to_string(),
+            message: message.clone(),
+            terms,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn search(&self, query: &SearchQuery, readable_rooms: &[String]) -> Vec<SearchHit> {
+        let query_terms: Vec<String> = tokenize(&query.query).collect();
+        if query_terms.is_empty() {
+            return Vec::new();
+        }
+
+        let before = query.before.as_deref().and_then(parse_time);
+        let after = query.after.as_deref().and_then(parse_time);
+
+        let inner = self.inner.lock().unwrap();
+        let total = inner.messages.len().max(1) as f32;
+
+        // Every query term has to appear, like `plainto_tsquery`
+        let mut candidates: Option<HashSet<i32>> = None;
+        for term in &query_terms {
+            let ids = inner.postings.get(term).cloned().unwrap_or_default();
+            candidates = Some(match candidates {
+                Some(acc) => acc.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+
+        let mut hits: Vec<SearchHit> = candidates.unwrap_or_default().iter()
+            .filter_map(|id| inner.messages.get(id))
+            .filter(|m| readable_rooms.contains(&m.room_name))
+            .filter(|m| query.room_name.as_ref().is_none_or(|r| &m.room_name == r))
+            .filter(|m| query.from_user.as_ref().is_none_or(|u| &m.message.user.username == u))
+            .filter(|m| {
+                let created = parse_time(&m.message.created_at);
+                before.is_none_or(|b| created.is_some_and(|c| c < b))
+                    && after.is_none_or(|a| created.is_some_and(|c| c > a))
+            })
+            .map(|m| {
+                let score = query_terms.iter()
+                    .map(|t| {
+                        let tf = *m.terms.get(t).unwrap_or(&0) as f32;
+                        let df = inner.postings.get(t).map(|p| p.len()).unwrap_or(1) as f32;
+                        tf * (1.0 + (total / df).ln())
+                    })
+                    .sum();
+                SearchHit {
+                    room_name: m.room_name.clone(),
+                    score,
+                    snippet: highlight(&m.message.content, &query_terms),
+                    message: m.message.clone(),
+                }
+            })
+            .collect();
+
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(b.message.id.cmp(&a.message.id)));
+        hits.truncate(query.limit as usize);
+        hits
+    }
+}
+
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+}
+
+fn parse_time(value: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(value).ok()
+}
+
+/// Cuts a window around the first matching word and wraps every match in highlight markers.
+/// The text around the markers is HTML-escaped.
+fn highlight(content: &str, terms: &[String]) -> String {
+    let words: Vec<(usize, &str)> = content
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| (w.as_ptr() as usize - content.as_ptr() as usize, w))
+        .collect();
+
+    let matches: Vec<(usize, usize)> = words.iter()
+        .filter(|(_, w)| terms.contains(&w.to_lowercase()))
+        .map(|(start, w)| (*start, start + w.len()))
+        .collect();
+
+    let Some(&(first, _)) = matches.first() else {
+        return escape_html(&content.chars().take(SNIPPET_CONTEXT * 2).collect::<String>());
+    };
+
+    let start = floor_char_boundary(content, first.saturating_sub(SNIPPET_CONTEXT));
+    let end = floor_char_boundary(content, (first + SNIPPET_CONTEXT * 2).min(content.len()));
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let mut cursor = start;
+    for &(m_start, m_end) in matches.iter().filter(|(s, e)| *s >= start && *e <= end) {
+        snippet.push_str(&escape_html(&content[cursor..m_start]));
+        snippet.push_str(HIGHLIGHT_START);
+        snippet.push_str(&escape_html(&content[m_start..m_end]));
+        snippet.push_str(HIGHLIGHT_END);
+        cursor = m_end;
+    }
+    snippet.push_str(&escape_html(&content[cursor..end]));
+    if end < content.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn floor_char_boundary(s: &str, mut index: usize) -> usize {
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i32, content: &str) -> RoomMessage {
+        RoomMessage {
+            id,
+            content: content.to_string(),
+            created_at: format!("2024-01-01T00:00:{:02}Z", id % 60),
+            edited_at: None,
+            message_type: "text".to_string(),
+            user: MessageAuthor {
+                id: 1,
+                username: "alice".to_string(),
+                display_name: "Alice".to_string(),
+                avatar_url: None,
+            },
+        }
+    }
+
+    fn query(text: &str) -> SearchQuery {
+        SearchQuery {
+            query: text.to_string(),
+            room_name: None,
+            from_user: None,
+            before: None,
+            after: None,
+            limit: 20,
+        }
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<i32> {
+        let mut ids: Vec<i32> = hits.iter().map(|h| h.message.id).collect();
+        ids.sort();
+        ids
+    }
+
+    fn rooms(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn tokenize_lowercases_and_splits_on_punctuation() {
+        let terms: Vec<String> = tokenize("Hello, WORLD! it's  Ünïcode-ok").collect();
+        assert_eq!(terms, ["hello", "world", "it", "s", "ünïcode", "ok"]);
+        assert_eq!(tokenize(" ,.!").count(), 0);
+    }
+
+    #[test]
+    fn every_query_term_has_to_match() {
+        let index = MemoryIndex::new(10);
+        index.insert("lobby", &message(1, "deploy the server"));
+        index.insert("lobby", &message(2, "restart the server"));
+        index.insert("lobby", &message(3, "deploy tonight"));
+
+        let readable = rooms(&["lobby"]);
+        assert_eq!(ids(&index.search(&query("server"), &readable)), [1, 2]);
+        assert_eq!(ids(&index.search(&query("deploy server"), &readable)), [1]);
+        assert!(index.search(&query("deploy missing"), &readable).is_empty());
+        assert!(index.search(&query("   "), &readable).is_empty());
+    }
+
+    #[test]
+    fn only_readable_rooms_are_searched() {
+        let index = MemoryIndex::new(10);
+        index.insert("lobby", &message(1, "secret plans"));
+        index.insert("private", &message(2, "secret plans"));
+
+        assert_eq!(ids(&index.search(&query("secret"), &rooms(&["lobby"]))), [1]);
+        assert!(index.search(&query("secret"), &[]).is_empty());
+
+        // Asking for a room that isn't readable doesn't open it up
+        let mut in_private = query("secret");
+        in_private.room_name = Some("private".to_string());
+        assert!(index.search(&in_private, &rooms(&["lobby"])).is_empty());
+        assert_eq!(ids(&index.search(&in_private, &rooms(&["lobby", "private"]))), [2]);
+    }
+
+    #[test]
+    fn oldest_messages_are_dropped_past_the_limit() {
+        let index = MemoryIndex::new(2);
+        index.insert("lobby", &message(1, "alpha"));
+        index.insert("lobby", &message(2, "alpha"));
+        index.insert("lobby", &message(3, "alpha"));
+        assert_eq!(index.len(), 2);
+        assert_eq!(ids(&index.search(&query("alpha"), &rooms(&["lobby"]))), [2, 3]);
+
+        // Indexing a message again replaces it instead of taking another slot
+        index.insert("lobby", &message(3, "beta"));
+        assert_eq!(index.len(), 2);
+        assert_eq!(ids(&index.search(&query("alpha"), &rooms(&["lobby"]))), [2]);
+        assert_eq!(ids(&index.search(&query("beta"), &rooms(&["lobby"]))), [3]);
+    }
+
+    #[test]
+    fn snippets_escape_message_html() {
+        let snippet = highlight("<img src=x onerror=alert(1)> hello & \"bye\"", &["hello".to_string()]);
+        assert_eq!(snippet, "&lt;img src=x onerror=alert(1)&gt; <mark>hello</mark> &amp; &quot;bye&quot;");
+
+        let unmatched = highlight("<script>", &["other".to_string()]);
+        assert_eq!(unmatched, "&lt;script&gt;");
+    }
+
+    #[test]
+    fn snippets_cut_on_char_boundaries() {
+        let content = format!("{} needle {}", "é".repeat(50), "ü".repeat(50));
+        let snippet = highlight(&content, &["needle".to_string()]);
+        assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+        assert!(snippet.contains("<mark>needle</mark>"));
+
+        assert_eq!(floor_char_boundary("aé", 2), 1);
+        assert_eq!(floor_char_boundary("aé", 3), 3);
+        assert_eq!(floor_char_boundary("日本", 4), 3);
+        assert_eq!(floor_char_boundary("", 0), 0);
+    }
+}
@@ -1,14 +1,32 @@
 use std::collections::{HashMap, HashSet};
-use std::time::Instant;
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime};
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tokio::sync::{mpsc, broadcast};
+use ts_rs::TS;
+use tokio::sync::{mpsc, oneshot};
+use crate::search::SearchBackend;
+use crate::uploads::{PendingUpload, UploadConfig};
+use crate::compression::{CompressionConfig, CompressionStats};
+use crate::protocol::Session;
+use crate::auth::{Authenticator, TokenSources};
+use crate::origin::OriginPolicy;
+use crate::proxy::ProxyConfig;
+use crate::bus::Bus;
+use crate::directory::Directory;
+use crate::outbox::Outbox;
+use crate::backend::Backend;
+use crate::membership::Memberships;
+use crate::catalog::RoomCatalog;
 
 #[derive(Clone)]
 pub struct UserInfo {
     pub session_id: Uuid,
     pub db_user_id: i32,
+    /// Client address, after resolving trusted proxies
+    pub remote_ip: IpAddr,
     pub username: String,
     pub display_name: String,
     pub avatar_url: Option<String>,
@@ -16,10 +34,12 @@ pub struct UserInfo {
     pub rooms: HashSet<String>,
     pub _joined_at: Instant,
     pub last_heartbeat: Instant,
+    /// When the token the session was opened or last refreshed with runs out, leeway included
+    pub expires_at: Option<SystemTime>,
     pub tx: mpsc::Sender<axum::extract::ws::Message>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
 pub struct RoomUser {
     pub username: String,
     pub display_name: String,
@@ -30,14 +50,53 @@ pub struct RoomUser {
 #[derive(Clone)]
 pub struct AppState {
     pub users: Arc<Mutex<HashMap<Uuid, UserInfo>>>,
+    /// Sessions currently viewing each room
     pub rooms: Arc<Mutex<HashMap<String, HashSet<Uuid>>>>,
+    /// Who belongs to each room, whether online or not
+    pub memberships: Arc<Mutex<Memberships>>,
+    /// Every room that exists, including empty ones
+    pub catalog: Arc<Mutex<RoomCatalog>>,
     pub profile_subscribers: Arc<Mutex<HashMap<i32, HashSet<Uuid>>>>, 
+    /// Mentions for offline users, by username. Held in memory only, so a restart drops them.
+    pub mention_inbox: Arc<Mutex<HashMap<String, Vec<PendingMention>>>>,
+
+    /// Sessions on other nodes, kept in sync over the bus
+    pub directory: Arc<Mutex<Directory>>,
+    pub bus: Arc<Bus>,
+    pub outbox: Arc<Outbox>,
+    pub backend: Arc<Backend>,
+    pub auth: Arc<Authenticator>,
+    pub token_sources: Arc<TokenSources>,
+    pub origin_policy: Arc<OriginPolicy>,
+    pub proxy_config: Arc<ProxyConfig>,
+    pub search: Arc<SearchBackend>,
+    pub uploads: Arc<Mutex<HashMap<Uuid, PendingUpload>>>,
+    pub upload_config: Arc<UploadConfig>,
+    pub compression_config: Arc<CompressionConfig>,
+    pub compression_stats: Arc<CompressionStats>,
+    pub fallback_sessions: Arc<Mutex<HashMap<Uuid, FallbackSession>>>,
+}
 
-    pub _tx: broadcast::Sender<String>,
-    pub http_client: reqwest::Client,
+/// A session served over SSE or long-polling instead of a WebSocket.
+/// Removing it from `fallback_sessions` ends the session.
+pub struct FallbackSession {
+    /// Secret handed out with the session id, required to poll or post events
+    pub key: Uuid,
+    pub session: Session,
+    /// Events waiting for the next poll. `None` for SSE, where the stream owns the receiver.
+    pub queue: Option<Arc<tokio::sync::Mutex<mpsc::Receiver<axum::extract::ws::Message>>>>,
+    pub _close: oneshot::Sender<()>,
 }
 
+/// A mention kept for a user who was offline when it happened
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingMention {
+    pub room_name: String,
+    pub message_id: Option<i32>,
+    pub from: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
 pub struct MessageAuthor {
     pub id: i32,
     pub username: String,
@@ -45,7 +104,7 @@ pub struct MessageAuthor {
     pub avatar_url: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
 pub struct RoomMessage{
     pub id: i32,
     pub content: String,
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use axum_server::tls_rustls::RustlsConfig;
+
+/// How often the certificate files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Reloads `config` when the certificate or key changes on disk, or on SIGHUP.
+/// New handshakes pick up the new certificate; open connections are untouched.
+pub async fn watch(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_seen: (Option<SystemTime>, Option<SystemTime>) = (modified(&cert), modified(&key));
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("failed to install SIGHUP handler");
+
+    loop {
+        #[cfg(unix)]
+        let forced = tokio::select! {
+            _ = interval.tick() => false,
+            _ = hangup.recv() => true,
+        };
+        #[cfg(not(unix))]
+        let forced = {
+            interval.tick().await;
+            false
+        };
+
+        let current = (modified(&cert), modified(&key));
+        if !forced && current == last_seen {
+            continue;
+        }
+        // A renewal writes two files; wait a moment so we don't load a cert with the old key
+        if !forced {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => {
+                println!("Reloaded TLS certificate from {}", cert.display());
+                last_seen = (modified(&cert), modified(&key));
+            }
+            // Keep serving the old certificate and try again on the next change
+            Err(e) => {
+                println!("Failed to reload TLS certificate, keeping the current one: {}", e);
+                last_seen = current;
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+use uuid::Uuid;
+
+use crate::auth::AuthenticatedUser;
+use crate::catalog::RoomCatalog;
+use crate::state::AppState;
+
+/// Binary chunk frames start with the upload id (16 bytes), the byte offset
+/// (u64, big endian) and a CRC32 of the data (u32, big endian)
+pub const CHUNK_HEADER_LEN: usize = 16 + 8 + 4;
+/// How often abandoned uploads are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct UploadConfig {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    pub chunk_size: u32,
+    pub allowed_types: Vec<String>,
+    /// Uploads that receive nothing for this long are dropped with their `.part` file
+    pub ttl: Duration,
+    /// Uploads one session may have in progress at once
+    pub max_per_session: usize,
+}
+
+impl UploadConfig {
+    /// Reads `UPLOAD_DIR`, `UPLOAD_MAX_BYTES`, `UPLOAD_CHUNK_SIZE`, `UPLOAD_ALLOWED_TYPES` (comma separated),
+    /// `UPLOAD_TTL` (seconds) and `UPLOAD_MAX_PER_SESSION`
+    pub fn from_env() -> Self {
+        let allowed_types = std::env::var("UPLOAD_ALLOWED_TYPES")
+            .map(|v| v.split(',').map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()).collect())
+            .unwrap_or_else(|_| {
+                ["image/png", "image/jpeg", "image/gif", "image/webp", "application/pdf", "text/plain"]
+                    .iter().map(|t| t.to_string()).collect()
+            });
+
+        UploadConfig {
+            dir: std::env::var("UPLOAD_DIR").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("uploads")),
+            max_bytes: env_number("UPLOAD_MAX_BYTES").unwrap_or(25 * 1024 * 1024),
+            chunk_size: env_number("UPLOAD_CHUNK_SIZE").unwrap_or(64 * 1024),
+            allowed_types,
+            ttl: Duration::from_secs(env_number("UPLOAD_TTL").unwrap_or(3600)),
+            max_per_session: env_number("UPLOAD_MAX_PER_SESSION").unwrap_or(4),
+        }
+    }
+
+    pub fn is_allowed_type(&self, mime_type: &str) -> bool {
+        self.allowed_types.iter().any(|t| t == &mime_type.to_lowercase())
+    }
+
+    pub fn part_path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    pub fn file_path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    pub fn meta_path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+fn env_number<T: std::str::FromStr>(key: &str) -> Option<T> {
+    std::env::var(key).ok().and_then(|v| v.parse().ok())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", content = "name", rename_all = "snake_case")]
+pub enum UploadTarget {
+    Room(String),
+    User(String),
+}
+
+/// An upload in progress. It survives the uploader disconnecting so the
+/// same user can resume it from `received` on a new connection, until it
+/// has been idle for `UploadConfig::ttl`.
+#[derive(Debug, Clone)]
+pub struct PendingUpload {
+    pub owner_db_id: i32,
+    /// Session that started or last resumed it, for the per-session limit
+    pub session_id: Uuid,
+    pub last_activity: Instant,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub received: u64,
+    pub target: UploadTarget,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, TS)]
+pub struct FileAttachment {
+    pub id: Uuid,
+    pub file_name: String,
+    pub mime_type: String,
+    #[ts(type = "number")]
+    pub size: u64,
+    pub url: String,
+}
+
+impl FileAttachment {
+    pub fn new(id: Uuid, upload: &PendingUpload) -> Self {
+        FileAttachment {
+            id,
+            file_name: upload.file_name.clone(),
+            mime_type: upload.mime_type.clone(),
+            size: upload.size,
+            url: format!("/files/{}", id),
+        }
+    }
+}
+
+/// What the `.json` file next to a completed upload holds: the attachment as
+/// clients see it, plus who may download it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredFile {
+    #[serde(flatten)]
+    pub attachment: FileAttachment,
+    #[serde(default)]
+    pub owner_db_id: i32,
+    /// `None` for files stored before targets were recorded; only their uploader can read them
+    #[serde(default)]
+    pub target: Option<UploadTarget>,
+}
+
+impl StoredFile {
+    /// The uploader, the DM recipient, and anyone who can see the room may download it
+    pub async fn readable_by(&self, user: &AuthenticatedUser, state: &AppState) -> Result<bool, StatusCode> {
+        if self.owner_db_id != 0 && self.owner_db_id == user.db_user_id {
+            return Ok(true);
+        }
+        match &self.target {
+            None => Ok(false),
+            Some(UploadTarget::User(username)) => Ok(*username == user.username),
+            Some(UploadTarget::Room(room)) => {
+                if !RoomCatalog::ensure_loaded(&state.catalog, &state.backend).await {
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                }
+                let private = state.catalog.lock().unwrap().get(room).map(|r| r.is_private);
+                Ok(match private {
+                    Some(false) => true,
+                    Some(true) => state.memberships.lock().unwrap().role(room, user.db_user_id).is_some(),
+                    None => false,
+                })
+            }
+        }
+    }
+}
+
+pub struct ChunkFrame<'a> {
+    pub upload_id: Uuid,
+    pub offset: u64,
+    pub checksum: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> ChunkFrame<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        if frame.len() < CHUNK_HEADER_LEN {
+            return None;
+        }
+        let upload_id = Uuid::from_slice(&frame[..16]).ok()?;
+        let offset = u64::from_be_bytes(frame[16..24].try_into().ok()?);
+        let checksum = u32::from_be_bytes(frame[24..28].try_into().ok()?);
+        Some(ChunkFrame { upload_id, offset, checksum, data: &frame[CHUNK_HEADER_LEN..] })
+    }
+
+    pub fn checksum_ok(&self) -> bool {
+        crc32fast::hash(self.data) == self.checksum
+    }
+}
+
+/// Strips directories and control characters so the name is safe to echo in headers
+pub fn sanitize_file_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base.chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(255)
+        .collect();
+    if cleaned.trim().is_empty() { "file".to_string() } else { cleaned }
+}
+
+/// Drops uploads idle for longer than `UploadConfig::ttl`, along with their
+/// `.part` files. `.part` files no upload refers to, such as those left by a
+/// previous run, are deleted once they are that old too.
+pub async fn sweep(state: AppState) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let removed = sweep_once(&state).await;
+        if removed > 0 {
+            println!("Removed {} abandoned uploads", removed);
+        }
+    }
+}
+
+/// One pass of `sweep`, returning how many `.part` files were removed
+pub async fn sweep_once(state: &AppState) -> usize {
+    let config = &state.upload_config;
+
+    // 1. Expire idle uploads
+    let expired: Vec<Uuid> = {
+        let mut uploads = state.uploads.lock().unwrap();
+        let expired: Vec<Uuid> = uploads.iter()
+            .filter(|(_, u)| u.last_activity.elapsed() >= config.ttl)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &expired {
+            uploads.remove(id);
+        }
+        expired
+    };
+    let mut removed = 0;
+    for id in expired {
+        if tokio::fs::remove_file(config.part_path(id)).await.is_ok() {
+            removed += 1;
+        }
+    }
+
+    // 2. Clean up orphaned parts
+    let Ok(mut entries) = tokio::fs::read_dir(&config.dir).await else { return removed };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let Some(id) = path.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".part"))
+            .and_then(|n| Uuid::parse_str(n).ok())
+        else { continue };
+        if state.uploads.lock().unwrap().contains_key(&id) {
+            continue;
+        }
+        let old = entry.metadata().await
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age >= config.ttl));
+        if old && tokio::fs::remove_file(&path).await.is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// `GET /files/:id` - streams a completed upload with its original name and type.
+/// Takes the token from the same places as `/ws` and answers 404 to anyone
+/// who may not read the file, so ids can't be probed.
+pub async fn download_file(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let config = &state.upload_config;
+
+    // 1. Who is asking
+    let Some(token) = state.token_sources.from_request(&headers, &params) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let user = match state.auth.authenticate(&token).await {
+        Ok(user) if !user.is_expired() => user,
+        _ => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+
+    // 2. Whether they may read it
+    let meta = match tokio::fs::read(config.meta_path(id)).await {
+        Ok(bytes) => bytes,
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let Ok(stored) = serde_json::from_slice::<StoredFile>(&meta) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match stored.readable_by(&user, &state).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(status) => return status.into_response(),
+    }
+
+    // 3. Stream it rather than reading it all into memory
+    let file = match tokio::fs::File::open(config.file_path(id)).await {
+        Ok(file) => file,
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let attachment = stored.attachment;
+    (
+        [
+            (header::CONTENT_TYPE, attachment.mime_type),
+            (header::CONTENT_LENGTH, attachment.size.to_string()),
+            (header::CONTENT_DISPOSITION, format!("inline; filename=\"{}\"", attachment.file_name)),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            (header::CACHE_CONTROL, "private".to_string()),
+        ],
+        Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+    ).into_response()
+}
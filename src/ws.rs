@@ -2,44 +2,127 @@ use axum::extract::ws::{WebSocket, Message};
 use futures_util::{sink::SinkExt, stream::{StreamExt, SplitSink, SplitStream}};
 use tokio::sync::mpsc;
 use uuid::Uuid;
-use std::time::Instant;
-use std::collections::HashSet;
+use std::time::{Instant, SystemTime};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 
 use tokio::time::{self, Duration};
 
-use my_websocket::state::{AppState, UserInfo, RoomMessage, MessageAuthor};
-use my_websocket::events::{ClientEvent, ServerEvent};
-
-async fn handle_client_event(
+use my_websocket::auth::AuthenticatedUser;
+use my_websocket::state::{AppState, UserInfo, RoomMessage, MessageAuthor, PendingMention};
+use my_websocket::events::{ClientEvent, ErrorCode, ServerEvent};
+use my_websocket::mentions::parse_mentions;
+use my_websocket::search::SearchQuery;
+use my_websocket::protocol::{Codec, Session, SUPPORTED_VERSIONS};
+use my_websocket::compression::Deflater;
+use my_websocket::uploads::{ChunkFrame, FileAttachment, PendingUpload, StoredFile, UploadTarget, sanitize_file_name};
+use my_websocket::bus::BusEvent;
+use my_websocket::backend::{BackendError, Endpoint};
+use my_websocket::catalog::{validate_room_name, RoomCatalog, RoomDetails, MAX_DESCRIPTION_LEN};
+use crate::fanout::{forget_room, remote_session, room_user, to_everyone, to_members, to_profile, to_room, to_user};
+
+/// Number of messages sent on `JoinRoom` and when `LoadHistory` omits a limit
+const DEFAULT_HISTORY_PAGE: u32 = 50;
+/// Upper bound on a single `LoadHistory` page
+const MAX_HISTORY_PAGE: u32 = 200;
+/// Mentions kept per offline user; older ones are dropped first
+const MAX_PENDING_MENTIONS: usize = 50;
+/// Hits returned when `SearchMessages` omits a limit, and the most it may ask for
+const DEFAULT_SEARCH_LIMIT: u32 = 20;
+const MAX_SEARCH_LIMIT: u32 = 100;
+/// How long a new connection has to send `Hello` before it is treated as a version 1 client
+const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long `RoomBroadcast` waits for the database id before sending the message without it
+const PERSIST_WAIT: Duration = Duration::from_secs(2);
+/// `room_members.role` values allowed to moderate a room
+const MODERATOR_ROLES: [&str; 3] = ["owner", "admin", "moderator"];
+/// `room_members.role` values allowed to change a room's description and privacy
+const ROOM_ADMIN_ROLES: [&str; 2] = ["owner", "admin"];
+
+pub async fn handle_client_event(
     event: ClientEvent, 
     state: AppState, 
     user_id: Uuid, 
     tx: mpsc::Sender<Message>
 ) {
     match event {
+        ClientEvent::Hello { .. } => {
+            send_error(tx, ErrorCode::InvalidPayload, "Hello is only accepted as the first frame").await;
+        }
+        ClientEvent::Authenticate { .. } => {
+            send_error(tx, ErrorCode::InvalidPayload, "Already authenticated, use RefreshToken to change tokens").await;
+        }
+        ClientEvent::RefreshToken { token } => {
+            let auth = match state.auth.authenticate(&token).await {
+                Ok(auth) if !auth.is_expired() => auth,
+                Ok(_) => {
+                    send_error(tx, ErrorCode::Unauthorized, "Token has already expired").await;
+                    return;
+                }
+                Err(e) => {
+                    println!("Token refresh for {} rejected: {}", user_id, e);
+                    send_error(tx, ErrorCode::Unauthorized, "Token is not valid").await;
+                    return;
+                }
+            };
+
+            // 1. The new token has to belong to the same account
+            let refreshed = {
+                let mut users = state.users.lock().unwrap();
+                match users.get_mut(&user_id) {
+                    Some(user) if user.db_user_id == auth.db_user_id => {
+                        user.expires_at = auth.deadline();
+                        true
+                    }
+                    _ => false,
+                }
+            };
+            if !refreshed {
+                send_error(tx, ErrorCode::Forbidden, "Token belongs to a different user").await;
+                return;
+            }
+
+            // 2. Confirm the new deadline
+            let event = ServerEvent::TokenRefreshed { expires_at: auth.expires_at_unix() };
+            let _ = tx.send(Message::Text(serde_json::to_string(&event).unwrap())).await;
+        }
         ClientEvent::JoinRoom(room_name) => {
             println!("User {} is joining room: {}", user_id, room_name);
-            
-            // 1. Update the rooms map (Room -> User list)
+
+            // 1. The room has to exist and be open to this user; new rooms come from CreateRoom
+            if let Err((code, message)) = check_room_visible(&room_name, user_id, &state).await {
+                send_error(tx, code, &message).await;
+                return;
+            }
+
+            // 2. Update the rooms map (Room -> User list)
             let count = {
                 let mut rooms = state.rooms.lock().unwrap();
                 rooms.entry(room_name.clone())
-                    .or_insert_with(HashSet::new)
+                    .or_default()
                     .insert(user_id);
                 rooms.get(&room_name).unwrap().len()
             }; 
 
-            // 2. Update the user's personal room list (User -> Room list)
+            // 3. Update the user's personal room list (User -> Room list)
             {
                 let mut users = state.users.lock().unwrap();
                 if let Some(user) = users.get_mut(&user_id) {
                     user.rooms.insert(room_name.clone());
                 }
             }
+
+            // 4. Let the other nodes know
+            state.bus.publish(BusEvent::Joined { room: room_name.clone(), session_id: user_id }).await;
+
+            // 5. Joining makes the user a member until they leave for good
+            set_membership(&room_name, user_id, true, &state).await;
+
             broadcast_status_update(user_id, "online", &state).await;
             broadcast_room_update(&room_name, &state).await;
             broadcast_user_joined(&room_name, user_id, &state).await;
-            fetch_room_messages(&room_name, tx.clone(), &state).await;
+            fetch_room_messages(&room_name, None, DEFAULT_HISTORY_PAGE, tx.clone(), &state).await;
+            send_room_pins(&room_name, tx.clone(), &state).await;
             
             println!("Room {} now has {} users", room_name, count);
         }
@@ -59,76 +142,70 @@ async fn handle_client_event(
                 from_display_name: display_name,
                 created_at,
                 edited_at: None,
+                mentions: Vec::new(),
+                message_type: "text".to_string(),
+                attachment: None,
             };
             let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
             if tx.send(msg).await.is_err() {
-                send_error(tx, "500", "Failed to send message").await;
+                send_error(tx, ErrorCode::Internal, "Failed to send message").await;
             }
         }
         ClientEvent::LeaveRoom(room_name) => {
             println!("User {} is leaving room", user_id);
-            
-            // 1. Update the rooms map via helper
-            perform_leave_room(&room_name, user_id, &state);
-
-            // 2. Update the user's personal room list
-            {
-                let mut users = state.users.lock().unwrap();
-                if let Some(user) = users.get_mut(&user_id) {
-                    user.rooms.remove(&room_name);
-                }
+            close_room(&room_name, user_id, &state).await;
+            set_membership(&room_name, user_id, false, &state).await;
+        }
+        ClientEvent::CloseRoom(room_name) => {
+            println!("User {} closed room {}", user_id, room_name);
+            close_room(&room_name, user_id, &state).await;
+        }
+        ClientEvent::CreateRoom { name, description, is_private } => {
+            if let Err((code, message)) = create_room(&name, description, is_private, user_id, &state).await {
+                send_error(tx, code, &message).await;
+            }
+        }
+        ClientEvent::UpdateRoom { name, description, is_private } => {
+            if let Err((code, message)) = update_room(&name, description, is_private, user_id, &state).await {
+                send_error(tx, code, &message).await;
+            }
+        }
+        ClientEvent::DeleteRoom { name } => {
+            if let Err((code, message)) = delete_room(&name, user_id, &state).await {
+                send_error(tx, code, &message).await;
             }
-            
-            broadcast_room_update(&room_name, &state).await;
         }
         ClientEvent::PrivateMessage { payload, target_username } => {
             if payload.trim().is_empty(){
-                send_error(tx, "400", "You cannot send an empty message").await;
+                send_error(tx, ErrorCode::InvalidPayload, "You cannot send an empty message").await;
                 return;
             }
 
-            // Get sender's username and find target by username
-            let (from_username, target_tx, is_self_message) = {
+            let (from_username, from_display_name) = {
                 let users = state.users.lock().unwrap();
-                let from_username = users.get(&user_id)
-                    .map(|u| u.username.clone())
-                    .unwrap_or_else(|| "Unknown".to_string());
-                
-                let is_self = from_username == target_username;
-                
-                let target_tx = users.values()
-                    .find(|u| u.username == target_username)
-                    .map(|u| u.tx.clone());
-                
-                (from_username, target_tx, is_self)
+                users.get(&user_id)
+                    .map(|u| (u.username.clone(), u.display_name.clone()))
+                    .unwrap_or_else(|| ("Unknown".to_string(), "Unknown".to_string()))
             };
             
-            if is_self_message {
-                send_error(tx, "400", "You cannot send a private message to yourself").await;
+            if from_username == target_username {
+                send_error(tx, ErrorCode::InvalidPayload, "You cannot send a private message to yourself").await;
                 return;
             }
-            
-            if let Some(receptor) = target_tx {
-                let from_display_name = {
-                    let users = state.users.lock().unwrap();
-                    users.get(&user_id).map(|u| u.display_name.clone()).unwrap_or_else(|| from_username.clone())
-                };
 
-                let created_at = chrono::Utc::now().to_rfc3339();
-                let out_event = ServerEvent::PrivateMessage { 
-                    from_id: user_id, 
-                    from_username, 
-                    from_display_name, 
-                    payload,
-                    created_at,
-                    edited_at: None,
-                };
-                let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
-                if receptor.send(msg).await.is_err() {
-                    send_error(tx, "500", "Destination user disconnected abruptly").await;
-                }
-            } else {
-                send_error(tx, "400", "User is offline or not found").await;
+            let created_at = chrono::Utc::now().to_rfc3339();
+            let out_event = ServerEvent::PrivateMessage { 
+                from_id: user_id, 
+                from_username, 
+                from_display_name, 
+                payload,
+                created_at,
+                edited_at: None,
+                attachment: None,
+            };
+            let delivered = to_user(&target_username, serde_json::to_string(&out_event).unwrap(), &state).await;
+            if !delivered && !state.directory.lock().unwrap().is_online(&target_username) {
+                send_error(tx, ErrorCode::UserOffline, "User is offline or not found").await;
             }
         }
         ClientEvent::ServerBroadcast{ payload } => {
@@ -147,89 +224,92 @@ async fn handle_client_event(
                 from_display_name: display_name,
                 created_at,
                 edited_at: None,
+                mentions: Vec::new(),
+                message_type: "text".to_string(),
+                attachment: None,
             };
-            let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
-            let transmitters: Vec<_> = {
-                let users = state.users.lock().unwrap();
-                users.iter()
-                    .filter(|(id, _)| *id != &user_id) // Skip the sender
-                    .map(|(_, info)| info.tx.clone())
-                    .collect()
-            };
-
-            // 2. Send the message
-            for tx in transmitters {
-                let _ = tx.send(msg.clone()).await;
-            }
+            // Everyone but the sender
+            to_everyone(serde_json::to_string(&out_event).unwrap(), Some(user_id), &state).await;
         }   
         ClientEvent::RoomBroadcast{ payload , room_name} => {
-            let (username, display_name) = {
+            if let Err((code, message)) = check_room_visible(&room_name, user_id, &state).await {
+                send_error(tx, code, &message).await;
+                return;
+            }
+
+            let (username, display_name, db_id, avatar_url) = {
                 let users = state.users.lock().unwrap();
                 users.get(&user_id)
-                    .map(|u| (u.username.clone(), u.display_name.clone()))
-                    .unwrap_or_else(|| ("Unknown".to_string(), "Unknown".to_string()))
+                    .map(|u| (u.username.clone(), u.display_name.clone(), u.db_user_id, u.avatar_url.clone()))
+                    .unwrap_or_else(|| ("Unknown".to_string(), "Unknown".to_string(), 0, None))
             };
 
+            println!("User {} is broadcasting to room {}: {}", user_id, room_name, payload);
+
+            let resolved = resolve_mentions(parse_mentions(&payload), &state).await;
+            let mentions: Vec<String> = resolved.iter().map(|(name, _)| name.clone()).collect();
+
+            let message_id = persist_message(&room_name, db_id, &payload, "text", None, &state).await;
+
             let created_at = chrono::Utc::now().to_rfc3339();
+            if let Some(id) = message_id {
+                state.search.index(&room_name, &RoomMessage {
+                    id,
+                    content: payload.clone(),
+                    created_at: created_at.clone(),
+                    edited_at: None,
+                    message_type: "text".to_string(),
+                    user: MessageAuthor {
+                        id: db_id,
+                        username: username.clone(),
+                        display_name: display_name.clone(),
+                        avatar_url,
+                    },
+                });
+            }
+
             let out_event = ServerEvent::SendMessage { 
                 payload: payload.clone(),
                 from_id: user_id,
-                from_username: username,
+                from_username: username.clone(),
                 from_display_name: display_name,
                 created_at,
                 edited_at: None,
+                mentions: mentions.clone(),
+                message_type: "text".to_string(),
+                attachment: None,
             };
-            let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
-            
-            println!("User {} is broadcasting to room {}: {}", user_id, room_name, payload);
+            // 2. Send the message to everyone but the sender
+            to_room(&room_name, serde_json::to_string(&out_event).unwrap(), Some(user_id), &state).await;
 
+            // 3. Notify mentioned users wherever they are
+            for (target, target_db_id) in resolved.iter().filter(|(m, _)| *m != username) {
+                notify_mention(target, *target_db_id, &room_name, message_id, &username, &state).await;
+            }
+        }
+        ClientEvent::GetRoomList => {
             let db_id = {
                 let users = state.users.lock().unwrap();
                 users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
             };
-
-            match state.http_client.post("https://localhost:443/api/internal/messages")
-                .json(&serde_json::json!({
-                    "roomId": room_name,
-                    "userId": db_id,
-                    "content": payload
-                }))
-                .send().await {
-                    Ok(response) => {
-                        if response.status().is_success() {
-                            println!("Message saved to database successfully");
-                        } else {
-                            println!("Failed to save message: HTTP {}", response.status());
-                        }
-                    }
-                    Err(e) => println!("Failed to reach API server: {}", e),
-                }
-
-            let transmitters: Vec<_> = {
-                let rooms = state.rooms.lock().unwrap();
-
-                if let Some(members) = rooms.get(&room_name) {
-                    let users = state.users.lock().unwrap();
-                    members.iter()
-                        .filter(|&&id| id != user_id) // Skip the sender
-                        .filter_map(|id| users.get(id).map(|info| info.tx.clone()))
-                        .collect()
-                } else {
-                    Vec::new()
-                }
-            };
-            // 2. Send the message
-            for tx in transmitters {
-                let _ = tx.send(msg.clone()).await;
-            }
-        }
-        ClientEvent::GetRoomList => {
             let msg = {
                  let rooms = state.rooms.lock().unwrap();
-                 let room_entries: Vec<my_websocket::events::RoomListEntry> = rooms.iter()
-                     .map(|(name, members)| my_websocket::events::RoomListEntry {
+                 let remote = state.directory.lock().unwrap().room_counts();
+                 let memberships = state.memberships.lock().unwrap();
+                 let catalog = state.catalog.lock().unwrap();
+                 // Rooms stay listed while nobody is in them; private ones only for their members
+                 let names: HashSet<&String> = rooms.keys()
+                     .chain(remote.keys())
+                     .chain(memberships.room_names())
+                     .chain(catalog.rooms().map(|r| &r.name))
+                     .filter(|name| catalog.get(name).is_none_or(|r| !r.is_private || memberships.role(name, db_id).is_some()))
+                     .collect();
+                 let room_entries: Vec<my_websocket::events::RoomListEntry> = names.into_iter()
+                     .map(|name| my_websocket::events::RoomListEntry {
                          name: name.clone(),
-                         count: members.len(),
+                         count: rooms.get(name).map_or(0, |m| m.len()) + remote.get(name).copied().unwrap_or(0),
+                         description: catalog.get(name).and_then(|r| r.description.clone()),
+                         is_private: catalog.get(name).is_some_and(|r| r.is_private),
                      })
                      .collect();
                  let out_event = ServerEvent::RoomList { rooms: room_entries };
@@ -239,7 +319,7 @@ async fn handle_client_event(
         }
         ClientEvent::ChangeDisplayname{ display_name } => {
             if display_name.trim().is_empty(){
-                send_error(tx, "400", "Display name cannot be empty").await;
+                send_error(tx, ErrorCode::InvalidPayload, "Display name cannot be empty").await;
                 return;
             }
 
@@ -267,31 +347,16 @@ async fn handle_client_event(
                     user.display_name = display_name.clone();
                 }
             }
+            if let Some(user) = room_user(user_id, &state) {
+                state.bus.publish(BusEvent::Presence { session_id: user_id, user }).await;
+            }
 
             // 4. Broadcast to Subscribers
-            let subscribers = {
-                let subs = state.profile_subscribers.lock().unwrap();
-                subs.get(&db_id).cloned()
+            let out_event = ServerEvent::DisplaynameChanged { 
+                old: old_name.clone(), 
+                new: display_name.clone() 
             };
-
-            if let Some(sessions) = subscribers {
-                let out_event = ServerEvent::DisplaynameChanged { 
-                    old: old_name.clone(), 
-                    new: display_name.clone() 
-                };
-                let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
-
-                let transmitters: Vec<_> = {
-                    let users = state.users.lock().unwrap();
-                    sessions.iter()
-                        .filter_map(|id| users.get(id).map(|u| u.tx.clone()))
-                        .collect()
-                };
-
-                for tx in transmitters {
-                    let _ = tx.send(msg.clone()).await;
-                }
-            }
+            to_profile(db_id, serde_json::to_string(&out_event).unwrap(), &state).await;
 
             // 5. Broadcast to Rooms
             let rooms: Vec<String> = {
@@ -317,12 +382,16 @@ async fn handle_client_event(
             }
         }
         ClientEvent::GetRoomUsers(room_name) => {
+            if let Err((code, message)) = check_room_visible(&room_name, user_id, &state).await {
+                send_error(tx, code, &message).await;
+                return;
+            }
             fetch_and_send_room_members(&room_name, tx.clone(), &state).await;
         }
         ClientEvent::UpdateStatus(status) => {
             let valid_statuses = ["online", "away", "busy", "offline"];
             if !valid_statuses.contains(&status.to_lowercase().as_str()) {
-                send_error(tx, "400", "Invalid status. Use: online, away, busy, offline").await;
+                send_error(tx, ErrorCode::InvalidPayload, "Invalid status. Use: online, away, busy, offline").await;
                 return;
             }
             
@@ -342,6 +411,7 @@ async fn handle_client_event(
                 users.values()
                     .find(|u| u.db_user_id == target_id)
                     .map(|u| u.status.clone())
+                    .or_else(|| state.directory.lock().unwrap().status_of(target_id))
                     .unwrap_or_else(|| "offline".to_string())
             };
 
@@ -368,115 +438,705 @@ async fn handle_client_event(
                 users.values()
                 .find(|u| u.display_name == target_display_name)
                 .map(|u| u.username.clone())
+                .or_else(|| state.directory.lock().unwrap().username_for(&target_display_name))
                 .unwrap_or_else(|| "Unknown".to_string())
             };
         
-            let out_event = ServerEvent::RecieveUsername{ username: username };
+            let out_event = ServerEvent::RecieveUsername{ username };
             let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
 
             let _ = tx.send(msg).await;
         }
+        ClientEvent::LoadHistory { room_name, before_id, limit } => {
+            if let Err((code, message)) = check_room_visible(&room_name, user_id, &state).await {
+                send_error(tx, code, &message).await;
+                return;
+            }
+            let limit = limit.unwrap_or(DEFAULT_HISTORY_PAGE).clamp(1, MAX_HISTORY_PAGE);
+            fetch_room_messages(&room_name, before_id, limit, tx, &state).await;
+        }
+        ClientEvent::PinMessage { room_name, message_id } => {
+            set_message_pinned(&room_name, message_id, true, user_id, tx, &state).await;
+        }
+        ClientEvent::UnpinMessage { room_name, message_id } => {
+            set_message_pinned(&room_name, message_id, false, user_id, tx, &state).await;
+        }
+        ClientEvent::BeginUpload { upload_id, file_name, size, mime_type, room_name, target_username } => {
+            let db_id = {
+                let users = state.users.lock().unwrap();
+                users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
+            };
+
+            // Resuming: the client reconnected and wants to know where to continue from
+            if let Some(id) = upload_id {
+                let resumed = {
+                    let uploads = state.uploads.lock().unwrap();
+                    uploads.get(&id).map(|u| (u.owner_db_id, u.received))
+                };
+                match resumed {
+                    Some((owner, received)) if owner == db_id => {
+                        if let Some(upload) = state.uploads.lock().unwrap().get_mut(&id) {
+                            upload.session_id = user_id;
+                            upload.last_activity = Instant::now();
+                        }
+                        send_upload_ready(id, received, &tx, &state).await;
+                    }
+                    Some(_) => send_error(tx, ErrorCode::Forbidden, "This upload belongs to another user").await,
+                    None => send_error(tx, ErrorCode::NotFound, "Unknown or expired upload").await,
+                }
+                return;
+            }
+
+            let target = match (room_name, target_username) {
+                (Some(room), None) => {
+                    let in_room = {
+                        let users = state.users.lock().unwrap();
+                        users.get(&user_id).is_some_and(|u| u.rooms.contains(&room))
+                    };
+                    if !in_room {
+                        send_error(tx, ErrorCode::NotInRoom, "You must join the room before sharing files").await;
+                        return;
+                    }
+                    UploadTarget::Room(room)
+                }
+                (None, Some(username)) => UploadTarget::User(username),
+                _ => {
+                    send_error(tx, ErrorCode::InvalidPayload, "Specify either room_name or target_username").await;
+                    return;
+                }
+            };
+
+            let config = &state.upload_config;
+            if size == 0 || size > config.max_bytes {
+                send_error_with_details(
+                    tx,
+                    ErrorCode::PayloadTooLarge,
+                    "File is empty or larger than the upload limit",
+                    serde_json::json!({ "max_bytes": config.max_bytes })
+                ).await;
+                return;
+            }
+            if !config.is_allowed_type(&mime_type) {
+                send_error(tx, ErrorCode::UnsupportedMediaType, "This file type is not allowed").await;
+                return;
+            }
+            let in_progress = state.uploads.lock().unwrap().values().filter(|u| u.session_id == user_id).count();
+            if in_progress >= config.max_per_session {
+                send_error_with_details(
+                    tx,
+                    ErrorCode::RateLimited,
+                    "Too many uploads in progress, finish or cancel one first",
+                    serde_json::json!({ "max_per_session": config.max_per_session })
+                ).await;
+                return;
+            }
+
+            let id = Uuid::new_v4();
+            let created = match tokio::fs::create_dir_all(&config.dir).await {
+                Ok(()) => tokio::fs::File::create(config.part_path(id)).await.map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = created {
+                println!("Failed to create upload file: {}", e);
+                send_error(tx, ErrorCode::Internal, "Failed to start upload").await;
+                return;
+            }
+
+            {
+                let mut uploads = state.uploads.lock().unwrap();
+                uploads.insert(id, PendingUpload {
+                    owner_db_id: db_id,
+                    session_id: user_id,
+                    last_activity: Instant::now(),
+                    file_name: sanitize_file_name(&file_name),
+                    mime_type: mime_type.to_lowercase(),
+                    size,
+                    received: 0,
+                    target,
+                });
+            }
+            println!("User {} started upload {} ({} bytes)", user_id, id, size);
+            send_upload_ready(id, 0, &tx, &state).await;
+        }
+        ClientEvent::CancelUpload { upload_id } => {
+            let db_id = {
+                let users = state.users.lock().unwrap();
+                users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
+            };
+            let removed = {
+                let mut uploads = state.uploads.lock().unwrap();
+                match uploads.get(&upload_id) {
+                    Some(u) if u.owner_db_id == db_id => uploads.remove(&upload_id),
+                    _ => None,
+                }
+            };
+            if removed.is_some() {
+                let _ = tokio::fs::remove_file(state.upload_config.part_path(upload_id)).await;
+            }
+        }
+        ClientEvent::UploadChunk { upload_id, offset, checksum, data } => {
+            let chunk = ChunkFrame { upload_id, offset, checksum, data: &data };
+            handle_upload_chunk(chunk, user_id, tx, &state).await;
+        }
+        ClientEvent::SearchMessages { query, room_name, from_user, before, after, limit } => {
+            if query.trim().is_empty() {
+                send_error(tx, ErrorCode::InvalidPayload, "Search query cannot be empty").await;
+                return;
+            }
+
+            // Only rooms the user belongs to or this session is viewing can be searched
+            let readable_rooms: Vec<String> = {
+                let users = state.users.lock().unwrap();
+                let memberships = state.memberships.lock().unwrap();
+                let rooms: HashSet<String> = users.get(&user_id)
+                    .map(|u| u.rooms.iter().cloned().chain(memberships.rooms_of(u.db_user_id)).collect())
+                    .unwrap_or_default();
+                rooms.into_iter().collect()
+            };
+
+            if room_name.as_ref().is_some_and(|r| !readable_rooms.contains(r)) {
+                send_error(tx, ErrorCode::NotInRoom, "You can only search rooms you have joined").await;
+                return;
+            }
+
+            let search_query = SearchQuery {
+                query,
+                room_name,
+                from_user,
+                before,
+                after,
+                limit: limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT),
+            };
+
+            match state.search.search(&search_query, &readable_rooms).await {
+                Ok(hits) => {
+                    let out_event = ServerEvent::SearchResults { query: search_query.query, hits };
+                    let _ = tx.send(Message::Text(serde_json::to_string(&out_event).unwrap())).await;
+                }
+                Err(e) => {
+                    println!("Search failed: {}", e);
+                    send_error(tx, ErrorCode::BackendUnavailable, "Search is currently unavailable").await;
+                }
+            }
+        }
+    }
+}
+/// Saves a room message through the outbox and returns its database id. If the
+/// API doesn't answer within `PERSIST_WAIT`, the outbox keeps retrying in the
+/// background and the message goes out without an id.
+async fn persist_message(
+    room_name: &str,
+    db_id: i32,
+    content: &str,
+    message_type: &str,
+    metadata: Option<serde_json::Value>,
+    state: &AppState
+) -> Option<i32> {
+    let key = format!("room:{}", room_name);
+    // Earlier messages are still waiting for the API, or the API is known to be down;
+    // either way this one won't get through in time
+    let backlogged = state.outbox.has_pending(&key) || state.backend.is_open();
+
+    let body = serde_json::json!({
+        "roomId": room_name,
+        "userId": db_id,
+        "content": content,
+        "messageType": message_type,
+        "metadata": metadata
+    });
+    let delivered = match state.outbox.submit(key, "https://localhost:443/api/internal/messages".to_string(), body).await {
+        Ok(delivered) => delivered,
+        Err(e) => {
+            println!("Failed to queue message for {}: {}", room_name, e);
+            return None;
+        }
+    };
+    if backlogged {
+        return None;
+    }
+
+    match time::timeout(PERSIST_WAIT, delivered).await {
+        Ok(Ok(data)) => {
+            println!("Message saved to database successfully");
+            data["messageId"].as_i64().map(|id| id as i32)
+        }
+        Ok(Err(_)) => None,
+        Err(_) => {
+            println!("Message for {} queued until the API recovers", room_name);
+            None
+        }
+    }
+}
+
+/// Keeps only the mentioned usernames that belong to real accounts, with their database ids.
+/// Online users are matched locally, the rest are checked against the database.
+async fn resolve_mentions(candidates: Vec<String>, state: &AppState) -> Vec<(String, i32)> {
+    if candidates.is_empty() {
+        return Vec::new();
+    }
+
+    let mut known: HashMap<String, i32> = {
+        let users = state.users.lock().unwrap();
+        users.values()
+            .filter(|u| candidates.contains(&u.username))
+            .map(|u| (u.username.clone(), u.db_user_id))
+            .collect()
+    };
+    let unknown: Vec<&String> = candidates.iter().filter(|name| !known.contains_key(*name)).collect();
+
+    if !unknown.is_empty() {
+        let body = serde_json::json!({ "usernames": unknown });
+        match state.backend
+            .send(Endpoint::ResolveUsers, |c| c.post("https://localhost:443/internal/users/resolve").json(&body))
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                if let Ok(data) = response.json::<serde_json::Value>().await {
+                    for u in data["users"].as_array().into_iter().flatten() {
+                        let (Some(name), Some(id)) = (u["username"].as_str(), u["id"].as_i64()) else { continue };
+                        if let Ok(id) = i32::try_from(id) {
+                            known.insert(name.to_string(), id);
+                        }
+                    }
+                }
+            }
+            Ok(response) => println!("Failed to resolve mentions: HTTP {}", response.status()),
+            Err(e) => println!("Failed to resolve mentions: {}", e),
+        }
+    }
+
+    candidates.into_iter()
+        .filter_map(|name| known.get(&name).map(|id| (name.clone(), *id)))
+        .collect()
+}
+
+/// Whether the user may read the room: it is public or they are a member
+fn can_read_room(room_name: &str, db_user_id: i32, state: &AppState) -> bool {
+    let private = state.catalog.lock().unwrap().get(room_name).map(|r| r.is_private);
+    match private {
+        Some(false) => true,
+        Some(true) => state.memberships.lock().unwrap().role(room_name, db_user_id).is_some(),
+        None => false,
+    }
+}
+
+/// Sends a `Mentioned` event to every session of `target`, or queues it if they are offline.
+/// Users who can't read the room aren't told about it at all.
+async fn notify_mention(target: &str, target_db_id: i32, room_name: &str, message_id: Option<i32>, from: &str, state: &AppState) {
+    if !can_read_room(room_name, target_db_id, state) {
+        return;
+    }
+    let out_event = ServerEvent::Mentioned {
+        room_name: room_name.to_string(),
+        message_id,
+        from: from.to_string(),
+    };
+    let delivered = to_user(target, serde_json::to_string(&out_event).unwrap(), state).await;
+
+    if !delivered && !state.directory.lock().unwrap().is_online(target) {
+        let mut inbox = state.mention_inbox.lock().unwrap();
+        let pending = inbox.entry(target.to_string()).or_default();
+        if pending.len() >= MAX_PENDING_MENTIONS {
+            pending.remove(0);
+        }
+        pending.push(PendingMention {
+            room_name: room_name.to_string(),
+            message_id,
+            from: from.to_string(),
+        });
     }
 }
+
+/// Delivers and clears any mentions queued while the user was offline. This runs
+/// before anything drains the session's queue, so it never waits on it: what
+/// doesn't fit stays in the inbox for the next connection.
+fn deliver_pending_mentions(username: &str, tx: &mpsc::Sender<Message>, state: &AppState) {
+    let mut inbox = state.mention_inbox.lock().unwrap();
+    let Some(mut pending) = inbox.remove(username) else { return };
+
+    while let Some(mention) = pending.first() {
+        let out_event = ServerEvent::Mentioned {
+            room_name: mention.room_name.clone(),
+            message_id: mention.message_id,
+            from: mention.from.clone(),
+        };
+        if tx.try_send(Message::Text(serde_json::to_string(&out_event).unwrap())).is_err() {
+            break;
+        }
+        pending.remove(0);
+    }
+    if !pending.is_empty() {
+        inbox.insert(username.to_string(), pending);
+    }
+}
+
 async fn fetch_change_displayname(
     db_id: i32,
     new_displayname: &str,
     state: &AppState
 ){
-    let _ = state.http_client.post(format!("https://localhost:443/internal/updateDisplayname/{}", db_id))
-        .json(&serde_json::json!({
-            "displayName": new_displayname
-        }))
-        .send()
-        .await;
+    let queued = state.outbox.submit(
+        format!("user:{}", db_id),
+        format!("https://localhost:443/internal/updateDisplayname/{}", db_id),
+        serde_json::json!({ "displayName": new_displayname }),
+    ).await;
+    if let Err(e) = queued {
+        println!("Failed to queue display name change for {}: {}", db_id, e);
+    }
+}
+
+fn parse_room_message(m: &serde_json::Value) -> Option<RoomMessage> {
+    Some(RoomMessage {
+        id: m["id"].as_i64()? as i32,
+        content: m["content"].as_str()?.to_string(),
+        created_at: m["createdAt"].as_str()?.to_string(),
+        edited_at: m["editedAt"].as_str().map(String::from),
+        message_type: m["messageType"].as_str().unwrap_or("text").to_string(),
+        user: MessageAuthor {
+            id: m["user"]["id"].as_i64()? as i32,
+            username: m["user"]["username"].as_str()?.to_string(),
+            display_name: m["user"]["displayName"].as_str()
+                .map(String::from)
+                .unwrap_or_else(|| m["user"]["username"].as_str().unwrap_or("").to_string()),
+            avatar_url: m["user"]["avatarUrl"].as_str().map(String::from),
+        },
+    })
 }
 
+/// Fetches one page of history older than `before_id` (newest page when `None`).
+/// One extra row is requested so we know whether an older page exists.
 async fn fetch_room_messages(    
     room_name: &str,
+    before_id: Option<i32>,
+    limit: u32,
     tx: mpsc::Sender<Message>,
     state: &AppState
 ){
-    match state.http_client
-        .get(format!("https://localhost:443/internal/rooms/{}/messages", room_name))
-        .send()
-        .await
-    {
+    let mut query = vec![("limit", (limit + 1).to_string())];
+    if let Some(before) = before_id {
+        query.push(("before", before.to_string()));
+    }
+
+    let url = format!("https://localhost:443/internal/rooms/{}/messages", room_name);
+    match state.backend.send(Endpoint::History, |c| c.get(&url).query(&query)).await {
         Ok(response) if response.status().is_success() => {
             if let Ok(data) = response.json::<serde_json::Value>().await {
-                let messages: Vec<RoomMessage> = data["messages"].as_array()
-                    .map(|arr| arr.iter()
-                        .filter_map(|m| {
-                            Some(RoomMessage {
-                                id: m["id"].as_i64()? as i32,
-                                content: m["content"].as_str()?.to_string(),
-                                created_at: m["createdAt"].as_str()?.to_string(),
-                                edited_at: m["editedAt"].as_str().map(String::from),
-                                message_type: m["messageType"].as_str().unwrap_or("text").to_string(),
-                                user: MessageAuthor {
-                                    id: m["user"]["id"].as_i64()? as i32,
-                                    username: m["user"]["username"].as_str()?.to_string(),
-                                    display_name: m["user"]["displayName"].as_str()
-                                        .map(String::from)
-                                        .unwrap_or_else(|| m["user"]["username"].as_str().unwrap_or("").to_string()),
-                                    avatar_url: m["user"]["avatarUrl"].as_str().map(String::from),
-                                },
-                            })
-                        })
-                        .collect())
+                let mut messages: Vec<RoomMessage> = data["messages"].as_array()
+                    .map(|arr| arr.iter().filter_map(parse_room_message).collect())
                     .unwrap_or_default();
 
+                // Messages arrive oldest first, so the extra row is at the front
+                let has_more = messages.len() > limit as usize;
+                if has_more {
+                    messages.drain(..messages.len() - limit as usize);
+                }
+                let next_cursor = if has_more { messages.first().map(|m| m.id) } else { None };
+
+                for message in &messages {
+                    state.search.index(room_name, message);
+                }
 
                 let out_event = ServerEvent::LoadRoomMessages{
                     room_name: room_name.to_string(),
-                    messages
+                    messages,
+                    has_more,
+                    next_cursor
                 };
                 let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
                 let _ = tx.send(msg).await;
             }
         }
-        Ok(_) => send_error(tx, "404", "Room not found").await,
+        Ok(_) => send_error(tx, ErrorCode::NotFound, "Room not found").await,
         Err(e) => {
             println!("API Error: {}", e);
-            send_error(tx, "500", "Failed to fetch messages").await;
+            send_error(tx, ErrorCode::BackendUnavailable, "Failed to fetch messages").await;
         }
     }
 }
 
 
-async fn send_error(tx: mpsc::Sender<Message>, code: &str, message: &str) {
-    let error = ServerEvent::Error { code: code.to_string(), message: message.to_string() };
-    if let Ok(msg) = serde_json::to_string(&error) {
-        let _ = tx.send(Message::Text(msg)).await;
+async fn fetch_room_pins(room_name: &str, state: &AppState) -> Option<Vec<RoomMessage>> {
+    let url = format!("https://localhost:443/internal/rooms/{}/pins", room_name);
+    match state.backend.send(Endpoint::Pins, |c| c.get(&url)).await {
+        Ok(response) if response.status().is_success() => {
+            let data = response.json::<serde_json::Value>().await.ok()?;
+            Some(data["pins"].as_array()
+                .map(|arr| arr.iter().filter_map(parse_room_message).collect())
+                .unwrap_or_default())
+        }
+        Ok(response) => {
+            println!("Failed to fetch pins for {}: HTTP {}", room_name, response.status());
+            None
+        }
+        Err(e) => {
+            println!("API Error: {}", e);
+            None
+        }
     }
 }
 
-async fn fetch_and_send_room_members(
+/// Sends the current pin list of a room to a single session
+async fn send_room_pins(room_name: &str, tx: mpsc::Sender<Message>, state: &AppState) {
+    if let Some(pins) = fetch_room_pins(room_name, state).await {
+        let out_event = ServerEvent::PinsUpdated { room_name: room_name.to_string(), pins };
+        let _ = tx.send(Message::Text(serde_json::to_string(&out_event).unwrap())).await;
+    }
+}
+
+/// Looks up the `room_members.role` of a user, `None` if they are not a member
+async fn fetch_member_role(room_name: &str, db_id: i32, state: &AppState) -> Result<Option<String>, BackendError> {
+    let url = format!("https://localhost:443/internal/rooms/{}/members", room_name);
+    let response = state.backend.send(Endpoint::Members, |c| c.get(&url)).await?;
+    let Ok(data) = response.json::<serde_json::Value>().await else { return Ok(None) };
+
+    Ok(data["members"].as_array()
+        .and_then(|members| members.iter().find(|m| m["id"].as_i64() == Some(db_id as i64)))
+        .and_then(|m| m["role"].as_str().map(String::from)))
+}
+
+/// Pins or unpins a message after checking the user moderates the room,
+/// then pushes the new pin list to everyone in the room
+async fn set_message_pinned(
     room_name: &str,
+    message_id: i32,
+    pinned: bool,
+    user_id: Uuid,
     tx: mpsc::Sender<Message>,
     state: &AppState
 ) {
-    use my_websocket::state::RoomUser;
-    
-    match state.http_client
-        .get(format!("https://localhost:443/internal/rooms/{}/members", room_name))
-        .send()
-        .await 
-    {
-        Ok(response) if response.status().is_success() => {
-            if let Ok(data) = response.json::<serde_json::Value>().await {
-                let users: Vec<RoomUser> = data["members"].as_array()
-                    .map(|arr| arr.iter()
-                        .filter_map(|m| {
-                            let username = m["username"].as_str()?.to_string();
-                            Some(RoomUser {
-                                username: username.clone(),
-                                display_name: m["displayName"].as_str().map(String::from).unwrap_or(username),
-                                avatar_url: m["avatarUrl"].as_str().map(String::from),
-                                status: m["status"].as_str().unwrap_or("online").to_string(),
-                            })
-                        })
-                        .collect())
-                    .unwrap_or_default();
+    let db_id = {
+        let users = state.users.lock().unwrap();
+        users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
+    };
+
+    let role = match fetch_member_role(room_name, db_id, state).await {
+        Ok(role) => role,
+        Err(e) => {
+            println!("API Error: {}", e);
+            send_error(tx, ErrorCode::BackendUnavailable, "Failed to check room permissions").await;
+            return;
+        }
+    };
+    if !role.is_some_and(|r| MODERATOR_ROLES.contains(&r.as_str())) {
+        send_error(tx, ErrorCode::Forbidden, "Only room moderators can pin or unpin messages").await;
+        return;
+    }
+
+    let url = format!("https://localhost:443/internal/rooms/{}/pins", room_name);
+    let request = |c: &reqwest::Client| if pinned {
+        c.post(&url).json(&serde_json::json!({
+            "messageId": message_id,
+            "userId": db_id
+        }))
+    } else {
+        c.delete(format!("{}/{}", url, message_id))
+    };
+
+    match state.backend.send(Endpoint::Write, request).await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(_) => {
+            send_error(tx, ErrorCode::NotFound, "Message not found in this room").await;
+            return;
+        }
+        Err(e) => {
+            println!("API Error: {}", e);
+            send_error(tx, ErrorCode::BackendUnavailable, "Failed to update pinned messages").await;
+            return;
+        }
+    }
+
+    let Some(pins) = fetch_room_pins(room_name, state).await else { return };
+    let out_event = ServerEvent::PinsUpdated { room_name: room_name.to_string(), pins };
+    to_room(room_name, serde_json::to_string(&out_event).unwrap(), None, state).await;
+}
+
+async fn send_upload_ready(upload_id: Uuid, offset: u64, tx: &mpsc::Sender<Message>, state: &AppState) {
+    let out_event = ServerEvent::UploadReady {
+        upload_id,
+        offset,
+        chunk_size: state.upload_config.chunk_size,
+    };
+    let _ = tx.send(Message::Text(serde_json::to_string(&out_event).unwrap())).await;
+}
+
+/// Appends one chunk to its upload. Chunks must arrive in order; a chunk
+/// at the wrong offset gets an `UploadReady` telling the client where to resume.
+async fn handle_upload_chunk(chunk: ChunkFrame<'_>, user_id: Uuid, tx: mpsc::Sender<Message>, state: &AppState) {
+    if !chunk.checksum_ok() {
+        send_error(tx, ErrorCode::InvalidPayload, "Chunk checksum mismatch").await;
+        return;
+    }
+
+    let db_id = {
+        let users = state.users.lock().unwrap();
+        users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
+    };
+
+    let upload = {
+        let uploads = state.uploads.lock().unwrap();
+        uploads.get(&chunk.upload_id).cloned()
+    };
+    let Some(upload) = upload else {
+        send_error(tx, ErrorCode::NotFound, "Unknown or expired upload").await;
+        return;
+    };
+    if upload.owner_db_id != db_id {
+        send_error(tx, ErrorCode::Forbidden, "This upload belongs to another user").await;
+        return;
+    }
+    if chunk.offset != upload.received {
+        send_upload_ready(chunk.upload_id, upload.received, &tx, state).await;
+        return;
+    }
+    if chunk.data.len() > state.upload_config.chunk_size as usize
+        || chunk.offset + chunk.data.len() as u64 > upload.size
+    {
+        send_error(tx, ErrorCode::PayloadTooLarge, "Chunk exceeds the declared upload size").await;
+        return;
+    }
+
+    let path = state.upload_config.part_path(chunk.upload_id);
+    let written = async {
+        use tokio::io::AsyncWriteExt;
+        let mut file = tokio::fs::OpenOptions::new().append(true).open(&path).await?;
+        file.write_all(chunk.data).await?;
+        file.flush().await
+    }.await;
+    if let Err(e) = written {
+        println!("Failed to write upload {}: {}", chunk.upload_id, e);
+        send_error(tx, ErrorCode::Internal, "Failed to store upload chunk").await;
+        return;
+    }
+
+    let received = {
+        let mut uploads = state.uploads.lock().unwrap();
+        match uploads.get_mut(&chunk.upload_id) {
+            Some(u) => {
+                u.received += chunk.data.len() as u64;
+                u.last_activity = Instant::now();
+                u.received
+            }
+            None => return,
+        }
+    };
+
+    if received < upload.size {
+        let out_event = ServerEvent::UploadProgress { upload_id: chunk.upload_id, received };
+        let _ = tx.send(Message::Text(serde_json::to_string(&out_event).unwrap())).await;
+        return;
+    }
+
+    complete_upload(chunk.upload_id, user_id, tx, state).await;
+}
+
+/// Moves a finished upload into place and shares it as a `file` message
+async fn complete_upload(upload_id: Uuid, user_id: Uuid, tx: mpsc::Sender<Message>, state: &AppState) {
+    let Some(upload) = state.uploads.lock().unwrap().remove(&upload_id) else { return };
+    let config = &state.upload_config;
+    let attachment = FileAttachment::new(upload_id, &upload);
+
+    let stored = match tokio::fs::rename(config.part_path(upload_id), config.file_path(upload_id)).await {
+        Ok(()) => {
+            let stored = StoredFile { attachment: attachment.clone(), owner_db_id: upload.owner_db_id, target: Some(upload.target.clone()) };
+            tokio::fs::write(config.meta_path(upload_id), serde_json::to_vec(&stored).unwrap()).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = stored {
+        println!("Failed to finalize upload {}: {}", upload_id, e);
+        send_error(tx, ErrorCode::Internal, "Failed to store upload").await;
+        return;
+    }
+    println!("Upload {} complete: {}", upload_id, attachment.file_name);
+
+    let (username, display_name, db_id) = {
+        let users = state.users.lock().unwrap();
+        users.get(&user_id)
+            .map(|u| (u.username.clone(), u.display_name.clone(), u.db_user_id))
+            .unwrap_or_else(|| ("Unknown".to_string(), "Unknown".to_string(), 0))
+    };
+    let created_at = chrono::Utc::now().to_rfc3339();
+
+    match &upload.target {
+        UploadTarget::Room(room_name) => {
+            persist_message(
+                room_name,
+                db_id,
+                &attachment.url,
+                "file",
+                Some(serde_json::to_value(&attachment).unwrap()),
+                state
+            ).await;
+
+            let out_event = ServerEvent::SendMessage {
+                payload: attachment.url.clone(),
+                from_id: user_id,
+                from_username: username,
+                from_display_name: display_name,
+                created_at,
+                edited_at: None,
+                mentions: Vec::new(),
+                message_type: "file".to_string(),
+                attachment: Some(attachment.clone()),
+            };
+            to_room(room_name, serde_json::to_string(&out_event).unwrap(), Some(user_id), state).await;
+        }
+        UploadTarget::User(target_username) => {
+            let out_event = ServerEvent::PrivateMessage {
+                from_id: user_id,
+                from_username: username,
+                from_display_name: display_name,
+                payload: attachment.url.clone(),
+                created_at,
+                edited_at: None,
+                attachment: Some(attachment.clone()),
+            };
+            to_user(target_username, serde_json::to_string(&out_event).unwrap(), state).await;
+        }
+    }
+
+    let done = ServerEvent::UploadComplete { upload_id, attachment };
+    let _ = tx.send(Message::Text(serde_json::to_string(&done).unwrap())).await;
+}
+
+async fn send_error(tx: mpsc::Sender<Message>, code: ErrorCode, message: &str) {
+    let error = ServerEvent::Error { code, message: message.to_string(), details: None };
+    if let Ok(msg) = serde_json::to_string(&error) {
+        let _ = tx.send(Message::Text(msg)).await;
+    }
+}
+
+async fn send_error_with_details(tx: mpsc::Sender<Message>, code: ErrorCode, message: &str, details: serde_json::Value) {
+    let error = ServerEvent::Error { code, message: message.to_string(), details: Some(details) };
+    if let Ok(msg) = serde_json::to_string(&error) {
+        let _ = tx.send(Message::Text(msg)).await;
+    }
+}
+
+async fn fetch_and_send_room_members(
+    room_name: &str,
+    tx: mpsc::Sender<Message>,
+    state: &AppState
+) {
+    use my_websocket::state::RoomUser;
+    
+    let url = format!("https://localhost:443/internal/rooms/{}/members", room_name);
+    match state.backend.send(Endpoint::Members, |c| c.get(&url)).await {
+        Ok(response) if response.status().is_success() => {
+            if let Ok(data) = response.json::<serde_json::Value>().await {
+                let users: Vec<RoomUser> = data["members"].as_array()
+                    .map(|arr| arr.iter()
+                        .filter_map(|m| {
+                            let username = m["username"].as_str()?.to_string();
+                            Some(RoomUser {
+                                username: username.clone(),
+                                display_name: m["displayName"].as_str().map(String::from).unwrap_or(username),
+                                avatar_url: m["avatarUrl"].as_str().map(String::from),
+                                status: m["status"].as_str().unwrap_or("online").to_string(),
+                            })
+                        })
+                        .collect())
+                    .unwrap_or_default();
                 let out_event = ServerEvent::RoomUpdate {
                     room_name: room_name.to_string(),
                     users
@@ -485,25 +1145,30 @@ async fn fetch_and_send_room_members(
                 let _ = tx.send(msg).await;
             }
         }
-        Ok(_) => send_error(tx, "404", "Room not found").await,
+        Ok(_) => send_error(tx, ErrorCode::NotFound, "Room not found").await,
         Err(e) => {
             println!("API error: {}", e);
-            send_error(tx, "500", "Failed to fetch room users").await;
+            send_error(tx, ErrorCode::BackendUnavailable, "Failed to fetch room users").await;
         }
     }
 }
 
 
 pub async fn broadcast_room_update(room_name: &str, state: &AppState) {
+    to_room(room_name, room_update_payload(room_name, state), None, state).await;
+}
+
+/// `RoomUpdate` listing everyone in the room, on this node or any other
+pub fn room_update_payload(room_name: &str, state: &AppState) -> String {
     use my_websocket::state::RoomUser;
     
-    let (room_users, transmitters) = {
+    let room_users = {
         let rooms = state.rooms.lock().unwrap();
         let users = state.users.lock().unwrap();
         let members = rooms.get(room_name).cloned().unwrap_or_default();
         
         // Get full user info instead of just usernames
-        let room_users: Vec<RoomUser> = members.iter()
+        let mut room_users: Vec<RoomUser> = members.iter()
             .filter_map(|id| users.get(id).map(|info| RoomUser {
                 username: info.username.clone(),
                 display_name: info.display_name.clone(),
@@ -511,52 +1176,27 @@ pub async fn broadcast_room_update(room_name: &str, state: &AppState) {
                 status: info.status.clone(),
             }))
             .collect();
-        
-        let txs: Vec<_> = members.iter()
-            .filter_map(|id| users.get(id).map(|info| info.tx.clone()))
-            .collect();
-        
-        (room_users, txs)
+        room_users.extend(state.directory.lock().unwrap().room_members(room_name));
+        room_users
     };
 
     let out_event = ServerEvent::RoomUpdate { room_name: room_name.to_string(), users: room_users };
-    let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
-
-    for tx in transmitters {
-        let _ = tx.send(msg.clone()).await;
-    }
+    serde_json::to_string(&out_event).unwrap()
 }
 
 pub async fn broadcast_user_joined(room_name: &str, user_id: Uuid, state: &AppState) {
-    // Get username and transmitters
-    let (username, transmitters) = {
-        let rooms = state.rooms.lock().unwrap();
+    let username = {
         let users = state.users.lock().unwrap();
-        
-        let username = users.get(&user_id)
+        users.get(&user_id)
             .map(|u| u.username.clone())
-            .unwrap_or_else(|| "Unknown".to_string());
-        
-        let txs = if let Some(members) = rooms.get(room_name) {
-            members.iter()
-                .filter_map(|id| users.get(id).map(|info| info.tx.clone()))
-                .collect::<Vec<_>>()
-        } else {
-            Vec::new()
-        };
-        
-        (username, txs)
+            .unwrap_or_else(|| "Unknown".to_string())
     };
 
     let out_event = ServerEvent::UserJoined { 
         room_name: room_name.to_string(), 
         username
     };
-    let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
-
-    for tx in transmitters {
-        let _ = tx.send(msg.clone()).await;
-    }
+    to_room(room_name, serde_json::to_string(&out_event).unwrap(), None, state).await;
 }
 
 /// Broadcasts a status change to all users in the given user's rooms
@@ -568,28 +1208,10 @@ pub async fn broadcast_status_update(user_id: Uuid, new_status: &str, state: &Ap
     };
 
     if let Some(uid) = db_id {
-        let subscriber_sessions = {
-            let subs = state.profile_subscribers.lock().unwrap();
-            subs.get(&uid).cloned()
+        let out_event = ServerEvent::UserStatusUpdate { 
+            status: new_status.to_string() 
         };
-
-        if let Some(sessions) = subscriber_sessions {
-            let out_event = ServerEvent::UserStatusUpdate { 
-                status: new_status.to_string() 
-            };
-            let message = serde_json::to_string(&out_event).unwrap();
-            
-            let transmitters: Vec<_> = {
-                let users = state.users.lock().unwrap();
-                sessions.iter()
-                    .filter_map(|id| users.get(id).map(|u| u.tx.clone()))
-                    .collect()
-            };
-
-            for tx in transmitters {
-                let _ = tx.send(Message::Text(message.clone())).await;
-            }
-        }
+        to_profile(uid, serde_json::to_string(&out_event).unwrap(), state).await;
     }    
     
     let (username, user_rooms) = {
@@ -601,104 +1223,86 @@ pub async fn broadcast_status_update(user_id: Uuid, new_status: &str, state: &Ap
             return;
         }
     };
+    if let Some(user) = room_user(user_id, state) {
+        state.bus.publish(BusEvent::Presence { session_id: user_id, user }).await;
+    }
 
     let out_event = ServerEvent::UserStatusChanged {
         username,
         status: new_status.to_string(),
     };
-    let msg = Message::Text(serde_json::to_string(&out_event).unwrap());
+    let payload = serde_json::to_string(&out_event).unwrap();
 
     // Broadcast to each room the user is in
     for room_name in user_rooms {
-        let transmitters: Vec<_> = {
-            let rooms = state.rooms.lock().unwrap();
-            let users = state.users.lock().unwrap();
-            
-            if let Some(members) = rooms.get(&room_name) {
-                members.iter()
-                    .filter(|&&id| id != user_id)
-                    .filter_map(|id| users.get(id).map(|info| info.tx.clone()))
-                    .collect()
-            } else {
-                Vec::new()
-            }
-        };
-
-        for tx in transmitters {
-            let _ = tx.send(msg.clone()).await;
-        }
+        to_room(&room_name, payload.clone(), Some(user_id), state).await;
     }
 }
 
-/// Persists status to database via Node.js
+/// Persists status to database via Node.js. Only ever for the session's own
+/// user: the backend trusts this server to have checked who is asking.
 pub async fn persist_status_to_db(user_id: Uuid, status: &str, state: &AppState) {
     let db_id = {
         let users = state.users.lock().unwrap();
-        users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
+        users.get(&user_id).map(|u| u.db_user_id)
     };
-
-    let _ = state.http_client
-        .post("https://localhost:443/internal/updateStatus")
-        .json(&serde_json::json!({
-            "userId": db_id,
-            "status": status
-        }))
-        .send()
-        .await;
-}
-
-pub async fn handle_socket(socket: WebSocket, state: AppState, token: String) {
-    // 1. Verify token with Node.js server
-    if token.is_empty() {
-        println!("Connection rejected: No token provided");
+    let Some(db_id) = db_id.filter(|id| *id > 0) else {
+        println!("Not persisting status for unknown session {}", user_id);
         return;
-    }
-
-    let auth_res = match state.http_client.post("https://localhost:443/api/auth/verify-session")
-        .json(&serde_json::json!({ "token": token }))
-        .send().await {
-            Ok(res) => res,
-            Err(e) => {
-                println!("Auth verification failed (network): {}", e);
-                return;
-            }
-        };
+    };
 
-    if !auth_res.status().is_success() {
-        println!("Connection rejected: Invalid token (HTTP {})", auth_res.status());
-        return;
+    let queued = state.outbox.submit(
+        format!("user:{}", db_id),
+        format!("https://localhost:443/internal/updateStatus/{}", db_id),
+        serde_json::json!({ "status": status }),
+    ).await;
+    if let Err(e) = queued {
+        println!("Failed to queue status change for {}: {}", db_id, e);
     }
+}
 
-    let auth_data: serde_json::Value = auth_res.json().await.unwrap_or_default();
-    if !auth_data["valid"].as_bool().unwrap_or(false) {
-        println!("Connection rejected: Token not valid");
-        return;
+/// Verifies a session token, locally for JWTs and with the Node.js server otherwise
+pub async fn authenticate(token: &str, state: &AppState) -> Option<AuthenticatedUser> {
+    match state.auth.authenticate(token).await {
+        Ok(auth) if auth.is_expired() => {
+            println!("Connection rejected: Token expired");
+            None
+        }
+        Ok(auth) => Some(auth),
+        Err(e) => {
+            println!("Connection rejected: {}", e);
+            None
+        }
     }
+}
 
-    let db_user_id = auth_data["userId"].as_i64().unwrap_or(0) as i32;
-    let username = auth_data["username"].as_str().unwrap_or("Anonymous").to_string();
-    let display_name = auth_data["displayName"].as_str().unwrap_or(&username).to_string();
-    let avatar_url = auth_data["avatarUrl"].as_str().map(String::from);
-    // Force status to "online" on new connection
-    let status = "online".to_string(); 
-
+/// Adds a session to the shared registry, marks the user online and queues the
+/// welcome. Every transport goes through here so they all behave the same.
+pub async fn open_session(
+    auth: AuthenticatedUser,
+    session: &Session,
+    transport: &str,
+    remote_ip: IpAddr,
+    tx: mpsc::Sender<Message>,
+    state: &AppState
+) -> Uuid {
     let session_id = Uuid::new_v4();
-
-    let (sender, reciever) = socket.split();
-    let (tx, rx) = mpsc::channel(100);
-
     let now = Instant::now(); 
+    let expires_at = auth.deadline();
     
     let user_info = UserInfo {
         session_id,
-        db_user_id,
-        username,
-        display_name,
-        avatar_url,
-        status: status.clone(),
+        db_user_id: auth.db_user_id,
+        remote_ip,
+        username: auth.username,
+        display_name: auth.display_name,
+        avatar_url: auth.avatar_url,
+        // Force status to "online" on new connection
+        status: "online".to_string(),
         rooms: HashSet::new(),
         _joined_at: now,
         last_heartbeat: now,
+        expires_at,
         tx,
     };
     {
@@ -706,29 +1310,70 @@ pub async fn handle_socket(socket: WebSocket, state: AppState, token: String) {
         users.insert(session_id, user_info.clone());
         println!("Users Online: {}", users.len());
     }
-    println!("New authenticated connection: {} (DB ID: {})", session_id, db_user_id);
+    if let Some(session) = remote_session(session_id, state) {
+        state.bus.publish(BusEvent::SessionOpened { session }).await;
+    }
+    println!(
+        "New authenticated connection: {} (DB ID: {}, from {}, {}, {:?}, protocol v{})",
+        session_id, auth.db_user_id, remote_ip, transport, session.codec, session.version
+    );
     
-    broadcast_status_update(session_id, &user_info.status, &state).await;
-    persist_status_to_db(session_id, &user_info.status, &state).await;
-
-    let welcome_msg = ServerEvent::IdentityAnnounced { payload: session_id.to_string() };
+    broadcast_status_update(session_id, &user_info.status, state).await;
+    persist_status_to_db(session_id, &user_info.status, state).await;
+
+    let welcome_msg = if session.is_legacy() {
+        ServerEvent::IdentityAnnounced { payload: session_id.to_string() }
+    } else {
+        ServerEvent::Welcome {
+            session_id,
+            protocol_version: session.version,
+            supported_versions: SUPPORTED_VERSIONS.to_vec(),
+            features: session.feature_list(),
+        }
+    };
     let welcome_msg = serde_json::to_string(&welcome_msg).unwrap();
     let _ = user_info.tx.try_send(Message::Text(welcome_msg));
+    let rooms = state.memberships.lock().unwrap().rooms_of(user_info.db_user_id);
+    let memberships_msg = serde_json::to_string(&ServerEvent::Memberships { rooms }).unwrap();
+    let _ = user_info.tx.try_send(Message::Text(memberships_msg));
+    deliver_pending_mentions(&user_info.username, &user_info.tx, state);
 
-    let mut interval = time::interval(Duration::from_secs(30));
+    session_id
+}
 
-    let mut read_task = tokio::spawn(read(reciever, user_info.tx.clone(), session_id, state.clone()));
-    let mut write_task = tokio::spawn(write(sender, rx, session_id));
+/// Pings the session every 30 seconds until `closed` completes, the client
+/// stops answering for 60 seconds or its token expires, then tears the session down.
+pub async fn run_heartbeat(
+    session_id: Uuid,
+    tx: mpsc::Sender<Message>,
+    state: AppState,
+    closed: impl std::future::Future<Output = ()>
+) {
+    let mut interval = time::interval(Duration::from_secs(30));
+    let started = Instant::now();
+    tokio::pin!(closed);
 
     loop {
+        // Re-read every round so a `RefreshToken` moves the deadline
+        let expires_at = state.users.lock().unwrap().get(&session_id).and_then(|u| u.expires_at);
+
         tokio::select! {
-            _ = &mut read_task => break,
-            _ = &mut write_task => break,
+            _ = &mut closed => break,
+
+            _ = sleep_until(expires_at) => {
+                if session_expired(session_id, &state) {
+                    println!("Session {} expired. Closing connection.", session_id);
+                    let expired = ServerEvent::SessionExpired { reason: "Session token expired".to_string() };
+                    let _ = tx.try_send(Message::Text(serde_json::to_string(&expired).unwrap()));
+                    let _ = tx.try_send(Message::Close(None));
+                    break;
+                }
+            }
 
             _ = interval.tick() => {
                 let last_seen = {
                     let users = state.users.lock().unwrap();
-                    users.get(&session_id).map(|u| u.last_heartbeat).unwrap_or(now)
+                    users.get(&session_id).map(|u| u.last_heartbeat).unwrap_or(started)
                 };
             
                 if last_seen.elapsed() > Duration::from_secs(60) {
@@ -738,9 +1383,11 @@ pub async fn handle_socket(socket: WebSocket, state: AppState, token: String) {
                     break;
                 }
 
+                // Never wait on the queue: a session nobody drains would block this
+                // loop and the timeout above would never fire
                 let ping = ServerEvent::Ping;
                 let msg = Message::Text(serde_json::to_string(&ping).unwrap());
-                let _ = user_info.tx.send(msg).await;
+                let _ = tx.try_send(msg);
             }
         }
     }
@@ -748,6 +1395,404 @@ pub async fn handle_socket(socket: WebSocket, state: AppState, token: String) {
     disconnect(session_id, state).await;
 }
 
+/// Resolves at `deadline`, or never when there is none
+async fn sleep_until(deadline: Option<SystemTime>) {
+    match deadline {
+        Some(deadline) => {
+            let remaining = deadline.duration_since(SystemTime::now()).unwrap_or_default();
+            time::sleep(remaining).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn session_expired(session_id: Uuid, state: &AppState) -> bool {
+    let users = state.users.lock().unwrap();
+    users.get(&session_id)
+        .and_then(|u| u.expires_at)
+        .is_some_and(|e| e <= SystemTime::now())
+}
+
+/// `token` is `None` when the upgrade carried none and the client authenticates with its first frame
+pub async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    token: Option<String>,
+    remote_ip: IpAddr,
+    codec: Codec,
+    deflate: bool
+) {
+    let (mut sender, mut reciever) = socket.split();
+    let mut deflater = deflate.then(|| Deflater::new(&state.compression_config));
+
+    // 1. Get the token from the first frame if the upgrade didn't carry one
+    let upgrade_token = token.is_some();
+    let token = match token {
+        Some(token) => token,
+        None => match time::timeout(state.token_sources.message_timeout, reciever.next()).await {
+            Ok(Some(Ok(frame))) => match decode_frame(&frame, codec) {
+                Some(Ok(ClientEvent::Authenticate { token })) => token,
+                _ => {
+                    let error = ServerEvent::Error {
+                        code: ErrorCode::Unauthorized,
+                        message: "Expected Authenticate as the first frame".to_string(),
+                        details: None,
+                    };
+                    send_direct(&mut sender, &error, codec, deflater.as_mut(), &state).await;
+                    return;
+                }
+            },
+            Ok(_) => return,
+            Err(_) => {
+                println!("Connection rejected: No Authenticate frame within {:?}", state.token_sources.message_timeout);
+                return;
+            }
+        },
+    };
+
+    // 2. Verify the token
+    let Some(auth) = authenticate(&token, &state).await else {
+        let error = ServerEvent::Error {
+            code: ErrorCode::Unauthorized,
+            message: "Token is not valid".to_string(),
+            details: None,
+        };
+        send_direct(&mut sender, &error, codec, deflater.as_mut(), &state).await;
+        return;
+    };
+
+    // 3. Protocol handshake: current clients open with Hello, version 1 clients send nothing
+    let (session, first_frame) = match negotiate(&mut reciever, upgrade_token, codec).await {
+        Ok(negotiated) => negotiated,
+        Err(Some(error)) => {
+            send_direct(&mut sender, &error, codec, deflater.as_mut(), &state).await;
+            return;
+        }
+        Err(None) => return,
+    };
+
+    let (tx, rx) = mpsc::channel(100);
+    let session_id = open_session(auth, &session, "websocket", remote_ip, tx.clone(), &state).await;
+
+    let mut read_task = tokio::spawn(read(reciever, first_frame, tx.clone(), session_id, state.clone(), codec));
+    let mut write_task = tokio::spawn(write(sender, rx, session_id, session, deflater, state.clone()));
+
+    let closed = async {
+        tokio::select! {
+            _ = &mut read_task => {},
+            _ = &mut write_task => {},
+        }
+    };
+    run_heartbeat(session_id, tx, state, closed).await;
+
+    // Drop the reader's handle on the channel so the writer can flush a close frame and finish
+    read_task.abort();
+}
+
+/// Waits up to `HELLO_TIMEOUT` for `Hello` and negotiates the session. Any other
+/// first frame makes it a version 1 session and is handed back to be handled as
+/// usual. When the upgrade already carried a token, an `Authenticate` frame is
+/// skipped: the web client sends one regardless, as it can't tell whether its
+/// cookie went along. `Err(None)` means the connection closed.
+async fn negotiate<S>(
+    reciever: &mut S,
+    upgrade_token: bool,
+    codec: Codec
+) -> Result<(Session, Option<Message>), Option<ServerEvent>>
+where
+    S: futures_util::Stream<Item = Result<Message, axum::Error>> + Unpin,
+{
+    let deadline = time::Instant::now() + HELLO_TIMEOUT;
+    loop {
+        let frame = match time::timeout_at(deadline, reciever.next()).await {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(_) => return Err(None),
+            Err(_) => return Ok((Session::legacy(codec), None)),
+        };
+        match decode_frame(&frame, codec) {
+            Some(Ok(ClientEvent::Authenticate { .. })) if upgrade_token => continue,
+            Some(Ok(ClientEvent::Hello { protocol_version, capabilities })) => {
+                return match Session::negotiate(codec, protocol_version, &capabilities) {
+                    Ok(session) => Ok((session, None)),
+                    Err(reason) => {
+                        println!("Connection rejected: {}", reason);
+                        Err(Some(ServerEvent::Error {
+                            code: ErrorCode::UnsupportedVersion,
+                            message: reason,
+                            details: Some(serde_json::json!({ "supported_versions": SUPPORTED_VERSIONS })),
+                        }))
+                    }
+                };
+            }
+            _ => return Ok((Session::legacy(codec), Some(frame))),
+        }
+    }
+}
+
+/// Stops a session viewing a room. Membership is untouched.
+async fn close_room(room_name: &str, user_id: Uuid, state: &AppState) {
+    // 1. Update the rooms map via helper
+    perform_leave_room(room_name, user_id, state);
+
+    // 2. Update the user's personal room list
+    {
+        let mut users = state.users.lock().unwrap();
+        if let Some(user) = users.get_mut(&user_id) {
+            user.rooms.remove(room_name);
+        }
+    }
+    state.bus.publish(BusEvent::Left { room: room_name.to_string(), session_id: user_id }).await;
+
+    broadcast_room_update(room_name, state).await;
+}
+
+/// Adds the session's user to a room's members or removes them, writes the
+/// change to `room_members` and tells every session of the user
+async fn set_membership(room_name: &str, user_id: Uuid, member: bool, state: &AppState) {
+    let (db_id, username) = {
+        let users = state.users.lock().unwrap();
+        match users.get(&user_id) {
+            Some(u) => (u.db_user_id, u.username.clone()),
+            None => return,
+        }
+    };
+    if db_id == 0 { return; }
+
+    // 1. Update the index, stopping here if nothing changed
+    let (changed, rooms) = {
+        let mut memberships = state.memberships.lock().unwrap();
+        let changed = if member { memberships.add(room_name, db_id) } else { memberships.remove(room_name, db_id) };
+        (changed, memberships.rooms_of(db_id))
+    };
+    if !changed { return; }
+
+    // 2. Persist it
+    let queued = state.outbox.submit(
+        format!("user:{}", db_id),
+        format!("https://localhost:443/internal/rooms/{}/membership", room_name),
+        serde_json::json!({ "userId": db_id, "member": member }),
+    ).await;
+    if let Err(e) = queued {
+        println!("Failed to queue membership change for {} in {}: {}", db_id, room_name, e);
+    }
+
+    // 3. Let the other nodes and the user's other sessions know
+    state.bus.publish(BusEvent::Membership { room: room_name.to_string(), db_user_id: db_id, member }).await;
+    let out_event = ServerEvent::Memberships { rooms };
+    to_user(&username, serde_json::to_string(&out_event).unwrap(), state).await;
+}
+
+/// Makes sure the room catalog is loaded, retrying the startup load if it failed
+async fn ensure_catalog(state: &AppState) -> bool {
+    RoomCatalog::ensure_loaded(&state.catalog, &state.backend).await
+}
+
+/// Checks the session may read or post in an existing room. Private rooms
+/// are closed to everyone but their members, joined or not.
+async fn check_room_visible(room_name: &str, user_id: Uuid, state: &AppState) -> Result<(), (ErrorCode, String)> {
+    if !ensure_catalog(state).await {
+        return Err((ErrorCode::BackendUnavailable, "Rooms are unavailable right now".to_string()));
+    }
+    let db_id = {
+        let users = state.users.lock().unwrap();
+        users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
+    };
+
+    let private = state.catalog.lock().unwrap().get(room_name).map(|r| r.is_private);
+    match private {
+        None => Err((ErrorCode::NotFound, "Room not found".to_string())),
+        Some(true) if state.memberships.lock().unwrap().role(room_name, db_id).is_none() => {
+            Err((ErrorCode::Forbidden, "This room is private".to_string()))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Public rooms are announced to everyone, private ones only to their members
+async fn announce_room(event: ServerEvent, public: bool, members: Vec<i32>, state: &AppState) {
+    let payload = serde_json::to_string(&event).unwrap();
+    if public {
+        to_everyone(payload, None, state).await;
+    } else {
+        to_members(members, payload, state).await;
+    }
+}
+
+fn validate_description(description: Option<String>) -> Result<Option<String>, (ErrorCode, String)> {
+    let description = description.map(|d| d.trim().to_string());
+    if description.as_ref().is_some_and(|d| d.chars().count() > MAX_DESCRIPTION_LEN) {
+        return Err((ErrorCode::InvalidPayload, format!("Descriptions are limited to {} characters", MAX_DESCRIPTION_LEN)));
+    }
+    Ok(description)
+}
+
+/// Stores a new room with the session's user as owner and first member
+async fn create_room(
+    name: &str,
+    description: Option<String>,
+    is_private: bool,
+    user_id: Uuid,
+    state: &AppState
+) -> Result<(), (ErrorCode, String)> {
+    // 1. Validate
+    validate_room_name(name).map_err(|e| (ErrorCode::InvalidPayload, e))?;
+    let description = validate_description(description)?.filter(|d| !d.is_empty());
+    let (db_id, username) = {
+        let users = state.users.lock().unwrap();
+        users.get(&user_id).map(|u| (u.db_user_id, u.username.clone())).unwrap_or((0, String::new()))
+    };
+    if db_id == 0 {
+        return Err((ErrorCode::Unauthorized, "Only registered users can create rooms".to_string()));
+    }
+    if !ensure_catalog(state).await {
+        return Err((ErrorCode::BackendUnavailable, "Rooms are unavailable right now".to_string()));
+    }
+    if state.catalog.lock().unwrap().get(name).is_some() {
+        return Err((ErrorCode::AlreadyExists, format!("Room {} already exists", name)));
+    }
+
+    // 2. Store it; the backend adds the owner to `room_members` in the same transaction
+    let body = serde_json::json!({
+        "name": name,
+        "description": description,
+        "isPrivate": is_private,
+        "createdById": db_id
+    });
+    let room = match state.backend.send(Endpoint::Write, |c| c.post("https://localhost:443/internal/rooms").json(&body)).await {
+        Ok(response) if response.status() == reqwest::StatusCode::CONFLICT => {
+            return Err((ErrorCode::AlreadyExists, format!("Room {} already exists", name)));
+        }
+        Ok(response) if response.status().is_success() => {
+            response.json::<serde_json::Value>().await.ok().and_then(|d| RoomDetails::from_api(&d["room"]))
+        }
+        Ok(response) => {
+            println!("Failed to create room {}: HTTP {}", name, response.status());
+            None
+        }
+        Err(e) => {
+            println!("API Error: {}", e);
+            return Err((ErrorCode::BackendUnavailable, "Failed to create room".to_string()));
+        }
+    };
+    let Some(room) = room else {
+        return Err((ErrorCode::Internal, "Failed to create room".to_string()));
+    };
+    println!("User {} created room {}", db_id, room.name);
+
+    // 3. Record it here and on the other nodes
+    state.catalog.lock().unwrap().insert(room.clone());
+    let rooms = {
+        let mut memberships = state.memberships.lock().unwrap();
+        memberships.grant(&room.name, db_id, "owner");
+        memberships.rooms_of(db_id)
+    };
+    state.bus.publish(BusEvent::RoomCreated { room: room.clone() }).await;
+
+    // 4. Announce it
+    let out_event = ServerEvent::Memberships { rooms };
+    to_user(&username, serde_json::to_string(&out_event).unwrap(), state).await;
+    let public = !room.is_private;
+    announce_room(ServerEvent::RoomCreated { room }, public, vec![db_id], state).await;
+    Ok(())
+}
+
+/// Changes a room's description or privacy. Only its owner or an admin may.
+async fn update_room(
+    name: &str,
+    description: Option<String>,
+    is_private: Option<bool>,
+    user_id: Uuid,
+    state: &AppState
+) -> Result<(), (ErrorCode, String)> {
+    // 1. Check the room exists and the user runs it
+    let db_id = {
+        let users = state.users.lock().unwrap();
+        users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
+    };
+    let Some(before) = state.catalog.lock().unwrap().get(name).cloned() else {
+        return Err((ErrorCode::NotFound, "Room not found".to_string()));
+    };
+    let role = state.memberships.lock().unwrap().role(name, db_id).map(String::from);
+    if before.owner_id != Some(db_id) && !role.is_some_and(|r| ROOM_ADMIN_ROLES.contains(&r.as_str())) {
+        return Err((ErrorCode::Forbidden, "Only the room owner or an admin can change the room".to_string()));
+    }
+    let description = validate_description(description)?;
+
+    // 2. Store the fields that were given
+    let mut body = serde_json::Map::new();
+    if let Some(description) = description {
+        body.insert("description".to_string(), if description.is_empty() { serde_json::Value::Null } else { description.into() });
+    }
+    if let Some(is_private) = is_private {
+        body.insert("isPrivate".to_string(), is_private.into());
+    }
+    let url = format!("https://localhost:443/internal/rooms/{}", name);
+    let room = match state.backend.send(Endpoint::Write, |c| c.patch(&url).json(&body)).await {
+        Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => {
+            return Err((ErrorCode::NotFound, "Room not found".to_string()));
+        }
+        Ok(response) if response.status().is_success() => {
+            response.json::<serde_json::Value>().await.ok().and_then(|d| RoomDetails::from_api(&d["room"]))
+        }
+        Ok(response) => {
+            println!("Failed to update room {}: HTTP {}", name, response.status());
+            None
+        }
+        Err(e) => {
+            println!("API Error: {}", e);
+            return Err((ErrorCode::BackendUnavailable, "Failed to update room".to_string()));
+        }
+    };
+    let Some(room) = room else {
+        return Err((ErrorCode::Internal, "Failed to update room".to_string()));
+    };
+
+    // 3. Record it and tell everyone who could see the room before or after
+    state.catalog.lock().unwrap().insert(room.clone());
+    state.bus.publish(BusEvent::RoomUpdated { room: room.clone() }).await;
+    let public = !before.is_private || !room.is_private;
+    let members = state.memberships.lock().unwrap().members(name);
+    announce_room(ServerEvent::RoomUpdated { room }, public, members, state).await;
+    Ok(())
+}
+
+/// Deletes a room with its messages and memberships. Only its owner may.
+async fn delete_room(name: &str, user_id: Uuid, state: &AppState) -> Result<(), (ErrorCode, String)> {
+    // 1. Check the room exists and belongs to the user
+    let db_id = {
+        let users = state.users.lock().unwrap();
+        users.get(&user_id).map(|u| u.db_user_id).unwrap_or(0)
+    };
+    let Some(room) = state.catalog.lock().unwrap().get(name).cloned() else {
+        return Err((ErrorCode::NotFound, "Room not found".to_string()));
+    };
+    let is_owner = room.owner_id == Some(db_id) || state.memberships.lock().unwrap().role(name, db_id) == Some("owner");
+    if !is_owner {
+        return Err((ErrorCode::Forbidden, "Only the room owner can delete the room".to_string()));
+    }
+
+    // 2. Delete it; a room the backend no longer has is already gone
+    let url = format!("https://localhost:443/internal/rooms/{}", name);
+    match state.backend.send(Endpoint::Write, |c| c.delete(&url)).await {
+        Ok(response) if response.status().is_success() || response.status() == reqwest::StatusCode::NOT_FOUND => {}
+        Ok(response) => {
+            println!("Failed to delete room {}: HTTP {}", name, response.status());
+            return Err((ErrorCode::Internal, "Failed to delete room".to_string()));
+        }
+        Err(e) => {
+            println!("API Error: {}", e);
+            return Err((ErrorCode::BackendUnavailable, "Failed to delete room".to_string()));
+        }
+    }
+    println!("User {} deleted room {}", db_id, name);
+
+    // 3. Take everyone out of it, here and on the other nodes
+    let members = forget_room(name, state);
+    state.bus.publish(BusEvent::RoomDeleted { room_name: name.to_string() }).await;
+    announce_room(ServerEvent::RoomDeleted { room_name: name.to_string() }, !room.is_private, members, state).await;
+    Ok(())
+}
+
 fn perform_leave_room(room_name: &str, user_id: Uuid, state: &AppState) {
     let mut rooms = state.rooms.lock().unwrap();
     if let Some(members) = rooms.get_mut(room_name) {
@@ -755,35 +1800,83 @@ fn perform_leave_room(room_name: &str, user_id: Uuid, state: &AppState) {
         
         if members.is_empty() {
             rooms.remove(room_name);
-            println!("Room {} was empty and has been removed", room_name);
+            println!("Nobody is viewing room {} anymore", room_name);
         } else {
             println!("Room {} now has {} users", room_name, members.len());
         }
     }
 }
 
-pub async fn read(mut reciever: SplitStream<WebSocket>, tx: mpsc::Sender<Message>, user_id: Uuid, state: AppState) {
-    while let Some(msg) = reciever.next().await {
-        if let Ok(msg) = msg {
-            if let Ok(text) = msg.to_text() {
-                match serde_json::from_str::<ClientEvent>(text) {
-                    Ok(event) => {
-                        handle_client_event(event, state.clone(), user_id, tx.clone()).await;
-                    }
-                    Err(e) => {
-                        println!("Failed to parse JSON from {}: {} \nRaw text: {}", user_id, e, text);
-                    }
+/// Decodes a frame into an event. Binary frames are events only on msgpack
+/// sessions; on JSON sessions they are upload chunks and yield `None`.
+fn decode_frame(frame: &Message, codec: Codec) -> Option<Result<ClientEvent, String>> {
+    match frame {
+        Message::Text(text) => Some(serde_json::from_str::<ClientEvent>(text).map_err(|e| e.to_string())),
+        Message::Binary(bytes) if codec == Codec::MsgPack => Some(codec.decode_binary(bytes)),
+        _ => None,
+    }
+}
+
+async fn handle_frame(frame: Message, tx: &mpsc::Sender<Message>, user_id: Uuid, state: &AppState, codec: Codec) {
+    match decode_frame(&frame, codec) {
+        Some(Ok(event)) => {
+            handle_client_event(event, state.clone(), user_id, tx.clone()).await;
+        }
+        Some(Err(e)) => {
+            println!("Failed to parse frame from {}: {}", user_id, e);
+            send_error_with_details(
+                tx.clone(),
+                ErrorCode::InvalidPayload,
+                "Frame could not be parsed as a client event",
+                serde_json::json!({ "reason": e })
+            ).await;
+        }
+        None => {
+            if let Message::Binary(bytes) = &frame {
+                match ChunkFrame::parse(bytes) {
+                    Some(chunk) => handle_upload_chunk(chunk, user_id, tx.clone(), state).await,
+                    None => send_error(tx.clone(), ErrorCode::InvalidPayload, "Malformed upload chunk").await,
                 }
             }
-        } else {
-            break;
+        }
+    }
+}
+
+/// `first_frame` is a frame that was read during the handshake but wasn't a `Hello`
+pub async fn read(
+    mut reciever: SplitStream<WebSocket>,
+    first_frame: Option<Message>,
+    tx: mpsc::Sender<Message>,
+    user_id: Uuid,
+    state: AppState,
+    codec: Codec
+) {
+    if let Some(frame) = first_frame {
+        handle_frame(frame, &tx, user_id, &state, codec).await;
+    }
+
+    while let Some(msg) = reciever.next().await {
+        match msg {
+            Ok(frame) => handle_frame(frame, &tx, user_id, &state, codec).await,
+            Err(_) => break,
         }
     }
     println!("Client {} disconnected (read task)", user_id);
 }
 
-pub async fn write(mut sender: SplitSink<WebSocket, Message>, mut rx: mpsc::Receiver<Message>, user_id: Uuid) {
+pub async fn write(
+    mut sender: SplitSink<WebSocket, Message>,
+    mut rx: mpsc::Receiver<Message>,
+    user_id: Uuid,
+    session: Session,
+    mut deflater: Option<Deflater>,
+    state: AppState
+) {
     while let Some(msg) = rx.recv().await {
+        let Some(mut msg) = session.outgoing(msg) else { continue };
+        if let Some(deflater) = deflater.as_mut() {
+            msg = deflater.wrap(msg, &state.compression_stats);
+        }
         if sender.send(msg).await.is_err() {
             break;
         }
@@ -791,6 +1884,21 @@ pub async fn write(mut sender: SplitSink<WebSocket, Message>, mut rx: mpsc::Rece
     println!("Client {} disconnected (write task)", user_id);
 }
 
+/// Sends an event before the session exists, bypassing the channel and feature filtering
+async fn send_direct(
+    sender: &mut SplitSink<WebSocket, Message>,
+    event: &ServerEvent,
+    codec: Codec,
+    deflater: Option<&mut Deflater>,
+    state: &AppState
+) {
+    let mut msg = codec.encode(Message::Text(serde_json::to_string(event).unwrap()));
+    if let Some(deflater) = deflater {
+        msg = deflater.wrap(msg, &state.compression_stats);
+    }
+    let _ = sender.send(msg).await;
+}
+
 pub async fn disconnect(user_id: Uuid, state: AppState) {
     println!("Cleaning up session for {}: ", user_id);
 
@@ -800,6 +1908,7 @@ pub async fn disconnect(user_id: Uuid, state: AppState) {
         let mut users = state.users.lock().unwrap();
         users.remove(&user_id).map(|u| u.rooms).unwrap_or_default()
     };
+    state.bus.publish(BusEvent::SessionClosed { session_id: user_id }).await;
     
     {
         let mut subs = state.profile_subscribers.lock().unwrap();
@@ -816,3 +1925,422 @@ pub async fn disconnect(user_id: Uuid, state: AppState) {
     let count = state.users.lock().unwrap().len();
     println!("User {} left. \nOnline users: {}", user_id, count);
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use my_websocket::auth::{Authenticator, TokenSources};
+    use my_websocket::backend::{Backend, BackendConfig};
+    use my_websocket::bus::Bus;
+    use my_websocket::catalog::RoomCatalog;
+    use my_websocket::compression::{CompressionConfig, CompressionStats};
+    use my_websocket::directory::Directory;
+    use my_websocket::membership::Memberships;
+    use my_websocket::origin::OriginPolicy;
+    use my_websocket::outbox::{Outbox, OutboxConfig};
+    use my_websocket::proxy::ProxyConfig;
+    use my_websocket::search::SearchBackend;
+    use my_websocket::uploads::UploadConfig;
+
+    /// A single node with no backend behind it; every backend call fails
+    pub(crate) async fn test_state() -> AppState {
+        test_state_on(Bus::local()).await
+    }
+
+    /// Like `test_state`, on the given bus
+    pub(crate) async fn test_state_on(bus: Bus) -> AppState {
+        let backend = Arc::new(Backend::new(BackendConfig::from_env(), reqwest::Client::new()));
+        let outbox_config = OutboxConfig {
+            dir: std::env::temp_dir().join(format!("outbox-test-{}", Uuid::new_v4())),
+            alert_depth: 1000,
+            max_backoff: Duration::from_secs(60),
+            compact_after: 1000,
+        };
+        let outbox = Outbox::open(outbox_config, backend.clone()).await.unwrap();
+        let mut catalog = RoomCatalog::default();
+        catalog.loaded = true;
+
+        AppState {
+            users: Arc::new(Mutex::new(HashMap::new())),
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            memberships: Arc::new(Mutex::new(Memberships::default())),
+            catalog: Arc::new(Mutex::new(catalog)),
+            profile_subscribers: Arc::new(Mutex::new(HashMap::new())),
+            mention_inbox: Arc::new(Mutex::new(HashMap::new())),
+            directory: Arc::new(Mutex::new(Directory::default())),
+            bus: Arc::new(bus),
+            outbox,
+            search: Arc::new(SearchBackend::from_env(backend.clone())),
+            auth: Arc::new(Authenticator::from_env(backend.clone())),
+            token_sources: Arc::new(TokenSources::from_env()),
+            origin_policy: Arc::new(OriginPolicy::from_env()),
+            proxy_config: Arc::new(ProxyConfig::from_env()),
+            backend,
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            upload_config: Arc::new(UploadConfig::from_env()),
+            compression_config: Arc::new(CompressionConfig::from_env()),
+            compression_stats: Arc::new(CompressionStats::default()),
+            fallback_sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registers a session for `username` and returns its id and outgoing queue
+    pub(crate) fn add_session(state: &AppState, username: &str, db_user_id: i32) -> (Uuid, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(100);
+        let session_id = Uuid::new_v4();
+        state.users.lock().unwrap().insert(session_id, UserInfo {
+            session_id,
+            db_user_id,
+            remote_ip: IpAddr::from([127, 0, 0, 1]),
+            username: username.to_string(),
+            display_name: username.to_string(),
+            avatar_url: None,
+            status: "online".to_string(),
+            rooms: HashSet::new(),
+            _joined_at: Instant::now(),
+            last_heartbeat: Instant::now(),
+            expires_at: None,
+            tx,
+        });
+        (session_id, rx)
+    }
+
+    /// The error code of the next frame, which has to be an error
+    pub(crate) fn next_error(rx: &mut mpsc::Receiver<Message>) -> ErrorCode {
+        let Ok(Message::Text(text)) = rx.try_recv() else { panic!("no frame queued") };
+        match serde_json::from_str::<ServerEvent>(&text).unwrap() {
+            ServerEvent::Error { code, .. } => code,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    /// A private room owned by user 1, and a session for user 2 who isn't a member
+    async fn private_room_and_outsider() -> (AppState, Uuid, mpsc::Sender<Message>, mpsc::Receiver<Message>) {
+        let state = test_state().await;
+        state.catalog.lock().unwrap().insert(RoomDetails {
+            name: "secret".to_string(),
+            description: None,
+            is_private: true,
+            owner_id: Some(1),
+        });
+        state.memberships.lock().unwrap().grant("secret", 1, "owner");
+        let (session_id, _) = add_session(&state, "mallory", 2);
+        let (tx, rx) = mpsc::channel(100);
+        (state, session_id, tx, rx)
+    }
+
+    #[tokio::test]
+    async fn joining_an_unknown_room_does_not_create_it() {
+        let state = test_state().await;
+        let (session_id, _) = add_session(&state, "alice", 1);
+        let (tx, mut rx) = mpsc::channel(100);
+
+        handle_client_event(ClientEvent::JoinRoom("nowhere".to_string()), state.clone(), session_id, tx).await;
+
+        assert_eq!(next_error(&mut rx), ErrorCode::NotFound);
+        assert!(state.catalog.lock().unwrap().get("nowhere").is_none());
+        assert!(state.rooms.lock().unwrap().get("nowhere").is_none());
+    }
+
+    #[tokio::test]
+    async fn creating_an_existing_room_is_rejected() {
+        let (state, session_id, tx, mut rx) = private_room_and_outsider().await;
+        let event = ClientEvent::CreateRoom { name: "secret".to_string(), description: None, is_private: false };
+        handle_client_event(event, state.clone(), session_id, tx).await;
+
+        assert_eq!(next_error(&mut rx), ErrorCode::AlreadyExists);
+        assert_eq!(state.catalog.lock().unwrap().get("secret").unwrap().owner_id, Some(1));
+    }
+
+    #[tokio::test]
+    async fn only_owners_and_admins_can_change_a_room() {
+        let (state, mallory, tx, mut rx) = private_room_and_outsider().await;
+        let update = || ClientEvent::UpdateRoom { name: "secret".to_string(), description: Some("mine".to_string()), is_private: None };
+
+        // A member without a role is turned away before the backend is asked
+        state.memberships.lock().unwrap().add("secret", 2);
+        handle_client_event(update(), state.clone(), mallory, tx.clone()).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::Forbidden);
+
+        // Owners and admins get as far as the backend, which isn't there in tests
+        let (owner, _) = add_session(&state, "owner", 1);
+        handle_client_event(update(), state.clone(), owner, tx.clone()).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::BackendUnavailable);
+
+        state.memberships.lock().unwrap().grant("secret", 2, "admin");
+        handle_client_event(update(), state.clone(), mallory, tx).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::BackendUnavailable);
+        assert_eq!(state.catalog.lock().unwrap().get("secret").unwrap().description, None);
+    }
+
+    #[tokio::test]
+    async fn only_owners_can_delete_a_room() {
+        let (state, mallory, tx, mut rx) = private_room_and_outsider().await;
+        let delete = || ClientEvent::DeleteRoom { name: "secret".to_string() };
+
+        // Admins run the room but don't own it
+        state.memberships.lock().unwrap().grant("secret", 2, "admin");
+        handle_client_event(delete(), state.clone(), mallory, tx.clone()).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::Forbidden);
+        assert!(state.catalog.lock().unwrap().get("secret").is_some());
+
+        let (owner, _) = add_session(&state, "owner", 1);
+        handle_client_event(delete(), state.clone(), owner, tx.clone()).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::BackendUnavailable);
+
+        handle_client_event(ClientEvent::DeleteRoom { name: "nowhere".to_string() }, state, owner, tx).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn non_member_cannot_post_to_private_room() {
+        let (state, session_id, tx, mut rx) = private_room_and_outsider().await;
+        let (_, mut owner_rx) = add_session(&state, "owner", 1);
+        state.rooms.lock().unwrap().entry("secret".to_string()).or_default().extend(state.users.lock().unwrap().keys().copied());
+
+        let event = ClientEvent::RoomBroadcast { payload: "hello".to_string(), room_name: "secret".to_string() };
+        handle_client_event(event, state.clone(), session_id, tx).await;
+
+        assert_eq!(next_error(&mut rx), ErrorCode::Forbidden);
+        assert!(owner_rx.try_recv().is_err(), "the message must not reach the room");
+        assert_eq!(state.outbox.snapshot().depth, 0, "the message must not be stored");
+    }
+
+    #[tokio::test]
+    async fn non_member_cannot_list_private_room_users() {
+        let (state, session_id, tx, mut rx) = private_room_and_outsider().await;
+        handle_client_event(ClientEvent::GetRoomUsers("secret".to_string()), state, session_id, tx).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::Forbidden);
+    }
+
+    #[tokio::test]
+    async fn non_member_cannot_load_private_room_history() {
+        let (state, session_id, tx, mut rx) = private_room_and_outsider().await;
+        let event = ClientEvent::LoadHistory { room_name: "secret".to_string(), before_id: None, limit: None };
+        handle_client_event(event, state, session_id, tx).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::Forbidden);
+    }
+
+    #[tokio::test]
+    async fn non_member_is_not_told_about_private_room_mentions() {
+        let (state, _, _, _) = private_room_and_outsider().await;
+        let (_, mut mallory_rx) = add_session(&state, "mallory", 2);
+
+        notify_mention("mallory", 2, "secret", Some(1), "owner", &state).await;
+        notify_mention("offline", 3, "secret", Some(1), "owner", &state).await;
+        assert!(mallory_rx.try_recv().is_err(), "the mention must not reach a non-member");
+        assert!(state.mention_inbox.lock().unwrap().is_empty(), "nor be queued for one");
+
+        // Members still hear about it
+        let (_, mut owner_rx) = add_session(&state, "owner", 1);
+        notify_mention("owner", 1, "secret", Some(1), "bob", &state).await;
+        assert!(owner_rx.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_mention_inbox_does_not_block_the_handshake() {
+        let state = test_state().await;
+        let mention = PendingMention { room_name: "lobby".to_string(), message_id: None, from: "bob".to_string() };
+        state.mention_inbox.lock().unwrap().insert("alice".to_string(), vec![mention; 150]);
+
+        let auth = AuthenticatedUser {
+            db_user_id: 1,
+            username: "alice".to_string(),
+            display_name: "Alice".to_string(),
+            avatar_url: None,
+            expires_at: None,
+            leeway: Duration::ZERO,
+        };
+        let (tx, rx) = mpsc::channel(100);
+        let session = Session::legacy(Codec::Json);
+        let opened = open_session(auth, &session, "test", IpAddr::from([127, 0, 0, 1]), tx, &state);
+        tokio::time::timeout(Duration::from_secs(5), opened).await.expect("handshake waited on the queue");
+
+        // Whatever didn't fit is kept for the next connection
+        let left = state.mention_inbox.lock().unwrap().get("alice").map_or(0, |m| m.len());
+        assert_eq!(left + rx.len(), 150 + 2);
+    }
+
+    #[tokio::test]
+    async fn mention_inbox_is_capped() {
+        let state = test_state().await;
+        state.catalog.lock().unwrap().insert(RoomDetails {
+            name: "lobby".to_string(),
+            description: None,
+            is_private: false,
+            owner_id: None,
+        });
+        for i in 0..(MAX_PENDING_MENTIONS as i32 + 20) {
+            notify_mention("offline", 9, "lobby", Some(i), "bob", &state).await;
+        }
+        let inbox = state.mention_inbox.lock().unwrap();
+        let pending = &inbox["offline"];
+        assert_eq!(pending.len(), MAX_PENDING_MENTIONS);
+        assert_eq!(pending.last().unwrap().message_id, Some(MAX_PENDING_MENTIONS as i32 + 19));
+    }
+
+    fn text_frame(event: serde_json::Value) -> Result<Message, axum::Error> {
+        Ok(Message::Text(event.to_string()))
+    }
+
+    #[tokio::test]
+    async fn redundant_authenticate_before_hello_is_skipped() {
+        let frames = vec![
+            text_frame(serde_json::json!({ "type": "authenticate", "payload": { "token": "t" } })),
+            text_frame(serde_json::json!({ "type": "hello", "payload": { "protocol_version": 2, "capabilities": [] } })),
+        ];
+        let mut reciever = futures_util::stream::iter(frames).chain(futures_util::stream::pending());
+
+        let (session, first_frame) = negotiate(&mut reciever, true, Codec::Json).await.unwrap();
+        assert!(!session.is_legacy());
+        assert!(first_frame.is_none());
+    }
+
+    #[tokio::test]
+    async fn authenticate_after_a_first_frame_token_is_handled_as_usual() {
+        let frames = vec![
+            text_frame(serde_json::json!({ "type": "authenticate", "payload": { "token": "t" } })),
+        ];
+        let mut reciever = futures_util::stream::iter(frames).chain(futures_util::stream::pending());
+
+        let (session, first_frame) = negotiate(&mut reciever, false, Codec::Json).await.unwrap();
+        assert!(session.is_legacy());
+        assert!(first_frame.is_some());
+    }
+
+    /// Uploads go to a fresh temporary directory
+    fn upload_config(ttl: Duration, max_per_session: usize) -> UploadConfig {
+        UploadConfig {
+            dir: std::env::temp_dir().join(format!("uploads-test-{}", Uuid::new_v4())),
+            ttl,
+            max_per_session,
+            ..UploadConfig::from_env()
+        }
+    }
+
+    fn begin_upload() -> ClientEvent {
+        ClientEvent::BeginUpload {
+            upload_id: None,
+            file_name: "notes.txt".to_string(),
+            size: 10,
+            mime_type: "text/plain".to_string(),
+            room_name: None,
+            target_username: Some("bob".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_uploads_per_session_are_capped() {
+        let mut state = test_state().await;
+        state.upload_config = Arc::new(upload_config(Duration::from_secs(60), 2));
+        let (session_id, _) = add_session(&state, "alice", 1);
+        let (tx, mut rx) = mpsc::channel(100);
+
+        handle_client_event(begin_upload(), state.clone(), session_id, tx.clone()).await;
+        handle_client_event(begin_upload(), state.clone(), session_id, tx.clone()).await;
+        while rx.try_recv().is_ok() {}
+        handle_client_event(begin_upload(), state.clone(), session_id, tx.clone()).await;
+        assert_eq!(next_error(&mut rx), ErrorCode::RateLimited);
+
+        // Other sessions have their own allowance
+        let (other_id, _) = add_session(&state, "alice", 1);
+        handle_client_event(begin_upload(), state.clone(), other_id, tx).await;
+        assert_eq!(state.uploads.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn sweep_removes_abandoned_uploads_and_orphaned_parts() {
+        let ttl = Duration::from_secs(60);
+        let mut state = test_state().await;
+        state.upload_config = Arc::new(upload_config(ttl, 4));
+        let (session_id, _) = add_session(&state, "alice", 1);
+        let (tx, _rx) = mpsc::channel(100);
+        handle_client_event(begin_upload(), state.clone(), session_id, tx.clone()).await;
+        handle_client_event(begin_upload(), state.clone(), session_id, tx).await;
+
+        let config = state.upload_config.clone();
+        let ids: Vec<Uuid> = state.uploads.lock().unwrap().keys().copied().collect();
+        let (abandoned, active) = (ids[0], ids[1]);
+        state.uploads.lock().unwrap().get_mut(&abandoned).unwrap().last_activity = Instant::now() - ttl * 2;
+
+        // Left behind by an earlier run, plus one that is still recent
+        let orphan = config.part_path(Uuid::new_v4());
+        let recent_orphan = config.part_path(Uuid::new_v4());
+        std::fs::File::create(&orphan).unwrap().set_modified(SystemTime::now() - ttl * 2).unwrap();
+        std::fs::File::create(&recent_orphan).unwrap();
+
+        assert_eq!(my_websocket::uploads::sweep_once(&state).await, 2);
+        assert!(!state.uploads.lock().unwrap().contains_key(&abandoned));
+        assert!(!config.part_path(abandoned).exists());
+        assert!(!orphan.exists());
+        assert!(state.uploads.lock().unwrap().contains_key(&active));
+        assert!(config.part_path(active).exists());
+        assert!(recent_orphan.exists());
+
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    fn stored_file(owner_db_id: i32, target: Option<UploadTarget>) -> StoredFile {
+        let attachment = FileAttachment {
+            id: Uuid::new_v4(),
+            file_name: "notes.txt".to_string(),
+            mime_type: "text/plain".to_string(),
+            size: 10,
+            url: String::new(),
+        };
+        StoredFile { attachment, owner_db_id, target }
+    }
+
+    fn reader(db_user_id: i32, username: &str) -> AuthenticatedUser {
+        AuthenticatedUser {
+            db_user_id,
+            username: username.to_string(),
+            display_name: username.to_string(),
+            avatar_url: None,
+            expires_at: None,
+            leeway: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn files_in_private_rooms_and_dms_are_only_readable_by_participants() {
+        let (state, _, _, _) = private_room_and_outsider().await;
+        state.catalog.lock().unwrap().insert(RoomDetails {
+            name: "lobby".to_string(),
+            description: None,
+            is_private: false,
+            owner_id: None,
+        });
+        let (owner, mallory) = (reader(1, "owner"), reader(2, "mallory"));
+
+        let in_private = stored_file(1, Some(UploadTarget::Room("secret".to_string())));
+        assert!(in_private.readable_by(&owner, &state).await.unwrap());
+        assert!(!in_private.readable_by(&mallory, &state).await.unwrap());
+
+        let in_public = stored_file(1, Some(UploadTarget::Room("lobby".to_string())));
+        assert!(in_public.readable_by(&mallory, &state).await.unwrap());
+
+        let in_dm = stored_file(3, Some(UploadTarget::User("owner".to_string())));
+        assert!(in_dm.readable_by(&owner, &state).await.unwrap());
+        assert!(in_dm.readable_by(&reader(3, "sender"), &state).await.unwrap());
+        assert!(!in_dm.readable_by(&mallory, &state).await.unwrap());
+
+        let untargeted = stored_file(1, None);
+        assert!(untargeted.readable_by(&owner, &state).await.unwrap());
+        assert!(!untargeted.readable_by(&mallory, &state).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn downloads_need_a_token() {
+        let state = test_state().await;
+        let response = my_websocket::uploads::download_file(
+            axum::extract::State(state),
+            axum::extract::Path(Uuid::new_v4()),
+            axum::http::HeaderMap::new(),
+            axum::extract::Query(HashMap::new()),
+        ).await;
+        assert_eq!(response.status(), axum::http::StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,70 @@
+use my_websocket::schema::{json_schema, typescript, JSON_SCHEMA_PATH, TYPESCRIPT_PATH};
+use std::path::Path;
+
+fn checked_in(path: &str) -> String {
+    let full = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+    std::fs::read_to_string(&full).unwrap_or_else(|e| panic!("failed to read {}: {}", full.display(), e))
+}
+
+#[test]
+fn json_schema_is_up_to_date() {
+    assert!(
+        checked_in(JSON_SCHEMA_PATH) == json_schema(),
+        "{} is stale, run `cargo run --bin export_schema`",
+        JSON_SCHEMA_PATH
+    );
+}
+
+#[test]
+fn typescript_types_are_up_to_date() {
+    assert!(
+        checked_in(TYPESCRIPT_PATH) == typescript(),
+        "{} is stale, run `cargo run --bin export_schema`",
+        TYPESCRIPT_PATH
+    );
+}
+
+/// Built-in TypeScript types the declarations may use without declaring them
+const TYPESCRIPT_GLOBALS: [&str; 2] = ["Array", "Uint8Array"];
+
+/// Every type name used in the declarations, skipping comments and string literals
+fn referenced_types(source: &str) -> Vec<String> {
+    let mut source = source.to_string();
+    while let Some(start) = source.find("/*") {
+        let end = source[start..].find("*/").map_or(source.len(), |e| start + e + 2);
+        source.replace_range(start..end, "");
+    }
+
+    let mut code = String::new();
+    let mut in_string = false;
+    for c in source.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            _ if !in_string => code.push(c),
+            _ => {}
+        }
+    }
+    code.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .filter(|word| word.starts_with(|c: char| c.is_ascii_uppercase()))
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn typescript_types_are_all_declared() {
+    let source = typescript();
+    let body: String = source.lines().filter(|l| !l.starts_with("//")).collect::<Vec<_>>().join("\n");
+    let declared: Vec<&str> = body.lines()
+        .filter_map(|l| l.strip_prefix("export type "))
+        .filter_map(|l| l.split([' ', '<', '=']).next())
+        .collect();
+
+    for name in referenced_types(&body) {
+        assert!(
+            declared.contains(&name.as_str()) || TYPESCRIPT_GLOBALS.contains(&name.as_str()),
+            "{} references {}, which it never declares",
+            TYPESCRIPT_PATH,
+            name
+        );
+    }
+}
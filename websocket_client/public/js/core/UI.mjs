@@ -964,6 +964,7 @@ export class UI {
 
             const roomEl = document.createElement('div');
             roomEl.classList.add('room');
+            if (this.memberships && this.memberships.has(roomName)) roomEl.classList.add('member');
 
             const nameSpan = document.createElement('span');
             nameSpan.textContent = roomName;
@@ -1002,6 +1003,12 @@ export class UI {
 
     }
 
+    // Rooms the user belongs to, highlighted in the room list
+    setMemberships(rooms) {
+        this.memberships = new Set(rooms);
+        if (this.rooms) this.updateRoomList(this.rooms);
+    }
+
     switchTab(tabName) {
         if (tabName === 'friends') {
             this.friendsView.classList.remove('hidden');
@@ -1036,6 +1043,9 @@ export class UI {
             return;
         }
 
+        // Joining only opens rooms that exist, so a new name is created first
+        const known = (this.rooms || []).some(r => (r.name || r) === roomToJoin);
+        if (!known) this.handler.handleCreateRoom(roomToJoin);
         this.handler.handleJoinRoom(roomToJoin);
         this.currentRoom = roomToJoin;
 
@@ -1125,10 +1135,18 @@ export class UI {
         if (this.contactButton) this.contactButton.addEventListener('click', () => this.handler.handleNavButtonClick('contact'));
     }
     showGlobalError(message, duration = 3000) {
+        this.showAlert(message, 'error', duration);
+    }
+
+    showNotice(message, duration = 5000) {
+        this.showAlert(message, 'info', duration);
+    }
+
+    showAlert(message, kind, duration) {
         if (!this.alertContainer) return;
 
         const alertEl = document.createElement('div');
-        alertEl.classList.add('global-alert', 'error');
+        alertEl.classList.add('global-alert', kind);
         alertEl.textContent = message;
 
         this.alertContainer.appendChild(alertEl);
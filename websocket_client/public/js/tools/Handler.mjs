@@ -131,6 +131,13 @@ export class Handler {
         }
     }
 
+    handleCreateRoom(roomName) {
+        this.socketManager.send(JSON.stringify({
+            type: "create_room",
+            payload: { name: roomName, description: null, is_private: false }
+        }));
+    }
+
     handleJoinRoom(roomName) {
         let message = {
             "type": "join_room",
@@ -1,3 +1,6 @@
+// Optional server features this client handles. Events for anything else are never sent to it.
+const CAPABILITIES = ['history_pagination', 'memberships', 'mentions'];
+
 export class SocketManager {
     constructor(ui) {
         this.ui = ui;
@@ -17,10 +20,16 @@ export class SocketManager {
         const tokenMatch = document.cookie.match(/session_token=([^;]+)/);
         const token = tokenMatch ? tokenMatch[1] : '';
 
-        this.socket = new WebSocket(`wss://127.0.0.1:3000/ws?token=${token}`);
+        // The token goes in the first frame rather than the URL, which ends up in access logs
+        this.socket = new WebSocket('wss://127.0.0.1:3000/ws');
 
         this.socket.onopen = () => {
             console.log("Connected to WSS Server");
+            this.send(JSON.stringify({ type: 'authenticate', payload: { token } }));
+            this.send(JSON.stringify({
+                type: 'hello',
+                payload: { protocol_version: 2, capabilities: CAPABILITIES }
+            }));
             this.createTimer();
             this.ui.changeMyStatus('online');
             this.status = 'online';
@@ -66,6 +75,9 @@ export class SocketManager {
 
     handleServerEvent(payload) {
         switch (payload.type) {
+            case 'welcome':
+                this.ui.myId = payload.session_id;
+                break;
             case 'identity_announced':
                 this.ui.myId = payload.payload;
                 break;
@@ -79,6 +91,9 @@ export class SocketManager {
             case 'error':
                 this.ui.showError(payload.code, payload.message);
                 break;
+            case 'session_expired':
+                this.ui.showError('session_expired', payload.reason);
+                break;
             case 'room_update':
                 this.ui.updateRoom(payload.room_name, payload.users);
                 break;
@@ -117,6 +132,12 @@ export class SocketManager {
             case 'recieve_username':
                 this.ui.handler.gotoProfileLink(payload.username);
                 break;
+            case 'memberships':
+                this.ui.setMemberships(payload.rooms);
+                break;
+            case 'mentioned':
+                this.ui.showNotice(`${payload.from} mentioned you in ${payload.room_name}`);
+                break;
         }
     }
 
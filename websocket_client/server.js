@@ -5,7 +5,8 @@ const crypto = require("crypto");
 const path = require("path");
 const fs = require("fs");
 const https = require("https");
-const { PrismaClient } = require('@prisma/client');
+const jwt = require("jsonwebtoken");
+const { PrismaClient, Prisma } = require('@prisma/client');
 
 const prisma = new PrismaClient();
 const app = express();
@@ -22,6 +23,41 @@ const sslOptions = {
     key: fs.readFileSync("../certs/key.pem"),
     cert: fs.readFileSync("../certs/cert.pem")
 };
+// Ask for (but don't require) a client certificate so the WebSocket server can use mTLS
+if (process.env.INTERNAL_CA_CERT) {
+    sslOptions.ca = fs.readFileSync(process.env.INTERNAL_CA_CERT);
+    sslOptions.requestCert = true;
+    sslOptions.rejectUnauthorized = false;
+}
+
+// Internal routes are only for the WebSocket server. It proves itself with a client
+// certificate signed by INTERNAL_CA_CERT or with the INTERNAL_API_SECRET bearer token.
+// Running with neither leaves them open, which is only allowed with DEV_MODE=1.
+const devMode = ['1', 'true', 'on'].includes(process.env.DEV_MODE);
+const internalAuthConfigured = Boolean(process.env.INTERNAL_API_SECRET || process.env.INTERNAL_CA_CERT);
+if (!internalAuthConfigured) {
+    if (!devMode) {
+        console.error("Refusing to start: set INTERNAL_API_SECRET or INTERNAL_CA_CERT (or DEV_MODE=1 for local development)");
+        process.exit(1);
+    }
+    console.warn("WARNING: INTERNAL_API_SECRET is not set, /internal routes are open (dev mode)");
+}
+
+const requireInternalCaller = (req, res, next) => {
+    if (req.socket.authorized) return next();
+    if (!internalAuthConfigured && devMode) return next();
+
+    const secret = process.env.INTERNAL_API_SECRET;
+    const given = Buffer.from(req.get('authorization') || '');
+    const expected = Buffer.from(`Bearer ${secret || ''}`);
+    if (secret && given.length === expected.length && crypto.timingSafeEqual(given, expected)) {
+        return next();
+    }
+
+    console.warn(`Rejected internal call to ${req.path} from ${req.ip}`);
+    res.status(401).json({ error: 'Internal caller not authenticated' });
+};
+app.use(['/internal', '/api/internal'], requireInternalCaller);
 //Verify User session
 const verifyInternalToken = async (req, res, next) => {
     const token = req.body.token || req.cookies.session_token;
@@ -211,19 +247,20 @@ app.post('/internal/updateDisplayname/:db_id', async (req, res) => {
 
 
 
-app.post('/internal/updateStatus/:user', verifyInternalToken, async (req, res) => {
+// The WebSocket server only calls this for the user of the session that changed status,
+// so authenticating the caller is what keeps anyone from changing another user's status
+app.post('/internal/updateStatus/:user', async (req, res) => {
 
     try {
-        const { user: userId } = req.params;
+        const userId = Number(req.params.user);
         const { status } = req.body;
 
-
-        if (req.user.id !== parseInt(userId)) {
-            return res.status(403).json({ error: ' Cannot update another user\'s status' });
+        if (!Number.isInteger(userId) || userId <= 0 || typeof status !== 'string') {
+            return res.status(400).json({ error: 'Invalid user or status' });
         }
 
         const updatedUser = await prisma.user.update({
-            where: { id: parseInt(userId) },
+            where: { id: userId },
             data: { status }
         });
 
@@ -359,13 +396,32 @@ app.post('/api/auth/verify-session', async (req, res) => {
             username: session.user.username,
             displayName: session.user.displayName || session.user.username,
             avatarUrl: session.user.avatarUrl,
-            status: session.user.status || 'online'
+            status: session.user.status || 'online',
+            expiresAt: session.expiresAt
         });
     } catch (error) {
         res.status(500).json({ valid: false });
     }
 });
 
+// Short-lived JWT the WebSocket server can verify without calling back here.
+// Signed with the same JWT_SECRET the Rust server is started with.
+app.post('/api/auth/ws-token', verifyInternalToken, (req, res) => {
+    if (!process.env.JWT_SECRET) {
+        return res.status(404).json({ error: 'WebSocket tokens are not enabled' });
+    }
+
+    const expiresIn = 15 * 60;
+    const token = jwt.sign({
+        sub: String(req.user.id),
+        username: req.user.username,
+        display_name: req.user.displayName || req.user.username,
+        avatar_url: req.user.avatarUrl
+    }, process.env.JWT_SECRET, { algorithm: 'HS256', expiresIn });
+
+    res.json({ token, expiresIn });
+});
+
 // Serve Profile Page (Protected)
 app.get('/profile/:username', gatekeeper, (req, res) => {
     res.sendFile(path.join(__dirname, 'secure_views/profile/index.html'));
@@ -420,6 +476,22 @@ app.get('/api/rooms/:roomId/messages', gatekeeper, async (req, res) => {
         res.status(500).json({ error: error.message });
     }
 });
+// Resolve @mentions for rust (returns only usernames that exist)
+app.post('/internal/users/resolve', async (req, res) => {
+    try {
+        const { usernames = [] } = req.body;
+
+        const users = await prisma.user.findMany({
+            where: { username: { in: usernames } },
+            select: { id: true, username: true }
+        });
+
+        res.json({ users });
+    } catch (error) {
+        res.status(500).json({ error: error.message });
+    }
+});
+
 //Get list of users for rust
 app.get('/internal/rooms/:roomId/members', async (req, res) => {
     try {
@@ -462,6 +534,118 @@ app.get('/internal/rooms/:roomId/members', async (req, res) => {
     }
 });
 
+// Every room, for rust to list rooms nobody is in
+app.get('/internal/rooms', async (req, res) => {
+    try {
+        const rooms = await prisma.room.findMany({
+            select: { name: true, description: true, isPrivate: true, createdById: true }
+        });
+
+        res.json({ rooms });
+    } catch (error) {
+        res.status(500).json({ error: error.message });
+    }
+});
+
+// Create a room; its creator becomes the owner and first member
+app.post('/internal/rooms', async (req, res) => {
+    try {
+        const { name, description = null, isPrivate = false } = req.body;
+        const createdById = parseInt(req.body.createdById);
+
+        const room = await prisma.$transaction(async (tx) => {
+            const room = await tx.room.create({
+                data: { name, description, isPrivate, createdById },
+                select: { id: true, name: true, description: true, isPrivate: true, createdById: true }
+            });
+            await tx.roomMember.create({ data: { roomId: room.id, userId: createdById, role: 'owner' } });
+            return room;
+        });
+
+        const { id, ...details } = room;
+        res.status(201).json({ room: details });
+    } catch (error) {
+        // Unique constraint on the name
+        if (error.code === 'P2002') return res.status(409).json({ error: 'Room already exists' });
+        res.status(500).json({ error: error.message });
+    }
+});
+
+// Change a room's description or privacy
+app.patch('/internal/rooms/:roomId', async (req, res) => {
+    try {
+        const data = {};
+        if ('description' in req.body) data.description = req.body.description;
+        if ('isPrivate' in req.body) data.isPrivate = Boolean(req.body.isPrivate);
+
+        const room = await prisma.room.update({
+            where: { name: req.params.roomId },
+            data,
+            select: { name: true, description: true, isPrivate: true, createdById: true }
+        });
+
+        res.json({ room });
+    } catch (error) {
+        if (error.code === 'P2025') return res.status(404).json({ error: 'Room not found' });
+        res.status(500).json({ error: error.message });
+    }
+});
+
+// Delete a room; messages and memberships go with it
+app.delete('/internal/rooms/:roomId', async (req, res) => {
+    try {
+        const { count } = await prisma.room.deleteMany({ where: { name: req.params.roomId } });
+        if (count === 0) return res.status(404).json({ error: 'Room not found' });
+
+        res.json({ success: true });
+    } catch (error) {
+        res.status(500).json({ error: error.message });
+    }
+});
+
+// Every room membership, for rust to rebuild its index at startup
+app.get('/internal/memberships', async (req, res) => {
+    try {
+        const rows = await prisma.roomMember.findMany({
+            select: { userId: true, role: true, room: { select: { name: true } } }
+        });
+
+        res.json({ memberships: rows.map(m => ({ room: m.room.name, userId: m.userId, role: m.role })) });
+    } catch (error) {
+        res.status(500).json({ error: error.message });
+    }
+});
+
+// Add or remove a member (rust queues these, so both directions must be safe to repeat)
+app.post('/internal/rooms/:roomId/membership', async (req, res) => {
+    try {
+        const { roomId } = req.params;
+        const userId = parseInt(req.body.userId);
+        const { member } = req.body;
+
+        // Rooms are created explicitly, a missing one was deleted
+        const room = await prisma.room.findUnique({ where: { name: roomId } });
+        if (!room) {
+            if (!member) return res.json({ success: true });
+            return res.status(404).json({ error: 'Room not found' });
+        }
+
+        if (member) {
+            // Keep the role of an existing member
+            await prisma.roomMember.upsert({
+                where: { roomId_userId: { roomId: room.id, userId } },
+                create: { roomId: room.id, userId },
+                update: {}
+            });
+        } else {
+            await prisma.roomMember.deleteMany({ where: { roomId: room.id, userId } });
+        }
+
+        res.json({ success: true });
+    } catch (error) {
+        res.status(500).json({ error: error.message });
+    }
+});
 
 app.post('/api/messages', gatekeeper, async (req, res) => {
     try {
@@ -492,6 +676,8 @@ app.post('/api/messages', gatekeeper, async (req, res) => {
 app.get('/internal/rooms/:roomId/messages', async (req, res) => {
     try {
         let { roomId } = req.params;
+        const limit = Math.min(parseInt(req.query.limit) || 50, 201);
+        const before = parseInt(req.query.before);
 
         let room = await prisma.room.findUnique({ where: { name: roomId.toString() } });
 
@@ -500,7 +686,8 @@ app.get('/internal/rooms/:roomId/messages', async (req, res) => {
         let messages = await prisma.message.findMany({
             where: {
                 roomId: room.id,
-                deletedAt: null
+                deletedAt: null,
+                ...(before && { id: { lt: before } })
             },
             select: {
                 id: true,
@@ -517,11 +704,11 @@ app.get('/internal/rooms/:roomId/messages', async (req, res) => {
                     }
                 }
             },
-            orderBy: { createdAt: 'asc' },
-            take: 100 //limit for pagination (change for longer message history)
+            orderBy: { id: 'desc' },
+            take: limit
         });
 
-        res.json({ success: true, messages: messages })
+        res.json({ success: true, messages: messages.reverse() })
     } catch (error) {
         console.log("Error Retrieving messages: ", error);
         res.status(500).json({ error: error.message })
@@ -529,20 +716,165 @@ app.get('/internal/rooms/:roomId/messages', async (req, res) => {
 });
 
 
+const pinnedMessageSelect = {
+    id: true,
+    content: true,
+    createdAt: true,
+    editedAt: true,
+    messageType: true,
+    user: { select: { id: true, username: true, displayName: true, avatarUrl: true } }
+};
+
+// Pinned messages for rust (moderator checks happen in the websocket server)
+app.get('/internal/rooms/:roomId/pins', async (req, res) => {
+    try {
+        const room = await prisma.room.findUnique({ where: { name: req.params.roomId } });
+        if (!room) return res.json({ pins: [] });
+
+        const pins = await prisma.message.findMany({
+            where: { roomId: room.id, deletedAt: null, pinnedAt: { not: null } },
+            select: pinnedMessageSelect,
+            orderBy: { pinnedAt: 'asc' }
+        });
+
+        res.json({ pins });
+    } catch (error) {
+        res.status(500).json({ error: error.message });
+    }
+});
+
+app.post('/internal/rooms/:roomId/pins', async (req, res) => {
+    try {
+        const { messageId, userId } = req.body;
+        const room = await prisma.room.findUnique({ where: { name: req.params.roomId } });
+        if (!room) return res.status(404).json({ error: 'Room not found' });
+
+        const { count } = await prisma.message.updateMany({
+            where: { id: parseInt(messageId), roomId: room.id, deletedAt: null },
+            data: { pinnedAt: new Date(), pinnedById: parseInt(userId) }
+        });
+        if (count === 0) return res.status(404).json({ error: 'Message not found' });
+
+        res.json({ success: true });
+    } catch (error) {
+        res.status(500).json({ error: error.message });
+    }
+});
+
+app.delete('/internal/rooms/:roomId/pins/:messageId', async (req, res) => {
+    try {
+        const room = await prisma.room.findUnique({ where: { name: req.params.roomId } });
+        if (!room) return res.status(404).json({ error: 'Room not found' });
+
+        const { count } = await prisma.message.updateMany({
+            where: { id: parseInt(req.params.messageId), roomId: room.id },
+            data: { pinnedAt: null, pinnedById: null }
+        });
+        if (count === 0) return res.status(404).json({ error: 'Message not found' });
+
+        res.json({ success: true });
+    } catch (error) {
+        res.status(500).json({ error: error.message });
+    }
+});
+
+// ts_headline marks matches with control characters that can't occur in the text it is given,
+// so the snippet can be HTML-escaped before the markers become <mark> tags
+const HEADLINE_START = '\u0002';
+const HEADLINE_END = '\u0003';
+const HEADLINE_OPTIONS = `StartSel="${HEADLINE_START}", StopSel="${HEADLINE_END}", MaxFragments=1, MaxWords=20, MinWords=5`;
+
+function escapeHtml(text) {
+    return text
+        .replaceAll('&', '&amp;')
+        .replaceAll('<', '&lt;')
+        .replaceAll('>', '&gt;')
+        .replaceAll('"', '&quot;')
+        .replaceAll("'", '&#39;');
+}
+
+function headlineToHtml(snippet) {
+    return escapeHtml(snippet || '')
+        .replaceAll(HEADLINE_START, '<mark>')
+        .replaceAll(HEADLINE_END, '</mark>');
+}
+
+// Full-text search for rust (PostgreSQL tsvector, restricted to the rooms rust allows)
+app.post('/internal/messages/search', async (req, res) => {
+    try {
+        const { query, rooms = [], roomName, fromUser, before, after, limit = 20 } = req.body;
+        if (!query || rooms.length === 0) return res.json({ hits: [] });
+
+        const searchRooms = roomName ? rooms.filter(r => r === roomName) : rooms;
+        const tsQuery = Prisma.sql`plainto_tsquery('simple', ${query})`;
+        const document = Prisma.sql`to_tsvector('simple', m.content)`;
+
+        const rows = await prisma.$queryRaw`
+            SELECT m.id, m.content, m.created_at, m.edited_at, m.message_type, r.name AS room_name,
+                   u.id AS user_id, u.username, u.display_name, u.avatar_url,
+                   ts_rank(${document}, ${tsQuery}) AS rank,
+                   ts_headline('simple', translate(m.content, chr(2) || chr(3), ''), ${tsQuery},
+                       ${HEADLINE_OPTIONS}) AS snippet
+            FROM messages m
+            JOIN rooms r ON r.id = m.room_id
+            LEFT JOIN users u ON u.id = m.user_id
+            WHERE m.deleted_at IS NULL
+              AND r.name IN (${Prisma.join(searchRooms)})
+              AND ${document} @@ ${tsQuery}
+              ${fromUser ? Prisma.sql`AND u.username = ${fromUser}` : Prisma.empty}
+              ${before ? Prisma.sql`AND m.created_at < ${new Date(before)}` : Prisma.empty}
+              ${after ? Prisma.sql`AND m.created_at > ${new Date(after)}` : Prisma.empty}
+            ORDER BY rank DESC, m.created_at DESC
+            LIMIT ${Math.min(parseInt(limit) || 20, 100)}`;
+
+        const hits = rows.map(row => ({
+            id: row.id,
+            content: row.content,
+            createdAt: row.created_at,
+            editedAt: row.edited_at,
+            messageType: row.message_type,
+            roomName: row.room_name,
+            rank: row.rank,
+            snippet: headlineToHtml(row.snippet),
+            user: {
+                id: row.user_id,
+                username: row.username,
+                displayName: row.display_name,
+                avatarUrl: row.avatar_url
+            }
+        }));
+
+        res.json({ hits });
+    } catch (error) {
+        console.error("Search error: ", error);
+        res.status(500).json({ error: error.message });
+    }
+});
+
 // Added for Rust server to call internally (may need token or separate internal path)
 app.post('/api/internal/messages', async (req, res) => {
     try {
-        const { roomId, userId, content, messageType = 'text' } = req.body;
+        const { roomId, userId, content, messageType = 'text', metadata } = req.body;
+        const idempotencyKey = req.get('Idempotency-Key') || null;
 
-        let room = await prisma.room.findUnique({ where: { name: roomId.toString() } });
-        if (!room) room = await prisma.room.create({ data: { name: roomId.toString() } });
+        // A retry of an insert that already went through
+        if (idempotencyKey) {
+            const existing = await prisma.message.findUnique({ where: { idempotencyKey } });
+            if (existing) return res.json({ success: true, messageId: existing.id });
+        }
+
+        // Rooms are created explicitly, a missing one was deleted
+        const room = await prisma.room.findUnique({ where: { name: roomId.toString() } });
+        if (!room) return res.status(404).json({ error: 'Room not found' });
 
         const message = await prisma.message.create({
             data: {
                 roomId: room.id,
                 userId: parseInt(userId),
                 content,
-                messageType
+                messageType,
+                metadata: metadata ?? undefined,
+                idempotencyKey
             }
         });
 